@@ -0,0 +1,455 @@
+//! Abstract syntax tree
+//!
+//! Child nodes are reference counted so the interpreter can hold on to
+//! them from its continuation frames without borrowing the whole tree.
+
+use std::rc::Rc;
+
+use crate::diagnostic::Span;
+
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub body: Rc<Block>,
+}
+
+/// A sequence of statements. The block's value is its last statement when
+/// that statement is an expression without a trailing `;`, otherwise `nil`.
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub stmts: Vec<Stmt>,
+    pub span: Span,
+}
+
+impl Block {
+    pub fn empty(span: Span) -> Self {
+        Block {
+            stmts: Vec::new(),
+            span,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum StmtKind {
+    Let {
+        pattern: Pattern,
+        ty: Option<TypeExpr>,
+        value: Rc<Expr>,
+    },
+    /// A named function declaration. Functions declared in a block are
+    /// visible to each other regardless of order (mutual recursion).
+    Fn(Rc<FnDef>),
+    Effect(Rc<EffectDecl>),
+    /// `with effect(params) { ... }` — handles `effect` for the rest of
+    /// the enclosing block.
+    With(Rc<Handler>),
+    /// `with value` — installs a handler value (`handler { ... }`) for the
+    /// rest of the enclosing block. `with return(x) { ... }` is short for
+    /// `with handler { return(x) { ... } }`.
+    Install(Rc<Expr>),
+    TypeDef {
+        name: String,
+        ty: TypeExpr,
+    },
+    Import {
+        path: String,
+        binding: Pattern,
+    },
+    /// `defer { ... }` — runs when the enclosing block exits, however it
+    /// exits. `assigned` lists the variables the body assigns to; those
+    /// are shared with the block, and the rest are captured by value.
+    Defer {
+        body: Rc<Block>,
+        assigned: Vec<String>,
+    },
+    /// An expression statement; `semi` records a trailing `;`.
+    Expr {
+        expr: Rc<Expr>,
+        semi: bool,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Expr { kind, span }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ExprKind {
+    Int(i64),
+    Float(f64),
+    Str(String),
+    /// An interpolated string: text and expression parts, in order.
+    Interp(Vec<InterpPart>),
+    Bool(bool),
+    Nil,
+    Ident(String),
+    List(Vec<Item>),
+    Object(Vec<ObjectItem>),
+    Unary {
+        op: UnaryOp,
+        expr: Rc<Expr>,
+    },
+    /// `lhs op rhs`. Operators have no precedence: a chain such as
+    /// `1 + 2 * 3` is parsed strictly left to right as `(1 + 2) * 3`.
+    Binary {
+        op: BinOp,
+        lhs: Rc<Expr>,
+        rhs: Rc<Expr>,
+    },
+    /// A comparison chain: `a < b <= c` means `a < b and b <= c`, with
+    /// each operand evaluated once.
+    Compare {
+        first: Rc<Expr>,
+        rest: Vec<(BinOp, Rc<Expr>)>,
+    },
+    /// A direct call: `f(a, b)`.
+    Call {
+        func: Rc<Expr>,
+        args: Vec<Arg>,
+    },
+    /// Postfix application: `x f`, `x f(y)`, `x | f(y)` and `x { ... }`.
+    /// The subject flows in from the left; `args` is `None` when the
+    /// function was written without an argument list.
+    Apply {
+        func: Rc<Expr>,
+        subject: Rc<Expr>,
+        args: Option<Vec<Arg>>,
+    },
+    /// The implicit pipeline argument, referenced by a statement that
+    /// starts with an operator (`{ * 2 }`) or `.field`.
+    Subject,
+    /// `expr as name` binds the value to `name` and passes it through.
+    As {
+        expr: Rc<Expr>,
+        name: String,
+    },
+    /// Postfix `?`: propagate an error value.
+    Try(Rc<Expr>),
+    Field {
+        expr: Rc<Expr>,
+        name: String,
+    },
+    /// `x.[n]`, or legacy `x[n]`.
+    Index {
+        expr: Rc<Expr>,
+        index: Rc<Expr>,
+    },
+    /// `&x` / `&mut x`
+    Ref {
+        mutable: bool,
+        expr: Rc<Expr>,
+    },
+    Assign {
+        target: Rc<Expr>,
+        value: Rc<Expr>,
+    },
+    /// A block evaluated in place, with its own scope.
+    Block(Rc<Block>),
+    /// A function literal: `fn(x) x * 2`.
+    Lambda(Rc<FnDef>),
+    /// `handler { clauses }`: handler clauses packaged as a value.
+    Handler(Rc<HandlerDef>),
+    If {
+        cond: Rc<Expr>,
+        then: Rc<Block>,
+        otherwise: Option<Rc<Expr>>,
+    },
+    Match {
+        subject: Rc<Expr>,
+        arms: Vec<MatchArm>,
+    },
+    Loop(Rc<Block>),
+    While {
+        cond: Rc<Expr>,
+        body: Rc<Block>,
+    },
+    Break(Option<Rc<Expr>>),
+    Continue,
+    Return(Option<Rc<Expr>>),
+    Spawn(Rc<Block>),
+    Nursery(Rc<Block>),
+    /// `@comptime expr` / `@startup expr`
+    Stage {
+        stage: Stage,
+        expr: Rc<Expr>,
+    },
+    /// Placeholder for an expression that failed to parse.
+    Error,
+}
+
+#[derive(Debug, Clone)]
+pub enum InterpPart {
+    Text(String),
+    Expr(Rc<Expr>),
+}
+
+/// A list element or call argument that may be spread with `...`.
+#[derive(Debug, Clone)]
+pub struct Item {
+    pub spread: bool,
+    pub expr: Rc<Expr>,
+}
+
+#[derive(Debug, Clone)]
+pub enum ObjectItem {
+    Field { key: Key, value: Rc<Expr> },
+    Spread(Rc<Expr>),
+}
+
+#[derive(Debug, Clone)]
+pub enum Key {
+    Name(String),
+    /// `[expr]: value` — typically a symbol.
+    Computed(Rc<Expr>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Arg {
+    /// `name: value` for named arguments.
+    pub name: Option<String>,
+    pub spread: bool,
+    pub value: Rc<Expr>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+impl BinOp {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Mod => "%",
+            BinOp::Eq => "==",
+            BinOp::Ne => "!=",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+            BinOp::Gt => ">",
+            BinOp::Ge => ">=",
+            BinOp::And => "and",
+            BinOp::Or => "or",
+        }
+    }
+
+    pub fn is_comparison(self) -> bool {
+        matches!(
+            self,
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Comptime,
+    Startup,
+}
+
+#[derive(Debug, Clone)]
+pub struct FnDef {
+    pub name: Option<String>,
+    pub params: Vec<Param>,
+    pub ret: Option<TypeExpr>,
+    pub body: Rc<Block>,
+    /// Whether the body refers to the implicit subject (`{ * 2 }`,
+    /// `as x`), not counting nested functions.
+    pub uses_subject: bool,
+    pub doc: Option<String>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub struct Param {
+    pub pattern: Pattern,
+    pub ty: Option<TypeExpr>,
+    pub default: Option<Rc<Expr>>,
+    /// `...rest`
+    pub rest: bool,
+    pub span: Span,
+}
+
+impl Param {
+    pub fn name(&self) -> Option<&str> {
+        match &self.pattern.kind {
+            PatternKind::Bind { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EffectDecl {
+    pub name: String,
+    pub params: Vec<Param>,
+    pub ret: Option<TypeExpr>,
+    pub resume: ResumeKind,
+    pub span: Span,
+}
+
+/// How a handler may call `resume`, decided by the effect's declaration:
+/// `: Never`, `: T` (once), or `: T where resume: Many` / `Fork`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResumeKind {
+    /// Never resumed: the handler aborts the computation.
+    Never,
+    /// Resumed exactly once on every path.
+    Once,
+    /// Resumed any number of times, one after another.
+    Many,
+    /// Resumed any number of times, each resumption a copy of the
+    /// computation, so only copyable values may be live when it is
+    /// performed.
+    Fork,
+}
+
+impl ResumeKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ResumeKind::Never => "Never",
+            ResumeKind::Once => "Once",
+            ResumeKind::Many => "Many",
+            ResumeKind::Fork => "Fork",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Handler {
+    pub effect: String,
+    /// A shallow handler handles only the first time its effect is
+    /// performed: the computation it resumes runs without it.
+    pub shallow: bool,
+    pub params: Vec<Param>,
+    pub body: Rc<Block>,
+    pub span: Span,
+}
+
+/// The clauses of a `handler { ... }` value, installed together.
+#[derive(Debug, Clone)]
+pub struct HandlerDef {
+    pub clauses: Vec<Rc<Handler>>,
+    /// `return(x) { ... }`: transforms the value of the handled block when
+    /// it finishes normally.
+    pub ret: Option<Rc<ReturnClause>>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReturnClause {
+    pub param: Param,
+    pub body: Rc<Block>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub struct MatchArm {
+    pub pattern: Pattern,
+    pub guard: Option<Rc<Expr>>,
+    pub body: Rc<Expr>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    pub kind: PatternKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum PatternKind {
+    /// `_`
+    Wildcard,
+    Bind {
+        name: String,
+        mutable: bool,
+    },
+    /// A literal to compare against (`1`, `"one"`, `nil`).
+    Literal(Rc<Expr>),
+    List {
+        items: Vec<Pattern>,
+        /// `...rest` (or `...` when `None` inside the option).
+        rest: Option<Option<String>>,
+    },
+    Object {
+        fields: Vec<(String, Pattern)>,
+        rest: Option<Option<String>>,
+    },
+    /// `a or b or c`
+    Or(Vec<Pattern>),
+    /// `Name(pattern)` — a tagged value such as `Ok(data)`.
+    Tagged {
+        tag: String,
+        inner: Box<Pattern>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct TypeExpr {
+    pub kind: TypeKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum TypeKind {
+    /// `Int`, `List(Int)`, `Map<K, V>`, `Size(16)`
+    Named {
+        name: String,
+        args: Vec<TypeExpr>,
+    },
+    /// An expression used as a type argument (`Size(16)`, `[u8; n]`).
+    Value(Rc<Expr>),
+    /// `[T]` or `[T; n]`
+    List {
+        elem: Box<TypeExpr>,
+        len: Option<Box<TypeExpr>>,
+    },
+    Object(Vec<(String, TypeExpr)>),
+    Optional(Box<TypeExpr>),
+    Unit,
+}
+
+impl TypeExpr {
+    /// The bare type name for simple named types.
+    pub fn simple_name(&self) -> Option<&str> {
+        match &self.kind {
+            TypeKind::Named { name, args } if args.is_empty() => Some(name),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Source spans and diagnostics
+//!
+//! Every token and AST node carries a [`Span`]. Positions are zero-based
+//! (like LSP), and diagnostics render them as `line N, column M` followed by
+//! the offending source line.
+
+use std::fmt;
+
+/// A region of source text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    /// Byte offset of the first character.
+    pub start: usize,
+    /// Byte offset one past the last character.
+    pub end: usize,
+    /// Zero-based line of `start`.
+    pub line: u32,
+    /// Zero-based column (in characters) of `start`.
+    pub col: u32,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize, line: u32, col: u32) -> Self {
+        Span {
+            start,
+            end,
+            line,
+            col,
+        }
+    }
+
+    /// The smallest span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        if other.start < self.start {
+            return other.to(self);
+        }
+        Span {
+            start: self.start,
+            end: self.end.max(other.end),
+            line: self.line,
+            col: self.col,
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.col)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A message about the program, pointing at the source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Span,
+    /// Secondary locations, each with a short explanation.
+    pub labels: Vec<(Span, String)>,
+    /// Free-standing notes and suggestions.
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>, span: Span) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            message: message.into(),
+            span,
+            labels: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn warning(message: impl Into<String>, span: Span) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..Diagnostic::error(message, span)
+        }
+    }
+
+    pub fn with_label(mut self, span: Span, label: impl Into<String>) -> Self {
+        self.labels.push((span, label.into()));
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    /// Render the diagnostic with a snippet of `source`.
+    pub fn render(&self, source: &Source) -> String {
+        let kind = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        let mut out = format!("{}: {}\n", kind, self.message);
+        out.push_str(&format!("  --> {} ({})\n", source.name, self.span));
+        out.push_str(&source.snippet(self.span));
+        for (span, label) in &self.labels {
+            out.push_str(&format!("  note: {} ({})\n", label, span));
+            out.push_str(&source.snippet(*span));
+        }
+        for note in &self.notes {
+            out.push_str(&format!("  = {}\n", note));
+        }
+        out
+    }
+}
+
+/// A named source file, used for rendering snippets.
+pub struct Source {
+    pub name: String,
+    pub text: String,
+}
+
+impl Source {
+    pub fn new(name: impl Into<String>, text: impl Into<String>) -> Self {
+        Source {
+            name: name.into(),
+            text: text.into(),
+        }
+    }
+
+    /// The text of a zero-based line, without its terminator.
+    pub fn line(&self, line: u32) -> Option<&str> {
+        self.text.lines().nth(line as usize)
+    }
+
+    fn snippet(&self, span: Span) -> String {
+        let Some(text) = self.line(span.line) else {
+            return String::new();
+        };
+        let gutter = format!("{}", span.line);
+        let pad = " ".repeat(gutter.len());
+        let width = self
+            .text
+            .get(span.start..span.end)
+            .and_then(|s| s.lines().next())
+            .map_or(1, |s| s.chars().count().max(1));
+        format!(
+            " {pad} |\n {gutter} | {text}\n {pad} | {}{}\n",
+            " ".repeat(span.col as usize),
+            "^".repeat(width)
+        )
+    }
+}
+
+/// Render a batch of diagnostics, ending with an error count summary.
+pub fn render_all(diagnostics: &[Diagnostic], source: &Source) -> String {
+    let mut out = String::new();
+    for diagnostic in diagnostics {
+        out.push_str(&diagnostic.render(source));
+        out.push('\n');
+    }
+    let errors = diagnostics.iter().filter(|d| d.is_error()).count();
+    match errors {
+        0 => {}
+        1 => out.push_str("aborting due to 1 error\n"),
+        n => out.push_str(&format!("aborting due to {} errors\n", n)),
+    }
+    out
+}
@@ -0,0 +1,2632 @@
+//! Tree-walking interpreter
+//!
+//! The evaluator is a small machine with an explicit continuation stack.
+//! Instead of recursing through Rust calls, every pending computation is a
+//! [`Frame`] on a stack: evaluating `a + b` pushes a frame that remembers
+//! `a + b` still needs its operands, then evaluates `a`. Values flow down
+//! into the top frame, and `break`, `return` and errors unwind through the
+//! frames until one handles them.
+//!
+//! Keeping the continuation as data means deep Later recursion never
+//! touches the Rust stack, and a call in tail position replaces its
+//! caller's frame, so tail-recursive loops run in constant space.
+
+mod builtins;
+mod env;
+mod tasks;
+mod value;
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io::{BufWriter, Stdout, Write};
+use std::rc::Rc;
+
+pub use builtins::Builtin;
+pub use value::Value;
+
+use crate::ast::{
+    Arg, BinOp, Block, EffectDecl, Expr, ExprKind, Handler, InterpPart, Item, Key as KeyExpr,
+    ObjectItem, Pattern, PatternKind, Program, ResumeKind, ReturnClause, StmtKind, TypeExpr,
+    UnaryOp,
+};
+use crate::diagnostic::{Diagnostic, Source, Span};
+use env::{Env, Slot};
+use tasks::{Combinator, Scheduler, Scope, Task, Wait};
+use value::{Closure, Effect, Key, Object, Symbol};
+
+/// A runtime failure, raised as the built-in `panic` effect, or an error
+/// raised as the built-in `error` effect.
+pub type Error = Box<Panic>;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// The name the implicit subject is bound to. It cannot clash with a
+/// user binding because `$` is not an identifier character.
+const SUBJECT: &str = "$";
+
+/// The deepest the continuation stack may grow before a program is
+/// stopped for runaway recursion.
+const MAX_FRAMES: usize = 1_000_000;
+
+/// Later source for library functions that call back into user code, so
+/// that those calls run on the machine like any other.
+const PRELUDE: &str = include_str!("interp/prelude.later");
+
+/// Run a parsed program, printing its output to stdout and any warnings
+/// about `source` to stderr.
+///
+/// The value of the program's final expression is printed at the end,
+/// unless the program has no final expression, or it is `nil` and the
+/// program already printed something itself.
+pub fn run(program: &Program, source: &Source) -> Result<()> {
+    let mut interp = Interp::new();
+    interp.load_prelude();
+    let result = interp.execute(program).map(|value| {
+        let shown = match program.body.stmts.last().map(|stmt| &stmt.kind) {
+            Some(StmtKind::Expr { semi: false, .. }) => {
+                !matches!(value, Value::Nil) || !interp.printed
+            }
+            _ => false,
+        };
+        if shown {
+            interp.print(&value.to_string());
+        }
+    });
+    let _ = interp.out.flush();
+    for warning in &interp.warnings {
+        eprint!("{}", warning.render(source));
+    }
+    result.map_err(|mut error| {
+        for failure in std::mem::take(&mut error.suppressed) {
+            let cleanup = failure.diagnostic;
+            let label = format!("cleanup also failed: {}", cleanup.message);
+            error.diagnostic.labels.push((cleanup.span, label));
+        }
+        error
+    })
+}
+
+pub struct Interp {
+    stack: Vec<Frame>,
+    control: Control,
+    /// Bindings made by the prelude; the root of every program's scope.
+    prelude: Env,
+    out: BufWriter<Stdout>,
+    /// Whether the program has written to stdout.
+    printed: bool,
+    /// Problems that didn't stop the program, reported when it ends.
+    warnings: Vec<Diagnostic>,
+    /// The task whose frames are on the stack.
+    task: Rc<Task>,
+    scheduler: Scheduler,
+}
+
+/// What the machine does next.
+enum Control {
+    Eval(Rc<Expr>, Env),
+    /// Deliver a value to the top frame.
+    Value(Value),
+    /// Unwind frames until one handles the jump.
+    Unwind(Unwind),
+    /// The running task is waiting: continue another.
+    Switch,
+}
+
+#[derive(Clone)]
+enum Unwind {
+    Break(Value, Span),
+    Continue(Span),
+    Return(Value, Span),
+    Panic(Error),
+    /// A `Never` effect, on its way to its handler.
+    Abort {
+        effect: Rc<Effect>,
+        args: Vec<Value>,
+        span: Span,
+        suppressed: Vec<Panic>,
+    },
+    /// A continuation that its handler dropped without resuming, on its
+    /// way to its [`Frame::Abandoned`].
+    Abandon,
+}
+
+impl Unwind {
+    /// Where cleanup failures are kept, if this unwind is itself a
+    /// failure: a failing `defer` doesn't replace it.
+    fn suppressed(&mut self) -> Option<&mut Vec<Panic>> {
+        match self {
+            Unwind::Panic(error) => Some(&mut error.suppressed),
+            Unwind::Abort { suppressed, .. } => Some(suppressed),
+            Unwind::Break(..) | Unwind::Continue(_) | Unwind::Return(..) | Unwind::Abandon => None,
+        }
+    }
+}
+
+/// A pending computation, waiting for a value.
+#[derive(Clone)]
+enum Frame {
+    /// Running the statements of a block; `idx` is the statement whose
+    /// value is awaited.
+    Block(BlockFrame),
+    /// Evaluating the operands of `expr` left to right; `values` holds the
+    /// ones done so far. See [`operand`].
+    Operands {
+        expr: Rc<Expr>,
+        values: Vec<Value>,
+        env: Env,
+    },
+    /// `lhs and rhs` / `lhs or rhs`, waiting for `lhs`.
+    Logic {
+        op: BinOp,
+        rhs: Rc<Expr>,
+        env: Env,
+        span: Span,
+    },
+    If {
+        then: Rc<Block>,
+        otherwise: Option<Rc<Expr>>,
+        env: Env,
+        span: Span,
+    },
+    /// A `match`, waiting for its subject.
+    Match {
+        expr: Rc<Expr>,
+        env: Env,
+    },
+    /// A `match` arm's guard, at `span`; `bound` is the scope with the
+    /// arm's bindings.
+    Guard {
+        expr: Rc<Expr>,
+        arm: usize,
+        span: Span,
+        subject: Value,
+        env: Env,
+        bound: Env,
+    },
+    Loop {
+        body: Rc<Block>,
+        env: Env,
+    },
+    /// A `while` loop, waiting for its condition or (`in_body`) its body.
+    While {
+        cond: Rc<Expr>,
+        body: Rc<Block>,
+        env: Env,
+        in_body: bool,
+    },
+    /// `break value`, waiting for the value.
+    Break(Span),
+    /// `return value`, waiting for the value.
+    Return(Span),
+    /// Binding a call's parameters, waiting for the default of `params[idx]`.
+    Params(Box<Binding>),
+    /// The boundary of a function call; `return` unwinds to here.
+    Call,
+    /// A postfix application whose callee ignored the subject: a function
+    /// result is applied to the subject in turn (`1 | add(1)`).
+    ApplyResult {
+        subject: Value,
+        span: Span,
+    },
+    /// A `with` handler, active for the frames above it. Values pass
+    /// through, or through its `return` clause if it has one. The
+    /// [`env::mark`] of when it was installed tells the bindings of the
+    /// computation it handles from those outside it.
+    Handler(Rc<Handlers>, u64),
+    /// A handler clause running for `resume`. See [`Interp::abandon`].
+    Handled(Rc<Continuation>),
+    /// The bottom of an abandoned continuation, whose frames unwind to
+    /// here to run their `defer`s. `exit` is how the handler clause that
+    /// dropped it was left, which carries on once they have run.
+    Abandoned(Exit),
+    /// Running the `defer`s of a block that has exited, waiting for the
+    /// one started last. `exit` is how the block is left once the rest
+    /// have run. Cancellation waits for the `defer` to finish, unless it
+    /// set a `cleanup-deadline` and that has passed.
+    Defer {
+        defers: Vec<Deferred>,
+        exit: Exit,
+        deadline: Option<i64>,
+    },
+    /// A `nursery`, or the bottom of a task's stack: its value or unwind
+    /// waits here until the tasks spawned in it have finished.
+    Nursery(Rc<Scope>),
+    /// A builtin such as `await` that blocks, waiting for its `nil`. See
+    /// [`Interp::suspend`].
+    Wait(Wait, Span),
+    /// The bottom of a combinator's stack: once its tasks have finished,
+    /// what it gives for them.
+    Combine(Combinator, Vec<Rc<Task>>, Span),
+}
+
+/// Handler clauses installed together by one `with`, each with what it
+/// handles, and the scope their bodies run in. This is also the value of
+/// a `handler { ... }` expression.
+pub struct Handlers {
+    clauses: Vec<(Rc<Handler>, Handles)>,
+    ret: Option<Rc<ReturnClause>>,
+    env: Env,
+}
+
+impl Handlers {
+    /// The clause that handles `effect`, if any.
+    fn effect(&self, effect: &Rc<Effect>) -> Option<&Rc<Handler>> {
+        self.clauses
+            .iter()
+            .find_map(|(handler, handles)| match handles {
+                Handles::Effect(e) if Rc::ptr_eq(e, effect) => Some(handler),
+                _ => None,
+            })
+    }
+
+    /// The clause that handles the built-in `fault`, if any.
+    fn fault(&self, fault: Fault) -> Option<&Rc<Handler>> {
+        self.clauses
+            .iter()
+            .find_map(|(handler, handles)| match handles {
+                Handles::Fault(f) if *f == fault => Some(handler),
+                _ => None,
+            })
+    }
+}
+
+/// What a handler clause handles.
+#[derive(Clone)]
+enum Handles {
+    Effect(Rc<Effect>),
+    Fault(Fault),
+}
+
+/// The rest of the computation that performed an effect: the frames from
+/// its handler up. Resuming pushes them back onto the stack, so the
+/// handler is in place for the next time the effect is performed, unless
+/// it is shallow.
+///
+/// A `Many` or `Fork` continuation can be resumed again: each resume runs
+/// a copy of the frames as they were captured, with its own copy of the
+/// variables the computation made, so no resume sees another's assignments
+/// to them. Variables from outside the handler are shared.
+pub struct Continuation {
+    effect: Rc<Effect>,
+    frames: RefCell<Option<Vec<Frame>>>,
+    /// For a continuation that may be copied, an open resource its frames
+    /// hold, which a second resume would duplicate.
+    held: Option<String>,
+    /// The [`env::mark`] of the handler; see [`Fork`].
+    mark: u64,
+    resumed: Cell<bool>,
+}
+
+#[derive(Clone)]
+struct BlockFrame {
+    block: Rc<Block>,
+    idx: usize,
+    env: Env,
+    last: Value,
+    /// The `defer`s run so far, to be run in reverse when the block exits.
+    defers: Vec<Deferred>,
+}
+
+/// A `defer` body, with the scope it captured when the `defer` ran.
+#[derive(Clone)]
+struct Deferred {
+    body: Rc<Block>,
+    env: Env,
+}
+
+/// How a block with `defer`s is left.
+#[derive(Clone)]
+enum Exit {
+    Value(Value),
+    Unwind(Unwind),
+}
+
+/// Arguments to a call.
+#[derive(Clone, Default)]
+struct Args {
+    positional: Vec<Value>,
+    named: Vec<(Rc<str>, Value)>,
+}
+
+/// The state of a call while its parameters are bound.
+#[derive(Clone)]
+struct Binding {
+    closure: Rc<Closure>,
+    args: Args,
+    idx: usize,
+    env: Env,
+    subject: Option<Value>,
+    span: Span,
+}
+
+/// The built-in `Never` effects. They never resume, so performing one
+/// unwinds the stack to its handler instead of capturing a continuation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// A runtime failure or `panic(value)`.
+    Panic,
+    /// A recoverable error, raised by `throw(e)` or by `?` on an `Err`.
+    Error,
+    /// Cancellation of a task, raised in it by the scope that owns it.
+    Cancel,
+}
+
+/// The payload of a built-in `Never` effect. `value` is what the handler
+/// receives; `diagnostic` is reported if nothing handles it.
+#[derive(Clone)]
+pub struct Panic {
+    pub fault: Fault,
+    pub value: Value,
+    pub diagnostic: Diagnostic,
+    /// Cleanup that failed while this propagated, in the order it failed.
+    /// The first failure wins; these ride along with it.
+    pub suppressed: Vec<Panic>,
+}
+
+impl Panic {
+    fn with_label(mut self: Box<Self>, span: Span, label: impl Into<String>) -> Box<Self> {
+        self.diagnostic.labels.push((span, label.into()));
+        self
+    }
+
+    fn with_note(mut self: Box<Self>, note: impl Into<String>) -> Box<Self> {
+        self.diagnostic.notes.push(note.into());
+        self
+    }
+
+    /// The failure as data for a handler: its value, which effect raised
+    /// it, and where.
+    fn record(&self) -> Value {
+        let effect = match self.fault {
+            Fault::Panic => "panic",
+            Fault::Error => "error",
+            Fault::Cancel => "cancel",
+        };
+        let span = self.diagnostic.span;
+        Value::object([
+            ("error", self.value.clone()),
+            ("effect", Value::str(effect)),
+            ("line", Value::Int(span.line.into())),
+            ("column", Value::Int(span.col.into())),
+        ])
+    }
+}
+
+/// Build a runtime error whose panic payload is its message.
+fn error(message: impl Into<String>, span: Span) -> Error {
+    let message = message.into();
+    Box::new(Panic {
+        fault: Fault::Panic,
+        value: Value::str(&message),
+        diagnostic: Diagnostic::error(message, span),
+        suppressed: Vec::new(),
+    })
+}
+
+/// Raise `value` as the built-in `error` effect.
+fn thrown(value: Value, span: Span) -> Error {
+    Box::new(Panic {
+        fault: Fault::Error,
+        diagnostic: Diagnostic::error(format!("unhandled error: {}", value), span),
+        value,
+        suppressed: Vec::new(),
+    })
+}
+
+/// An arithmetic error on integers, carrying the operator and operands
+/// so a `panic` handler can inspect them.
+fn arithmetic(message: &str, op: &str, operands: &[Value], span: Span) -> Error {
+    let shown = match operands {
+        [operand] => format!("{}({})", op, operand),
+        _ => operands
+            .iter()
+            .map(Value::to_string)
+            .collect::<Vec<_>>()
+            .join(&format!(" {} ", op)),
+    };
+    let fields = [
+        ("error", Value::str(message)),
+        ("op", Value::str(op)),
+        ("operands", Value::list(operands.to_vec())),
+        ("line", Value::Int(span.line.into())),
+        ("column", Value::Int(span.col.into())),
+    ];
+    Box::new(Panic {
+        fault: Fault::Panic,
+        value: Value::object(fields),
+        diagnostic: Diagnostic::error(format!("{}: {}", message, shown), span),
+        suppressed: Vec::new(),
+    })
+}
+
+impl Interp {
+    fn new() -> Self {
+        Interp {
+            stack: Vec::new(),
+            control: Control::Value(Value::Nil),
+            prelude: Env::default(),
+            out: BufWriter::new(std::io::stdout()),
+            printed: false,
+            warnings: Vec::new(),
+            task: Task::root(),
+            scheduler: Scheduler::default(),
+        }
+    }
+
+    fn load_prelude(&mut self) {
+        let (tokens, _) = crate::lexer::lex(PRELUDE);
+        let (program, diagnostics) = crate::parser::parse(tokens);
+        assert!(diagnostics.is_empty(), "prelude failed to parse");
+        let mut env = Env::default();
+        for stmt in &program.body.stmts {
+            match &stmt.kind {
+                StmtKind::Fn(def) => {
+                    if let Some(name) = &def.name {
+                        env = env.hoist(name);
+                    }
+                }
+                StmtKind::Effect(decl) => env = env.bind(&decl.name, new_effect(decl)),
+                _ => {}
+            }
+        }
+        for stmt in &program.body.stmts {
+            if let StmtKind::Fn(def) = &stmt.kind {
+                self.define_fn(def, &env);
+            }
+        }
+        self.prelude = env;
+    }
+
+    /// Write a line of program output.
+    pub fn print(&mut self, text: &str) {
+        let _ = writeln!(self.out, "{}", text);
+        self.printed = true;
+    }
+
+    fn execute(&mut self, program: &Program) -> Result<Value> {
+        self.stack.push(Frame::Nursery(self.task.scope().clone()));
+        self.enter_block(program.body.clone(), self.prelude.clone());
+        loop {
+            let done = match std::mem::replace(&mut self.control, Control::Value(Value::Nil)) {
+                Control::Eval(expr, env) => {
+                    self.eval(expr, env);
+                    None
+                }
+                Control::Value(value) => match self.stack.pop() {
+                    Some(frame) => {
+                        self.resume(frame, value);
+                        None
+                    }
+                    None => self.complete(Ok(value)),
+                },
+                Control::Unwind(unwind) => match self.stack.pop() {
+                    Some(frame) => {
+                        self.unwind(frame, unwind);
+                        None
+                    }
+                    None => self.complete(Err(stray(unwind))),
+                },
+                Control::Switch => self.switch().err().map(Err),
+            };
+            if let Some(result) = done {
+                return result;
+            }
+        }
+    }
+
+    // ---------------------------------------------------------------------
+    // Machine transitions
+    // ---------------------------------------------------------------------
+
+    fn ret(&mut self, value: Value) {
+        self.control = Control::Value(value);
+    }
+
+    /// Raise the built-in `panic` effect.
+    fn raise(&mut self, error: Error) {
+        self.control = Control::Unwind(Unwind::Panic(error));
+    }
+
+    fn finish(&mut self, result: Result<Value>) {
+        match result {
+            Ok(value) => self.ret(value),
+            Err(error) => self.raise(error),
+        }
+    }
+
+    fn eval(&mut self, expr: Rc<Expr>, env: Env) {
+        let span = expr.span;
+        match &expr.kind {
+            ExprKind::Int(n) => self.ret(Value::Int(*n)),
+            ExprKind::Float(x) => self.ret(Value::Float(*x)),
+            ExprKind::Str(s) => self.ret(Value::str(s)),
+            ExprKind::Bool(b) => self.ret(Value::Bool(*b)),
+            ExprKind::Nil => self.ret(Value::Nil),
+            ExprKind::Ident(name) => {
+                let result = self.lookup(name, &env, span);
+                self.finish(result);
+            }
+            ExprKind::Subject => {
+                let result = match env.lookup(SUBJECT) {
+                    Some(Slot::Value(value)) => Ok(value.clone()),
+                    _ => Err(error("no implicit subject here", span)
+                        .with_note("only a function body can start with an operator or `.field`")),
+                };
+                self.finish(result);
+            }
+            ExprKind::Binary {
+                op: op @ (BinOp::And | BinOp::Or),
+                lhs,
+                rhs,
+            } => {
+                self.stack.push(Frame::Logic {
+                    op: *op,
+                    rhs: rhs.clone(),
+                    env: env.clone(),
+                    span,
+                });
+                self.control = Control::Eval(lhs.clone(), env);
+            }
+            ExprKind::Block(block) => self.enter_block(block.clone(), env),
+            ExprKind::Lambda(def) => self.ret(Value::Func(Rc::new(Closure {
+                def: def.clone(),
+                env,
+            }))),
+            ExprKind::Handler(def) => {
+                let mut clauses = Vec::new();
+                for handler in &def.clauses {
+                    match self.handles(handler, &env) {
+                        Ok(handles) => clauses.push((handler.clone(), handles)),
+                        Err(error) => return self.raise(error),
+                    }
+                }
+                self.ret(Value::Handler(Rc::new(Handlers {
+                    clauses,
+                    ret: def.ret.clone(),
+                    env,
+                })));
+            }
+            ExprKind::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                self.stack.push(Frame::If {
+                    then: then.clone(),
+                    otherwise: otherwise.clone(),
+                    env: env.clone(),
+                    span: cond.span,
+                });
+                self.control = Control::Eval(cond.clone(), env);
+            }
+            ExprKind::Match { subject, .. } => {
+                let subject = subject.clone();
+                self.stack.push(Frame::Match {
+                    expr,
+                    env: env.clone(),
+                });
+                self.control = Control::Eval(subject, env);
+            }
+            ExprKind::Loop(body) => {
+                self.stack.push(Frame::Loop {
+                    body: body.clone(),
+                    env: env.clone(),
+                });
+                self.enter_block(body.clone(), env);
+            }
+            ExprKind::While { cond, body } => {
+                self.stack.push(Frame::While {
+                    cond: cond.clone(),
+                    body: body.clone(),
+                    env: env.clone(),
+                    in_body: false,
+                });
+                self.control = Control::Eval(cond.clone(), env);
+            }
+            ExprKind::Break(value) => match value {
+                Some(value) => {
+                    self.stack.push(Frame::Break(span));
+                    self.control = Control::Eval(value.clone(), env);
+                }
+                None => self.control = Control::Unwind(Unwind::Break(Value::Nil, span)),
+            },
+            ExprKind::Continue => self.control = Control::Unwind(Unwind::Continue(span)),
+            ExprKind::Return(value) => match value {
+                Some(value) => {
+                    self.stack.push(Frame::Return(span));
+                    self.control = Control::Eval(value.clone(), env);
+                }
+                None => self.control = Control::Unwind(Unwind::Return(Value::Nil, span)),
+            },
+            ExprKind::Stage { expr, .. } => self.control = Control::Eval(expr.clone(), env),
+            ExprKind::Spawn(body) => {
+                let task = self.spawn(body.clone(), env, span);
+                self.ret(task);
+            }
+            ExprKind::Nursery(body) => {
+                self.stack.push(Frame::Nursery(Scope::new(span)));
+                self.enter_block(body.clone(), env);
+            }
+            ExprKind::Error => {
+                self.raise(error("cannot run an expression that failed to parse", span))
+            }
+            _ => match operand(&expr, 0) {
+                Some(first) => {
+                    let first = first.clone();
+                    self.stack.push(Frame::Operands {
+                        expr,
+                        values: Vec::with_capacity(4),
+                        env: env.clone(),
+                    });
+                    self.control = Control::Eval(first, env);
+                }
+                None => self.operate(expr, Vec::new(), env),
+            },
+        }
+    }
+
+    /// Deliver `value` to `frame`.
+    fn resume(&mut self, frame: Frame, value: Value) {
+        match frame {
+            Frame::Block(mut frame) => {
+                let stmt = &frame.block.stmts[frame.idx];
+                match &stmt.kind {
+                    StmtKind::Let { pattern, ty, .. } => {
+                        if let Some(ty) = ty
+                            && let Err(error) = check_annotation(ty, &value)
+                        {
+                            return self.raise(error);
+                        }
+                        match bind_pattern(pattern, value, &frame.env, pattern.span) {
+                            Ok(env) => frame.env = env,
+                            Err(error) => return self.raise(error),
+                        }
+                        frame.last = Value::Nil;
+                    }
+                    StmtKind::Expr { semi, .. } => {
+                        frame.last = if *semi { Value::Nil } else { value };
+                    }
+                    StmtKind::Install(expr) => {
+                        let Value::Handler(handlers) = value else {
+                            return self.raise(error(
+                                format!("`with` needs a handler, got {}", value.type_name()),
+                                expr.span,
+                            ));
+                        };
+                        self.stack.push(Frame::Handler(handlers, env::mark()));
+                        frame.last = Value::Nil;
+                    }
+                    _ => unreachable!("block frames only wait on `let`, `with` and expressions"),
+                }
+                frame.idx += 1;
+                self.exec(frame);
+            }
+            Frame::Operands {
+                expr,
+                mut values,
+                env,
+            } => {
+                values.push(value);
+                match operand(&expr, values.len()) {
+                    Some(next) => {
+                        let next = next.clone();
+                        self.stack.push(Frame::Operands {
+                            expr,
+                            values,
+                            env: env.clone(),
+                        });
+                        self.control = Control::Eval(next, env);
+                    }
+                    None => self.operate(expr, values, env),
+                }
+            }
+            Frame::Logic { op, rhs, env, span } => match value {
+                Value::Bool(b) if b == (op == BinOp::And) => self.control = Control::Eval(rhs, env),
+                Value::Bool(b) => self.ret(Value::Bool(b)),
+                other => self.raise(error(
+                    format!("expected bool, got {}", other.type_name()),
+                    span,
+                )),
+            },
+            Frame::If {
+                then,
+                otherwise,
+                env,
+                span,
+            } => match value {
+                Value::Bool(true) => self.enter_block(then, env),
+                Value::Bool(false) => match otherwise {
+                    Some(otherwise) => self.control = Control::Eval(otherwise, env),
+                    None => self.ret(Value::Nil),
+                },
+                other => self.raise(error(
+                    format!("expected bool in condition, got {}", other.type_name()),
+                    span,
+                )),
+            },
+            Frame::Match { expr, env } => self.match_arms(expr, 0, value, env),
+            Frame::Guard {
+                expr,
+                arm,
+                span,
+                subject,
+                env,
+                bound,
+            } => match value {
+                Value::Bool(true) => {
+                    let ExprKind::Match { arms, .. } = &expr.kind else {
+                        unreachable!()
+                    };
+                    self.control = Control::Eval(arms[arm].body.clone(), bound);
+                }
+                Value::Bool(false) => self.match_arms(expr, arm + 1, subject, env),
+                other => self.raise(error(
+                    format!("expected bool in guard, got {}", other.type_name()),
+                    span,
+                )),
+            },
+            Frame::Loop { body, env } => {
+                self.stack.push(Frame::Loop {
+                    body: body.clone(),
+                    env: env.clone(),
+                });
+                self.enter_block(body, env);
+                self.tick();
+            }
+            Frame::While {
+                cond,
+                body,
+                env,
+                in_body,
+            } => {
+                if in_body {
+                    self.stack.push(Frame::While {
+                        cond: cond.clone(),
+                        body,
+                        env: env.clone(),
+                        in_body: false,
+                    });
+                    self.control = Control::Eval(cond, env);
+                    self.tick();
+                    return;
+                }
+                match value {
+                    Value::Bool(true) => {
+                        self.stack.push(Frame::While {
+                            cond,
+                            body: body.clone(),
+                            env: env.clone(),
+                            in_body: true,
+                        });
+                        self.enter_block(body, env);
+                    }
+                    Value::Bool(false) => self.ret(Value::Nil),
+                    other => self.raise(error(
+                        format!("expected bool in condition, got {}", other.type_name()),
+                        cond.span,
+                    )),
+                }
+            }
+            Frame::Break(span) => self.control = Control::Unwind(Unwind::Break(value, span)),
+            Frame::Return(span) => self.control = Control::Unwind(Unwind::Return(value, span)),
+            Frame::Params(mut binding) => {
+                let param = &binding.closure.def.params[binding.idx];
+                match bind_pattern(&param.pattern, value, &binding.env, param.span) {
+                    Ok(env) => {
+                        binding.env = env;
+                        binding.idx += 1;
+                        self.bind_params(binding);
+                    }
+                    Err(error) => self.raise(error),
+                }
+            }
+            Frame::Handler(handlers, _) => match &handlers.ret {
+                Some(ret) => {
+                    let env = handlers.env.clone();
+                    match bind_pattern(&ret.param.pattern, value, &env, ret.param.span) {
+                        Ok(env) => self.enter_block(ret.body.clone(), env),
+                        Err(error) => self.raise(error),
+                    }
+                }
+                None => self.ret(value),
+            },
+            Frame::Call => self.ret(value),
+            // A `defer`'s value is discarded.
+            Frame::Defer { defers, exit, .. } => self.leave(defers, exit),
+            Frame::Nursery(scope) => self.join(scope, Exit::Value(value)),
+            Frame::Handled(continuation) => self.abandon(continuation, Exit::Value(value)),
+            Frame::Abandoned(exit) => self.leave(Vec::new(), exit),
+            Frame::Wait(wait, span) => self.wait(wait, span),
+            Frame::Combine(combinator, tasks, span) => self.combined(combinator, &tasks, span),
+            Frame::ApplyResult { subject, span } => {
+                if value.is_callable() {
+                    let args = Args {
+                        positional: vec![subject],
+                        named: Vec::new(),
+                    };
+                    if let Err(error) = self.call(value, args, None, span) {
+                        self.raise(error);
+                    }
+                } else {
+                    self.ret(value);
+                }
+            }
+        }
+    }
+
+    /// Pass `unwind` through `frame`, which has been popped.
+    fn unwind(&mut self, frame: Frame, unwind: Unwind) {
+        match (frame, unwind) {
+            (Frame::Loop { .. } | Frame::While { .. }, Unwind::Break(value, _)) => self.ret(value),
+            (Frame::Loop { body, env }, Unwind::Continue(_)) => {
+                self.stack.push(Frame::Loop {
+                    body: body.clone(),
+                    env: env.clone(),
+                });
+                self.enter_block(body, env);
+                self.tick();
+            }
+            (
+                Frame::While {
+                    cond, body, env, ..
+                },
+                Unwind::Continue(_),
+            ) => {
+                self.stack.push(Frame::While {
+                    cond: cond.clone(),
+                    body,
+                    env: env.clone(),
+                    in_body: false,
+                });
+                self.control = Control::Eval(cond, env);
+                self.tick();
+            }
+            (Frame::Call, Unwind::Return(value, _)) => self.ret(value),
+            (Frame::Handler(handlers, _), Unwind::Panic(error))
+                if let Some(handler) = handlers.fault(error.fault).cloned() =>
+            {
+                let env = handlers.env.clone();
+                let Panic {
+                    value, suppressed, ..
+                } = *error;
+                let args = self.failure_args(&handler, vec![value], suppressed);
+                if let Err(error) = self.handle(&handler, args, env, handler.span) {
+                    self.raise(error);
+                }
+            }
+            (
+                Frame::Handler(handlers, _),
+                Unwind::Abort {
+                    effect,
+                    args,
+                    span,
+                    suppressed,
+                },
+            ) if let Some(handler) = handlers.effect(&effect).cloned() => {
+                let env = handlers.env.clone();
+                let args = self.failure_args(&handler, args, suppressed);
+                if let Err(error) = self.handle(&handler, args, env, span) {
+                    self.raise(error);
+                }
+            }
+            (Frame::Call, unwind @ (Unwind::Break(..) | Unwind::Continue(_))) => {
+                self.raise(stray(unwind))
+            }
+            (Frame::Block(frame), unwind) if !frame.defers.is_empty() => {
+                self.leave(frame.defers, Exit::Unwind(unwind))
+            }
+            // The first failure wins: a `defer` that fails while its block
+            // is already failing is kept with the original failure.
+            (
+                Frame::Defer {
+                    defers,
+                    exit: Exit::Unwind(mut first),
+                    ..
+                },
+                Unwind::Panic(mut failure),
+            ) => {
+                let exit = match first.suppressed() {
+                    Some(suppressed) => {
+                        let inner = std::mem::take(&mut failure.suppressed);
+                        suppressed.push(*failure);
+                        suppressed.extend(inner);
+                        first
+                    }
+                    None => Unwind::Panic(failure),
+                };
+                self.leave(defers, Exit::Unwind(exit))
+            }
+            // Otherwise a jump out of a `defer` replaces the way its block
+            // was left.
+            (Frame::Defer { defers, .. }, unwind) => self.leave(defers, Exit::Unwind(unwind)),
+            (Frame::Nursery(scope), unwind) => self.join(scope, Exit::Unwind(unwind)),
+            (Frame::Handled(continuation), unwind) => {
+                self.abandon(continuation, Exit::Unwind(unwind))
+            }
+            (Frame::Abandoned(exit), Unwind::Abandon) => self.leave(Vec::new(), exit),
+            (_, unwind) => self.control = Control::Unwind(unwind),
+        }
+    }
+
+    // ---------------------------------------------------------------------
+    // Blocks and statements
+    // ---------------------------------------------------------------------
+
+    /// Start running `block` in a new scope. Named functions and effects
+    /// are declared up front so they can refer to each other.
+    fn enter_block(&mut self, block: Rc<Block>, mut env: Env) {
+        for stmt in &block.stmts {
+            match &stmt.kind {
+                StmtKind::Fn(def) => {
+                    if let Some(name) = &def.name {
+                        env = env.hoist(name);
+                    }
+                }
+                StmtKind::Effect(decl) => env = env.bind(&decl.name, new_effect(decl)),
+                _ => {}
+            }
+        }
+        self.exec(BlockFrame {
+            block,
+            idx: 0,
+            env,
+            last: Value::Nil,
+            defers: Vec::new(),
+        });
+    }
+
+    /// Run statements from `frame.idx` until one needs evaluating.
+    fn exec(&mut self, mut frame: BlockFrame) {
+        let block = frame.block.clone();
+        while let Some(stmt) = block.stmts.get(frame.idx) {
+            match &stmt.kind {
+                StmtKind::Let { value, .. } => {
+                    let env = frame.env.clone();
+                    self.stack.push(Frame::Block(frame));
+                    self.control = Control::Eval(value.clone(), env);
+                    return;
+                }
+                StmtKind::Expr { expr, semi } => {
+                    let env = frame.env.clone();
+                    // The last expression is in tail position: the block's
+                    // frame is done, so drop it before evaluating, unless
+                    // it has `defer`s to run after.
+                    if frame.idx + 1 < block.stmts.len() || *semi || !frame.defers.is_empty() {
+                        self.stack.push(Frame::Block(frame));
+                    }
+                    self.control = Control::Eval(expr.clone(), env);
+                    return;
+                }
+                StmtKind::Fn(def) => {
+                    frame.last = self.define_fn(def, &frame.env);
+                }
+                StmtKind::TypeDef { .. } | StmtKind::Effect(_) => frame.last = Value::Nil,
+                // The handler covers the rest of the block, which runs in
+                // frames above it.
+                StmtKind::With(handler) => {
+                    let handles = match self.handles(handler, &frame.env) {
+                        Ok(handles) => handles,
+                        Err(error) => return self.raise(error),
+                    };
+                    let handlers = Rc::new(Handlers {
+                        clauses: vec![(handler.clone(), handles)],
+                        ret: None,
+                        env: frame.env.clone(),
+                    });
+                    self.stack.push(Frame::Handler(handlers, env::mark()));
+                    frame.last = Value::Nil;
+                }
+                StmtKind::Install(value) => {
+                    let env = frame.env.clone();
+                    self.stack.push(Frame::Block(frame));
+                    self.control = Control::Eval(value.clone(), env);
+                    return;
+                }
+                StmtKind::Import { .. } => {
+                    return self.raise(error("imports are not supported yet", stmt.span));
+                }
+                StmtKind::Defer { body, assigned } => {
+                    frame.defers.push(Deferred {
+                        body: body.clone(),
+                        env: capture(&frame.env, assigned),
+                    });
+                    frame.last = Value::Nil;
+                }
+            }
+            frame.idx += 1;
+        }
+        self.leave(frame.defers, Exit::Value(frame.last));
+    }
+
+    /// Run `defers`, last first, then leave their block by `exit`.
+    fn leave(&mut self, mut defers: Vec<Deferred>, exit: Exit) {
+        let Some(deferred) = defers.pop() else {
+            return match exit {
+                Exit::Value(value) => self.ret(value),
+                Exit::Unwind(unwind) => self.control = Control::Unwind(unwind),
+            };
+        };
+        self.stack.push(Frame::Defer {
+            defers,
+            exit,
+            deadline: None,
+        });
+        self.enter_block(deferred.body, deferred.env);
+    }
+
+    /// Create the closure for a `fn` statement and fill in its hoisted
+    /// binding.
+    fn define_fn(&mut self, def: &Rc<crate::ast::FnDef>, env: &Env) -> Value {
+        let closure = Value::Func(Rc::new(Closure {
+            def: def.clone(),
+            env: env.clone(),
+        }));
+        match def.name.as_deref().and_then(|name| env.lookup(name)) {
+            Some(Slot::Hoisted(cell)) => {
+                *cell.borrow_mut() = Some(closure);
+                Value::Nil
+            }
+            _ => closure,
+        }
+    }
+
+    fn match_arms(&mut self, expr: Rc<Expr>, from: usize, subject: Value, env: Env) {
+        let ExprKind::Match { arms, .. } = &expr.kind else {
+            unreachable!()
+        };
+        for (i, arm) in arms.iter().enumerate().skip(from) {
+            let Ok(bound) = bind_pattern(&arm.pattern, subject.clone(), &env, arm.pattern.span)
+            else {
+                continue;
+            };
+            match &arm.guard {
+                Some(guard) => {
+                    let guard = guard.clone();
+                    self.stack.push(Frame::Guard {
+                        expr: expr.clone(),
+                        arm: i,
+                        span: guard.span,
+                        subject,
+                        env,
+                        bound: bound.clone(),
+                    });
+                    self.control = Control::Eval(guard, bound);
+                }
+                None => self.control = Control::Eval(arm.body.clone(), bound),
+            }
+            return;
+        }
+        self.raise(
+            error("non-exhaustive pattern match", expr.span)
+                .with_note(format!("no arm matches {}", subject.repr())),
+        );
+    }
+
+    // ---------------------------------------------------------------------
+    // Operators, calls and access
+    // ---------------------------------------------------------------------
+
+    /// Complete `expr` once all of its operands are evaluated.
+    fn operate(&mut self, expr: Rc<Expr>, values: Vec<Value>, env: Env) {
+        let span = expr.span;
+        let mut values = values.into_iter();
+        let mut next = || values.next().expect("operand count matches");
+        let result = match &expr.kind {
+            ExprKind::Unary { op, .. } => unary(*op, next(), span),
+            ExprKind::Binary { op, .. } => {
+                let lhs = next();
+                binary(*op, lhs, next(), span)
+            }
+            ExprKind::Compare { rest, .. } => {
+                let mut prev = next();
+                let mut result = Ok(Value::Bool(true));
+                for (op, _) in rest {
+                    let value = next();
+                    match binary(*op, prev, value.clone(), span) {
+                        Ok(Value::Bool(true)) => prev = value,
+                        other => {
+                            result = other;
+                            break;
+                        }
+                    }
+                }
+                result
+            }
+            ExprKind::List(items) => build_list(items, &mut next),
+            ExprKind::Object(items) => build_object(items, &mut next),
+            ExprKind::Interp(parts) => {
+                let mut text = String::new();
+                for part in parts {
+                    match part {
+                        InterpPart::Text(s) => text.push_str(s),
+                        InterpPart::Expr(_) => text.push_str(&next().to_string()),
+                    }
+                }
+                Ok(Value::str(&text))
+            }
+            ExprKind::Call { args, .. } => {
+                let func = next();
+                let result = collect_args(args, &mut next)
+                    .and_then(|args| self.call(func, args, None, span));
+                if let Err(error) = result {
+                    self.raise(error);
+                }
+                return;
+            }
+            ExprKind::Apply { args, .. } => {
+                let subject = next();
+                let func = next();
+                let result = match args {
+                    Some(args) => collect_args(args, &mut next),
+                    None => Ok(Args::default()),
+                };
+                let given = args.is_some();
+                let result = result.and_then(|args| {
+                    self.push_in_place(&expr, &subject, &func, &args, &env)?;
+                    self.apply(subject, func, args, given, span)
+                });
+                if let Err(error) = result {
+                    self.raise(error);
+                }
+                return;
+            }
+            ExprKind::As { name, .. } => {
+                let value = next();
+                self.bind_in_block(name, value.clone());
+                Ok(value)
+            }
+            ExprKind::Try(_) => match next() {
+                Value::Tagged(tagged) if &*tagged.tag == "Ok" => Ok(tagged.value.clone()),
+                Value::Tagged(tagged) if &*tagged.tag == "Err" => {
+                    Err(thrown(tagged.value.clone(), span))
+                }
+                other => Ok(other),
+            },
+            ExprKind::Field { name, .. } => self.field(next(), name, &env, span),
+            ExprKind::Index { .. } => {
+                let target = next();
+                index(target, next(), span)
+            }
+            ExprKind::Ref { .. } => Ok(next()),
+            ExprKind::Assign { target, .. } => assign(target, next(), &env).map(|()| Value::Nil),
+            _ => unreachable!("not an operator expression"),
+        };
+        self.finish(result);
+    }
+
+    /// In `x = x push(item)`, take the list out of `x` before `push` runs,
+    /// so that `push` holds the only reference to it and appends in place
+    /// rather than copying it. The assignment puts it back straight after,
+    /// with nothing run in between. The call's cancellation point is passed
+    /// first, so once the list is taken the call cannot fail.
+    fn push_in_place(
+        &self,
+        expr: &Expr,
+        subject: &Value,
+        func: &Value,
+        args: &Args,
+        env: &Env,
+    ) -> Result<()> {
+        let (Value::List(list), Value::Builtin(builtin)) = (subject, func) else {
+            return Ok(());
+        };
+        if builtin.name != "push" || args.positional.len() != 1 || !args.named.is_empty() {
+            return Ok(());
+        }
+        let ExprKind::Apply { subject: read, .. } = &expr.kind else {
+            return Ok(());
+        };
+        let ExprKind::Ident(name) = &read.kind else {
+            return Ok(());
+        };
+        let Some(Frame::Operands { expr: parent, .. }) = self.stack.last() else {
+            return Ok(());
+        };
+        let ExprKind::Assign { target, .. } = &parent.kind else {
+            return Ok(());
+        };
+        if !matches!(&target.kind, ExprKind::Ident(assigned) if assigned == name) {
+            return Ok(());
+        }
+        if let Some(Slot::Mut(cell)) = env.lookup(name) {
+            self.checkpoint()?;
+            let mut held = cell.borrow_mut();
+            if matches!(&*held, Value::List(old) if Rc::ptr_eq(old, list)) {
+                *held = Value::Nil;
+            }
+        }
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str, env: &Env, span: Span) -> Result<Value> {
+        match env.lookup(name) {
+            Some(Slot::Value(value)) => Ok(value.clone()),
+            Some(Slot::Mut(cell)) => Ok(cell.borrow().clone()),
+            Some(Slot::Hoisted(cell)) => cell
+                .borrow()
+                .clone()
+                .ok_or_else(|| error(format!("variable `{}` used before definition", name), span)),
+            None => match builtins::lookup(name) {
+                Some(builtin) => Ok(Value::Builtin(builtin)),
+                None => Err(self.undefined(name, env, span)),
+            },
+        }
+    }
+
+    /// Explain why `name` is not in scope.
+    fn undefined(&self, name: &str, env: &Env, span: Span) -> Error {
+        if name == "_" {
+            return error("cannot use `_` as variable", span)
+                .with_note("`_` discards a value in a pattern; it never binds one");
+        }
+        for frame in self.stack.iter().rev() {
+            let Frame::Block(frame) = frame else {
+                continue;
+            };
+            for (i, stmt) in frame.block.stmts.iter().enumerate().skip(frame.idx) {
+                if let StmtKind::Let { pattern, .. } = &stmt.kind
+                    && binds(pattern, name)
+                {
+                    if i == frame.idx {
+                        return error("cannot reference value during its own initialization", span)
+                            .with_label(pattern.span, format!("`{}` is being defined here", name));
+                    }
+                    return error(format!("variable `{}` used before definition", name), span)
+                        .with_label(pattern.span, format!("`{}` is defined here", name));
+                }
+            }
+        }
+        let mut err = error(format!("undefined variable `{}`", name), span);
+        let mut candidates: Vec<&str> = env.names().collect();
+        for builtin in builtins::names() {
+            candidates.push(builtin);
+        }
+        if let Some(suggestion) = closest(name, candidates.into_iter()) {
+            err = err.with_note(format!("did you mean `{}`?", suggestion));
+        }
+        err
+    }
+
+    /// Bind `name` in the scope of the innermost enclosing block, so that
+    /// `expr as name` is visible to the statements after it.
+    fn bind_in_block(&mut self, name: &str, value: Value) {
+        for frame in self.stack.iter_mut().rev() {
+            match frame {
+                Frame::Block(frame) => {
+                    frame.env = frame.env.bind(name, value);
+                    return;
+                }
+                // The block was left in tail position: nothing follows.
+                Frame::Call => return,
+                _ => {}
+            }
+        }
+    }
+
+    /// Postfix application: `subject func` or `subject func(args)`.
+    ///
+    /// When the function needs more arguments than were written, the
+    /// subject becomes its first argument. Otherwise the subject is the
+    /// function's implicit subject.
+    fn apply(
+        &mut self,
+        subject: Value,
+        func: Value,
+        mut args: Args,
+        given: bool,
+        span: Span,
+    ) -> Result<()> {
+        let supplied = args.positional.len() + args.named.len();
+        match &func {
+            Value::Func(closure) => {
+                let required = closure
+                    .def
+                    .params
+                    .iter()
+                    .filter(|p| p.default.is_none() && !p.rest)
+                    .count();
+                if supplied < required {
+                    args.positional.insert(0, subject.clone());
+                } else if given && !closure.def.uses_subject {
+                    // `x | add(1)` where `add(n)` returns a function.
+                    self.stack.push(Frame::ApplyResult {
+                        subject: subject.clone(),
+                        span,
+                    });
+                }
+                self.call(func, args, Some(subject), span)
+            }
+            Value::Builtin(builtin) => {
+                if supplied < builtin.arity {
+                    args.positional.insert(0, subject);
+                }
+                self.call(func, args, None, span)
+            }
+            Value::Effect(effect) => {
+                if supplied < effect.arity {
+                    args.positional.insert(0, subject);
+                }
+                self.call(func, args, None, span)
+            }
+            Value::Resume(_) => {
+                if supplied == 0 {
+                    args.positional.insert(0, subject);
+                }
+                self.call(func, args, None, span)
+            }
+            _ => self.call(func, args, Some(subject), span),
+        }
+    }
+
+    fn call(
+        &mut self,
+        func: Value,
+        mut args: Args,
+        subject: Option<Value>,
+        span: Span,
+    ) -> Result<()> {
+        self.checkpoint()?;
+        match func {
+            Value::Func(closure) => {
+                let params = &closure.def.params;
+                let rest = params.iter().any(|p| p.rest);
+                if !rest && args.positional.len() > params.len() {
+                    return Err(arg_count(params.len(), args.positional.len(), span));
+                }
+                let env = closure.env.clone();
+                self.bind_params(Box::new(Binding {
+                    closure,
+                    args,
+                    idx: 0,
+                    env,
+                    subject,
+                    span,
+                }));
+                // A task that only recurses still uses up its time slice.
+                self.step();
+                Ok(())
+            }
+            Value::Builtin(builtin) => {
+                if let Some((name, _)) = args.named.first() {
+                    return Err(error(
+                        format!("`{}` takes no named argument `{}`", builtin.name, name),
+                        span,
+                    ));
+                }
+                let value = (builtin.func)(self, args.positional, span)?;
+                self.ret(value);
+                Ok(())
+            }
+            Value::Bound(bound) => {
+                let (func, receiver) = (*bound).clone();
+                args.positional.insert(0, receiver);
+                self.call(func, args, subject, span)
+            }
+            Value::Effect(effect) => {
+                if let Some((name, _)) = args.named.first() {
+                    return Err(error(
+                        format!(
+                            "effect `{}` takes no named argument `{}`",
+                            effect.name, name
+                        ),
+                        span,
+                    ));
+                }
+                if args.positional.len() != effect.arity {
+                    return Err(arg_count(effect.arity, args.positional.len(), span));
+                }
+                self.perform(effect, args.positional, span)
+            }
+            Value::Resume(continuation) => {
+                if !args.named.is_empty() || args.positional.len() > 1 {
+                    return Err(arg_count(1, args.positional.len() + args.named.len(), span));
+                }
+                let value = args.positional.pop().unwrap_or(Value::Nil);
+                self.continue_with(&continuation, value, span)
+            }
+            other => Err(error(format!("cannot call {}", other.type_name()), span)),
+        }
+    }
+
+    /// Bind parameters from `binding.idx` on, then run the body. Stops to
+    /// evaluate a default value when an argument is missing.
+    fn bind_params(&mut self, mut binding: Box<Binding>) {
+        let closure = binding.closure.clone();
+        let params = &closure.def.params;
+        while let Some(param) = params.get(binding.idx) {
+            let name = param.name();
+            let value = if param.rest {
+                let rest = binding
+                    .args
+                    .positional
+                    .split_off(binding.idx.min(binding.args.positional.len()));
+                Some(Value::list(rest))
+            } else if let Some(i) = binding
+                .args
+                .named
+                .iter()
+                .position(|(n, _)| Some(&**n) == name)
+            {
+                Some(binding.args.named.remove(i).1)
+            } else if binding.idx < binding.args.positional.len() {
+                Some(std::mem::replace(
+                    &mut binding.args.positional[binding.idx],
+                    Value::Nil,
+                ))
+            } else {
+                None
+            };
+            match (value, &param.default) {
+                (Some(value), _) => {
+                    match bind_pattern(&param.pattern, value, &binding.env, param.span) {
+                        Ok(env) => binding.env = env,
+                        Err(error) => return self.raise(error),
+                    }
+                }
+                (None, Some(default)) => {
+                    let env = binding.env.clone();
+                    let default = default.clone();
+                    self.stack.push(Frame::Params(binding));
+                    self.control = Control::Eval(default, env);
+                    return;
+                }
+                (None, None) => {
+                    let supplied = binding.args.positional.len() + binding.args.named.len();
+                    let required = params
+                        .iter()
+                        .filter(|p| p.default.is_none() && !p.rest)
+                        .count();
+                    return self.raise(arg_count(required, supplied, binding.span));
+                }
+            }
+            binding.idx += 1;
+        }
+        if let Some((name, _)) = binding.args.named.first() {
+            return self.raise(error(format!("unknown argument `{}`", name), binding.span));
+        }
+        let mut env = binding.env;
+        if let Some(subject) = binding.subject {
+            env = env.bind(SUBJECT, subject);
+        }
+        // A call in tail position reuses its caller's boundary.
+        if !matches!(self.stack.last(), Some(Frame::Call)) {
+            if self.stack.len() >= MAX_FRAMES {
+                return self.raise(error("stack overflow: recursion too deep", binding.span));
+            }
+            self.stack.push(Frame::Call);
+        }
+        self.enter_block(closure.def.body.clone(), env);
+    }
+
+    // ---------------------------------------------------------------------
+    // Effects
+    // ---------------------------------------------------------------------
+
+    /// What the handler `with name(...)` handles: the effect `name` is
+    /// bound to, or a built-in one.
+    fn handles(&self, handler: &Handler, env: &Env) -> Result<Handles> {
+        let name = handler.effect.as_str();
+        match self.lookup(name, env, handler.span) {
+            Ok(Value::Effect(effect)) => Ok(Handles::Effect(effect)),
+            _ if env.lookup(name).is_some() => {
+                Err(error(format!("`{}` is not an effect", name), handler.span))
+            }
+            _ => match name {
+                "panic" => Ok(Handles::Fault(Fault::Panic)),
+                "error" => Ok(Handles::Fault(Fault::Error)),
+                "cancel" => Ok(Handles::Fault(Fault::Cancel)),
+                _ => Err(error(format!("cannot handle `{}` yet", name), handler.span)),
+            },
+        }
+    }
+
+    /// Perform `effect`: capture the frames up to the innermost handler
+    /// for it as a continuation and run the handler below them.
+    fn perform(&mut self, effect: Rc<Effect>, args: Vec<Value>, span: Span) -> Result<()> {
+        let found = self
+            .stack
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(at, frame)| match frame {
+                Frame::Handler(handlers, _) => Some((at, handlers.effect(&effect)?.clone())),
+                _ => None,
+            });
+        let Some((at, handler)) = found else {
+            return Err(error(format!("unhandled effect: {}", effect.name), span));
+        };
+        // A `Never` handler has nothing to resume: the computation unwinds
+        // to it, running its `defer`s on the way.
+        if effect.resume == ResumeKind::Never {
+            self.control = Control::Unwind(Unwind::Abort {
+                effect,
+                args,
+                span,
+                suppressed: Vec::new(),
+            });
+            return Ok(());
+        }
+        let mut frames = self.stack.split_off(at);
+        let Frame::Handler(handlers, mark) = &frames[0] else {
+            unreachable!("the search stopped at a handler");
+        };
+        let (env, mark) = (handlers.env.clone(), *mark);
+        // A shallow handler is not part of the computation it resumes.
+        if handler.shallow {
+            frames.remove(0);
+        }
+        let held = match effect.resume {
+            ResumeKind::Many | ResumeKind::Fork => held(&frames, &env),
+            ResumeKind::Never | ResumeKind::Once => None,
+        };
+        let continuation = Continuation {
+            effect,
+            frames: RefCell::new(Some(frames)),
+            held,
+            mark,
+            resumed: Cell::new(false),
+        };
+        let continuation = Rc::new(continuation);
+        self.stack.push(Frame::Handled(continuation.clone()));
+        let env = env.bind("resume", Value::Resume(continuation));
+        self.handle(&handler, args, env, span)
+    }
+
+    /// Run `handler`'s body with its parameters bound to `args`. Its value
+    /// is the value of the scope it handles, unless it resumes.
+    fn handle(
+        &mut self,
+        handler: &Handler,
+        args: Vec<Value>,
+        mut env: Env,
+        span: Span,
+    ) -> Result<()> {
+        if args.len() != handler.params.len() {
+            return Err(error(
+                format!(
+                    "handler for `{}` takes {} parameter{}, but the effect passes {}",
+                    handler.effect,
+                    handler.params.len(),
+                    if handler.params.len() == 1 { "" } else { "s" },
+                    args.len()
+                ),
+                handler.span,
+            )
+            .with_label(span, "the effect is performed here"));
+        }
+        for (param, value) in handler.params.iter().zip(args) {
+            env = bind_pattern(&param.pattern, value, &env, param.span)?;
+        }
+        self.enter_block(handler.body.clone(), env);
+        Ok(())
+    }
+
+    /// The arguments for the handler of a failure: the effect's, then the
+    /// cleanup failures suppressed while it propagated, as a list, if the
+    /// handler takes a parameter for them. Otherwise they are logged.
+    fn failure_args(
+        &mut self,
+        handler: &Handler,
+        mut args: Vec<Value>,
+        suppressed: Vec<Panic>,
+    ) -> Vec<Value> {
+        if handler.params.len() > args.len() {
+            args.push(Value::list(suppressed.iter().map(Panic::record).collect()));
+            return args;
+        }
+        for failure in suppressed {
+            let cleanup = failure.diagnostic;
+            self.warnings.push(
+                Diagnostic::warning(format!("cleanup failed: {}", cleanup.message), cleanup.span)
+                    .with_label(handler.span, "the original failure was handled here")
+                    .with_note(format!(
+                        "take the cleanup failures as data with an extra parameter: `with {}(..., cleanup) {{ ... }}`",
+                        handler.effect
+                    )),
+            );
+        }
+        args
+    }
+
+    /// Carry on from a handler clause, left by `exit`. If the clause didn't
+    /// resume `continuation` and nothing else kept it, it is dropped: its
+    /// frames are unwound first, so the `defer`s of the abandoned
+    /// computation run.
+    fn abandon(&mut self, continuation: Rc<Continuation>, exit: Exit) {
+        let frames = match Rc::strong_count(&continuation) == 1 && !continuation.resumed.get() {
+            true => continuation.frames.borrow_mut().take(),
+            false => None,
+        };
+        match frames {
+            Some(frames) => {
+                self.stack.push(Frame::Abandoned(exit));
+                self.stack.extend(frames);
+                self.control = Control::Unwind(Unwind::Abandon);
+            }
+            None => self.leave(Vec::new(), exit),
+        }
+    }
+
+    /// `resume(value)`: put the continuation's frames back on the stack
+    /// and deliver `value` where the effect was performed.
+    fn continue_with(
+        &mut self,
+        continuation: &Continuation,
+        value: Value,
+        span: Span,
+    ) -> Result<()> {
+        let frames = match continuation.effect.resume {
+            ResumeKind::Many | ResumeKind::Fork => {
+                if continuation.resumed.replace(true)
+                    && let Some(held) = &continuation.held
+                {
+                    return Err(error(
+                        format!(
+                            "cannot resume `{}` again: the computation holds {}",
+                            continuation.effect.name, held
+                        ),
+                        span,
+                    )
+                    .with_note("resuming again would run a copy of the computation, and open resources cannot be copied"));
+                }
+                // The frames run as they are, leaving a copy of them as
+                // they were for the next resume.
+                let mut frames = continuation.frames.borrow_mut();
+                let copy = frames
+                    .as_deref()
+                    .map(|frames| Fork::new(continuation.mark).frames(frames));
+                std::mem::replace(&mut *frames, copy)
+            }
+            ResumeKind::Never | ResumeKind::Once => continuation.frames.borrow_mut().take(),
+        };
+        let Some(frames) = frames else {
+            return Err(error("`resume` called twice", span)
+                .with_note("a handler can continue the computation it handles only once"));
+        };
+        // A resume in tail position is the end of the handler clause, and
+        // nothing is left to abandon.
+        if let Some(Frame::Handled(handled)) = self.stack.last()
+            && std::ptr::eq(Rc::as_ptr(handled), continuation)
+        {
+            self.stack.pop();
+        }
+        self.stack.extend(frames);
+        self.ret(value);
+        Ok(())
+    }
+
+    /// `value.name`. Objects give their field; on other values, or when
+    /// an object lacks the field, a function called `name` in scope is
+    /// bound to the value as its first argument (`"hello".len()`).
+    fn field(&self, value: Value, name: &str, env: &Env, span: Span) -> Result<Value> {
+        if let Value::Object(object) = &value
+            && let Some(field) = object.get_name(name)
+        {
+            return Ok(field.clone());
+        }
+        match self.lookup(name, env, span) {
+            Ok(func) if func.is_callable() => Ok(Value::Bound(Rc::new((func, value)))),
+            _ => match value {
+                Value::Object(_) => Err(error(format!("key `{}` not found", name), span)),
+                other => Err(error(
+                    format!("no field `{}` on {}", name, other.type_name()),
+                    span,
+                )),
+            },
+        }
+    }
+}
+
+/// A new effect for `decl`, distinct from every other.
+fn new_effect(decl: &EffectDecl) -> Value {
+    Value::Effect(Rc::new(Effect {
+        name: Rc::from(decl.name.as_str()),
+        arity: decl.params.len(),
+        resume: decl.resume,
+    }))
+}
+
+/// The `i`th operand of an operator expression, in evaluation order.
+fn operand(expr: &Expr, i: usize) -> Option<&Rc<Expr>> {
+    match &expr.kind {
+        ExprKind::Unary { expr, .. }
+        | ExprKind::As { expr, .. }
+        | ExprKind::Try(expr)
+        | ExprKind::Field { expr, .. }
+        | ExprKind::Ref { expr, .. } => (i == 0).then_some(expr),
+        ExprKind::Assign { value, .. } => (i == 0).then_some(value),
+        ExprKind::Binary { lhs, rhs, .. } => [lhs, rhs].get(i).copied(),
+        ExprKind::Index { expr, index } => [expr, index].get(i).copied(),
+        ExprKind::Compare { first, rest } => match i {
+            0 => Some(first),
+            _ => rest.get(i - 1).map(|(_, e)| e),
+        },
+        ExprKind::Call { func, args } => match i {
+            0 => Some(func),
+            _ => args.get(i - 1).map(|a| &a.value),
+        },
+        ExprKind::Apply {
+            func,
+            subject,
+            args,
+        } => match i {
+            0 => Some(subject),
+            1 => Some(func),
+            _ => args.as_ref()?.get(i - 2).map(|a| &a.value),
+        },
+        ExprKind::List(items) => items.get(i).map(|item| &item.expr),
+        ExprKind::Object(items) => items
+            .iter()
+            .flat_map(|item| match item {
+                ObjectItem::Field {
+                    key: KeyExpr::Computed(key),
+                    value,
+                } => vec![key, value],
+                ObjectItem::Field { value, .. } => vec![value],
+                ObjectItem::Spread(expr) => vec![expr],
+            })
+            .nth(i),
+        ExprKind::Interp(parts) => parts
+            .iter()
+            .filter_map(|part| match part {
+                InterpPart::Expr(expr) => Some(expr),
+                InterpPart::Text(_) => None,
+            })
+            .nth(i),
+        _ => None,
+    }
+}
+
+/// Copies the part of a continuation made since its handler's
+/// [`env::mark`], so that resuming it again starts from the state it was
+/// captured in. Bindings made since then get fresh cells; older ones, from
+/// outside the handler, are shared. Each binding and continuation is copied
+/// once, so whatever shared one before shares its copy. Closures and
+/// continuations are copied where they are bound or held by a frame, but
+/// not inside lists or objects.
+struct Fork {
+    mark: u64,
+    bindings: HashMap<*const env::Binding, Env>,
+    continuations: HashMap<*const Continuation, Rc<Continuation>>,
+}
+
+impl Fork {
+    fn new(mark: u64) -> Fork {
+        Fork {
+            mark,
+            bindings: HashMap::new(),
+            continuations: HashMap::new(),
+        }
+    }
+
+    fn env(&mut self, env: &Env) -> Env {
+        let Some(binding) = env.binding() else {
+            return Env::default();
+        };
+        if binding.serial < self.mark {
+            return env.clone();
+        }
+        let key = Rc::as_ptr(binding);
+        if let Some(copy) = self.bindings.get(&key) {
+            return copy.clone();
+        }
+        let next = self.env(binding.next());
+        // A cell is filled after its copy is recorded, since the closure
+        // it holds may refer back to it.
+        let slot = match &binding.slot {
+            Slot::Value(value) => Slot::Value(self.value(value)),
+            Slot::Mut(_) => Slot::Mut(Rc::new(RefCell::new(Value::Nil))),
+            Slot::Hoisted(_) => Slot::Hoisted(Rc::new(RefCell::new(None))),
+        };
+        let copy = next.rebind(binding, slot);
+        self.bindings.insert(key, copy.clone());
+        match (&binding.slot, copy.binding().map(|copy| &copy.slot)) {
+            (Slot::Mut(old), Some(Slot::Mut(new))) => {
+                let value = old.borrow().clone();
+                *new.borrow_mut() = self.value(&value);
+            }
+            (Slot::Hoisted(old), Some(Slot::Hoisted(new))) => {
+                let value = old.borrow().clone();
+                *new.borrow_mut() = value.map(|value| self.value(&value));
+            }
+            _ => {}
+        }
+        copy
+    }
+
+    fn value(&mut self, value: &Value) -> Value {
+        match value {
+            Value::Func(closure) => Value::Func(self.closure(closure)),
+            Value::Bound(bound) => {
+                Value::Bound(Rc::new((self.value(&bound.0), self.value(&bound.1))))
+            }
+            Value::Resume(continuation) => Value::Resume(self.continuation(continuation)),
+            other => other.clone(),
+        }
+    }
+
+    fn closure(&mut self, closure: &Rc<Closure>) -> Rc<Closure> {
+        Rc::new(Closure {
+            def: closure.def.clone(),
+            env: self.env(&closure.env),
+        })
+    }
+
+    fn continuation(&mut self, continuation: &Rc<Continuation>) -> Rc<Continuation> {
+        let key = Rc::as_ptr(continuation);
+        if let Some(copy) = self.continuations.get(&key) {
+            return copy.clone();
+        }
+        let copy = Rc::new(Continuation {
+            effect: continuation.effect.clone(),
+            frames: RefCell::new(None),
+            held: continuation.held.clone(),
+            mark: continuation.mark,
+            resumed: Cell::new(continuation.resumed.get()),
+        });
+        self.continuations.insert(key, copy.clone());
+        let frames = continuation.frames.borrow().clone();
+        *copy.frames.borrow_mut() = frames.map(|frames| self.frames(&frames));
+        copy
+    }
+
+    fn args(&mut self, args: &Args) -> Args {
+        Args {
+            positional: args.positional.iter().map(|v| self.value(v)).collect(),
+            named: args
+                .named
+                .iter()
+                .map(|(name, v)| (name.clone(), self.value(v)))
+                .collect(),
+        }
+    }
+
+    fn defers(&mut self, defers: &[Deferred]) -> Vec<Deferred> {
+        defers
+            .iter()
+            .map(|deferred| Deferred {
+                body: deferred.body.clone(),
+                env: self.env(&deferred.env),
+            })
+            .collect()
+    }
+
+    fn frames(&mut self, frames: &[Frame]) -> Vec<Frame> {
+        frames.iter().map(|frame| self.frame(frame)).collect()
+    }
+
+    fn frame(&mut self, frame: &Frame) -> Frame {
+        match frame {
+            Frame::Block(frame) => Frame::Block(BlockFrame {
+                block: frame.block.clone(),
+                idx: frame.idx,
+                env: self.env(&frame.env),
+                last: self.value(&frame.last),
+                defers: self.defers(&frame.defers),
+            }),
+            Frame::Operands { expr, values, env } => Frame::Operands {
+                expr: expr.clone(),
+                values: values.iter().map(|v| self.value(v)).collect(),
+                env: self.env(env),
+            },
+            Frame::Logic { op, rhs, env, span } => Frame::Logic {
+                op: *op,
+                rhs: rhs.clone(),
+                env: self.env(env),
+                span: *span,
+            },
+            Frame::If {
+                then,
+                otherwise,
+                env,
+                span,
+            } => Frame::If {
+                then: then.clone(),
+                otherwise: otherwise.clone(),
+                env: self.env(env),
+                span: *span,
+            },
+            Frame::Match { expr, env } => Frame::Match {
+                expr: expr.clone(),
+                env: self.env(env),
+            },
+            Frame::Guard {
+                expr,
+                arm,
+                span,
+                subject,
+                env,
+                bound,
+            } => Frame::Guard {
+                expr: expr.clone(),
+                arm: *arm,
+                span: *span,
+                subject: self.value(subject),
+                env: self.env(env),
+                bound: self.env(bound),
+            },
+            Frame::Loop { body, env } => Frame::Loop {
+                body: body.clone(),
+                env: self.env(env),
+            },
+            Frame::While {
+                cond,
+                body,
+                env,
+                in_body,
+            } => Frame::While {
+                cond: cond.clone(),
+                body: body.clone(),
+                env: self.env(env),
+                in_body: *in_body,
+            },
+            Frame::Params(binding) => Frame::Params(Box::new(Binding {
+                closure: self.closure(&binding.closure),
+                args: self.args(&binding.args),
+                idx: binding.idx,
+                env: self.env(&binding.env),
+                subject: binding.subject.as_ref().map(|v| self.value(v)),
+                span: binding.span,
+            })),
+            Frame::ApplyResult { subject, span } => Frame::ApplyResult {
+                subject: self.value(subject),
+                span: *span,
+            },
+            Frame::Handler(handlers, mark) => Frame::Handler(
+                Rc::new(Handlers {
+                    clauses: handlers.clauses.clone(),
+                    ret: handlers.ret.clone(),
+                    env: self.env(&handlers.env),
+                }),
+                *mark,
+            ),
+            Frame::Defer {
+                defers,
+                exit,
+                deadline,
+            } => Frame::Defer {
+                defers: self.defers(defers),
+                exit: match exit {
+                    Exit::Value(value) => Exit::Value(self.value(value)),
+                    Exit::Unwind(unwind) => Exit::Unwind(unwind.clone()),
+                },
+                deadline: *deadline,
+            },
+            Frame::Handled(continuation) => Frame::Handled(self.continuation(continuation)),
+            Frame::Abandoned(Exit::Value(value)) => {
+                Frame::Abandoned(Exit::Value(self.value(value)))
+            }
+            frame => frame.clone(),
+        }
+    }
+}
+
+/// Describe an open resource held by `frames`, for messages. The frames
+/// run inside `outer`; bindings they share with it live outside the
+/// continuation and are not copied with it.
+fn held(frames: &[Frame], outer: &Env) -> Option<String> {
+    let outside: Vec<*const env::Binding> = outer.bindings().map(|b| b as *const _).collect();
+    let describe = |resource: &value::Resource| format!("an open `{}`", resource.kind);
+    for frame in frames {
+        let (env, values): (Option<&Env>, Vec<&Value>) = match frame {
+            Frame::Block(frame) => (Some(&frame.env), vec![&frame.last]),
+            Frame::Operands { values, env, .. } => (Some(env), values.iter().collect()),
+            Frame::Guard { subject, bound, .. } => (Some(bound), vec![subject]),
+            Frame::Params(binding) => {
+                let args = &binding.args;
+                let values = args.positional.iter();
+                let values = values.chain(args.named.iter().map(|(_, v)| v));
+                (Some(&binding.env), values.chain(&binding.subject).collect())
+            }
+            Frame::ApplyResult { subject, .. } => (None, vec![subject]),
+            Frame::Defer { exit, .. } => match exit {
+                Exit::Value(value) => (None, vec![value]),
+                Exit::Unwind(_) => (None, Vec::new()),
+            },
+            Frame::Logic { env, .. }
+            | Frame::If { env, .. }
+            | Frame::Match { env, .. }
+            | Frame::Loop { env, .. }
+            | Frame::While { env, .. } => (Some(env), Vec::new()),
+            Frame::Handler(handlers, _) => (Some(&handlers.env), Vec::new()),
+            Frame::Abandoned(exit) => match exit {
+                Exit::Value(value) => (None, vec![value]),
+                Exit::Unwind(_) => (None, Vec::new()),
+            },
+            Frame::Break(_)
+            | Frame::Return(_)
+            | Frame::Call
+            | Frame::Handled(_)
+            | Frame::Nursery(_)
+            | Frame::Wait(..)
+            | Frame::Combine(..) => (None, Vec::new()),
+        };
+        if let Some(resource) = values.into_iter().find_map(Value::open_resource) {
+            return Some(describe(resource));
+        }
+        for binding in env.into_iter().flat_map(Env::bindings) {
+            if outside.contains(&(binding as *const _)) {
+                break;
+            }
+            let value = match &binding.slot {
+                Slot::Value(value) => value.clone(),
+                Slot::Mut(cell) => cell.borrow().clone(),
+                Slot::Hoisted(_) => continue,
+            };
+            if let Some(resource) = value.open_resource() {
+                return Some(format!("`{}`, {}", binding.name, describe(resource)));
+            }
+        }
+    }
+    None
+}
+
+fn collect_args(args: &[Arg], next: &mut impl FnMut() -> Value) -> Result<Args> {
+    let mut out = Args::default();
+    for arg in args {
+        let value = next();
+        if let Some(name) = &arg.name {
+            out.named.push((Rc::from(name.as_str()), value));
+        } else if arg.spread {
+            match value {
+                Value::List(items) => out.positional.extend(items.iter().cloned()),
+                other => {
+                    return Err(error(
+                        format!("cannot spread {} into arguments", other.type_name()),
+                        arg.value.span,
+                    ));
+                }
+            }
+        } else {
+            out.positional.push(value);
+        }
+    }
+    Ok(out)
+}
+
+fn build_list(items: &[Item], next: &mut impl FnMut() -> Value) -> Result<Value> {
+    let mut out = Vec::with_capacity(items.len());
+    for item in items {
+        let value = next();
+        if !item.spread {
+            out.push(value);
+            continue;
+        }
+        match value {
+            Value::List(list) => out.extend(list.iter().cloned()),
+            other => {
+                return Err(error(
+                    format!("cannot spread {} into a list", other.type_name()),
+                    item.expr.span,
+                ));
+            }
+        }
+    }
+    Ok(Value::list(out))
+}
+
+fn build_object(items: &[ObjectItem], next: &mut impl FnMut() -> Value) -> Result<Value> {
+    let mut object = Object::default();
+    for item in items {
+        match item {
+            ObjectItem::Field { key, .. } => {
+                let key = match key {
+                    KeyExpr::Name(name) => Key::Name(Rc::from(name.as_str())),
+                    KeyExpr::Computed(expr) => match next() {
+                        Value::Symbol(symbol) => Key::Symbol(symbol),
+                        Value::Str(name) => Key::Name(name),
+                        other => {
+                            return Err(error(
+                                format!(
+                                    "object keys must be strings or symbols, got {}",
+                                    other.type_name()
+                                ),
+                                expr.span,
+                            ));
+                        }
+                    },
+                };
+                object.insert(key, next());
+            }
+            ObjectItem::Spread(expr) => match next() {
+                Value::Object(other) => {
+                    for (key, value) in &other.fields {
+                        object.insert(key.clone(), value.clone());
+                    }
+                }
+                other => {
+                    return Err(error(
+                        format!("cannot spread {} into an object", other.type_name()),
+                        expr.span,
+                    ));
+                }
+            },
+        }
+    }
+    Ok(Value::Object(Rc::new(object)))
+}
+
+fn unary(op: UnaryOp, value: Value, span: Span) -> Result<Value> {
+    match (op, value) {
+        (UnaryOp::Neg, Value::Int(n)) => n
+            .checked_neg()
+            .map(Value::Int)
+            .ok_or_else(|| arithmetic("integer overflow", "-", &[Value::Int(n)], span)),
+        (UnaryOp::Neg, Value::Float(x)) => Ok(Value::Float(-x)),
+        (UnaryOp::Not, Value::Bool(b)) => Ok(Value::Bool(!b)),
+        (UnaryOp::Neg, other) => Err(error(
+            format!("expected number, got {}", other.type_name()),
+            span,
+        )),
+        (UnaryOp::Not, other) => Err(error(
+            format!("expected bool, got {}", other.type_name()),
+            span,
+        )),
+    }
+}
+
+fn binary(op: BinOp, lhs: Value, rhs: Value, span: Span) -> Result<Value> {
+    use std::cmp::Ordering;
+    match op {
+        BinOp::Eq => return Ok(Value::Bool(lhs.equals(&rhs))),
+        BinOp::Ne => return Ok(Value::Bool(!lhs.equals(&rhs))),
+        BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => {
+            let ordering = match (&lhs, &rhs) {
+                (Value::Int(a), Value::Int(b)) => Some(a.cmp(b)),
+                (Value::Str(a), Value::Str(b)) => Some(a.cmp(b)),
+                _ => match (number(&lhs), number(&rhs)) {
+                    (Some(a), Some(b)) => a.partial_cmp(&b),
+                    _ => {
+                        return Err(error(
+                            format!(
+                                "cannot compare {} with {}",
+                                lhs.type_name(),
+                                rhs.type_name()
+                            ),
+                            span,
+                        ));
+                    }
+                },
+            };
+            let result = match ordering {
+                None => false,
+                Some(ordering) => match op {
+                    BinOp::Lt => ordering == Ordering::Less,
+                    BinOp::Le => ordering != Ordering::Greater,
+                    BinOp::Gt => ordering == Ordering::Greater,
+                    _ => ordering != Ordering::Less,
+                },
+            };
+            return Ok(Value::Bool(result));
+        }
+        _ => {}
+    }
+    match (&lhs, &rhs) {
+        (Value::Int(a), Value::Int(b)) => {
+            let (a, b) = (*a, *b);
+            if matches!(op, BinOp::Div | BinOp::Mod) && b == 0 {
+                return Err(arithmetic(
+                    "division by zero",
+                    op.as_str(),
+                    &[lhs, rhs],
+                    span,
+                ));
+            }
+            let result = match op {
+                BinOp::Add => a.checked_add(b),
+                BinOp::Sub => a.checked_sub(b),
+                BinOp::Mul => a.checked_mul(b),
+                BinOp::Div => a.checked_div(b),
+                BinOp::Mod => a.checked_rem(b),
+                _ => unreachable!(),
+            };
+            result.map(Value::Int).ok_or_else(|| {
+                arithmetic(
+                    "integer overflow",
+                    op.as_str(),
+                    &[lhs.clone(), rhs.clone()],
+                    span,
+                )
+            })
+        }
+        (Value::Str(a), _) if op == BinOp::Add => Ok(Value::str(&format!("{}{}", a, rhs))),
+        (Value::List(a), Value::List(b)) if op == BinOp::Add => {
+            Ok(Value::list(a.iter().chain(b.iter()).cloned().collect()))
+        }
+        _ => {
+            let a = number(&lhs)
+                .ok_or_else(|| error(format!("expected number, got {}", lhs.type_name()), span))?;
+            let b = number(&rhs)
+                .ok_or_else(|| error(format!("expected number, got {}", rhs.type_name()), span))?;
+            Ok(Value::Float(match op {
+                BinOp::Add => a + b,
+                BinOp::Sub => a - b,
+                BinOp::Mul => a * b,
+                BinOp::Div => a / b,
+                BinOp::Mod => a % b,
+                _ => unreachable!(),
+            }))
+        }
+    }
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Int(n) => Some(*n as f64),
+        Value::Float(x) => Some(*x),
+        _ => None,
+    }
+}
+
+/// `target.[index]`. Negative indices count from the end.
+fn index(target: Value, index: Value, span: Span) -> Result<Value> {
+    let position = |i: i64, len: usize| {
+        let at = if i < 0 { i + len as i64 } else { i };
+        if at < 0 || at >= len as i64 {
+            Err(error(
+                format!("index {} out of bounds for length {}", i, len),
+                span,
+            ))
+        } else {
+            Ok(at as usize)
+        }
+    };
+    match (&target, &index) {
+        (Value::List(items), Value::Int(i)) => Ok(items[position(*i, items.len())?].clone()),
+        (Value::Str(s), Value::Int(i)) => {
+            let len = s.chars().count();
+            let c = s.chars().nth(position(*i, len)?).unwrap_or_default();
+            Ok(Value::str(c.encode_utf8(&mut [0; 4])))
+        }
+        (Value::Object(object), Value::Symbol(symbol)) => object
+            .get(&Key::Symbol(symbol.clone()))
+            .cloned()
+            .ok_or_else(|| error(format!("key `{}` not found", symbol), span)),
+        (Value::Object(object), Value::Str(name)) => object
+            .get_name(name)
+            .cloned()
+            .ok_or_else(|| error(format!("key `{}` not found", name), span)),
+        _ => Err(error(
+            format!(
+                "cannot index {} with {}",
+                target.type_name(),
+                index.type_name()
+            ),
+            span,
+        )),
+    }
+}
+
+/// `target = value`, where the target is a variable or a field path
+/// rooted at one (`point.x = 1`).
+fn assign(target: &Expr, value: Value, env: &Env) -> Result<()> {
+    let mut path = Vec::new();
+    let mut root = target;
+    while let ExprKind::Field { expr, name } = &root.kind {
+        path.push(name.as_str());
+        root = expr;
+    }
+    let ExprKind::Ident(name) = &root.kind else {
+        return Err(error("cannot assign to this expression", target.span));
+    };
+    match env.lookup(name) {
+        Some(Slot::Mut(cell)) => {
+            let mut slot = cell.borrow_mut();
+            set_path(&mut slot, &path, value, target.span)
+        }
+        Some(_) => Err(error(
+            format!("cannot mutate immutable variable `{}`", name),
+            target.span,
+        )
+        .with_label(root.span, "cannot assign to immutable variable")
+        .with_note(format!(
+            "declare it with `let mut {}` to allow assignment",
+            name
+        ))),
+        None => Err(error(format!("undefined variable `{}`", name), root.span)),
+    }
+}
+
+/// Replace the field at `path` (innermost last) inside `slot`.
+fn set_path(slot: &mut Value, path: &[&str], value: Value, span: Span) -> Result<()> {
+    let Some((name, outer)) = path.split_last() else {
+        *slot = value;
+        return Ok(());
+    };
+    let Value::Object(object) = slot else {
+        return Err(error(
+            format!("no field `{}` on {}", name, slot.type_name()),
+            span,
+        ));
+    };
+    let object = Rc::make_mut(object);
+    let key = Key::Name(Rc::from(*name));
+    match object.fields.iter_mut().find(|(k, _)| k.same(&key)) {
+        Some((_, field)) => set_path(field, outer, value, span),
+        None if outer.is_empty() => {
+            object.fields.push((key, value));
+            Ok(())
+        }
+        None => Err(error(format!("key `{}` not found", name), span)),
+    }
+}
+
+/// Check a value against a simple `let` type annotation.
+fn check_annotation(ty: &TypeExpr, value: &Value) -> Result<()> {
+    let Some(name) = ty.simple_name() else {
+        return Ok(());
+    };
+    let expected = match name {
+        "Int" => "int",
+        "Float" => "float",
+        "String" => "string",
+        "Bool" => "bool",
+        _ => return Ok(()),
+    };
+    let actual = value.type_name();
+    if actual == expected {
+        return Ok(());
+    }
+    let mut chars = actual.chars();
+    let actual = match chars.next() {
+        Some(c) => c.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    };
+    Err(error(format!("expected {}, got {}", name, actual), ty.span))
+}
+
+/// Match `value` against `pattern`, extending `env` with its bindings.
+fn bind_pattern(pattern: &Pattern, value: Value, env: &Env, span: Span) -> Result<Env> {
+    match &pattern.kind {
+        PatternKind::Bind {
+            name,
+            mutable: false,
+        } => return Ok(env.bind(name, value)),
+        PatternKind::Bind {
+            name,
+            mutable: true,
+        } => return Ok(env.bind_mut(name, value)),
+        _ => {}
+    }
+    let mut bindings = Vec::new();
+    destructure(pattern, value, &mut bindings).map_err(|message| error(message, span))?;
+    let mut env = env.clone();
+    for (i, (name, mutable, value)) in bindings.iter().enumerate() {
+        if bindings[..i].iter().any(|(other, ..)| other == name) {
+            return Err(error(
+                format!("variable `{}` bound multiple times", name),
+                span,
+            ));
+        }
+        env = if *mutable {
+            env.bind_mut(name, value.clone())
+        } else {
+            env.bind(name, value.clone())
+        };
+    }
+    Ok(env)
+}
+
+type Bindings<'a> = Vec<(&'a str, bool, Value)>;
+
+fn destructure<'a>(
+    pattern: &'a Pattern,
+    value: Value,
+    out: &mut Bindings<'a>,
+) -> std::result::Result<(), String> {
+    match &pattern.kind {
+        PatternKind::Wildcard => Ok(()),
+        PatternKind::Bind { name, mutable } => {
+            out.push((name, *mutable, value));
+            Ok(())
+        }
+        PatternKind::Literal(expr) => {
+            let expected = literal(expr).ok_or("unsupported literal pattern")?;
+            if expected.equals(&value) {
+                Ok(())
+            } else {
+                Err(format!(
+                    "pattern match failed: expected {}, got {}",
+                    expected.repr(),
+                    value.repr()
+                ))
+            }
+        }
+        PatternKind::List { items, rest } => {
+            let Value::List(list) = value else {
+                return Err(format!(
+                    "pattern match failed: expected a list, got {}",
+                    value.type_name()
+                ));
+            };
+            if rest.is_none() && list.len() != items.len() {
+                return Err(format!(
+                    "expected {} elements, got {}",
+                    items.len(),
+                    list.len()
+                ));
+            }
+            if list.len() < items.len() {
+                return Err(format!(
+                    "expected at least {} elements, got {}",
+                    items.len(),
+                    list.len()
+                ));
+            }
+            for (item, value) in items.iter().zip(list.iter()) {
+                destructure(item, value.clone(), out)?;
+            }
+            if let Some(Some(name)) = rest {
+                out.push((name, false, Value::list(list[items.len()..].to_vec())));
+            }
+            Ok(())
+        }
+        PatternKind::Object { fields, rest } => {
+            let Value::Object(object) = value else {
+                return Err(format!(
+                    "pattern match failed: expected an object, got {}",
+                    value.type_name()
+                ));
+            };
+            for (name, pattern) in fields {
+                let field = object
+                    .get_name(name)
+                    .ok_or_else(|| format!("missing key `{}`", name))?;
+                destructure(pattern, field.clone(), out)?;
+            }
+            if let Some(Some(name)) = rest {
+                let remaining = object
+                    .fields
+                    .iter()
+                    .filter(|(key, _)| !matches!(key, Key::Name(k) if fields.iter().any(|(n, _)| n == &**k)))
+                    .cloned()
+                    .collect();
+                out.push((
+                    name,
+                    false,
+                    Value::Object(Rc::new(Object { fields: remaining })),
+                ));
+            }
+            Ok(())
+        }
+        PatternKind::Or(alternatives) => {
+            let mut last = String::from("pattern match failed");
+            for alternative in alternatives {
+                let mark = out.len();
+                match destructure(alternative, value.clone(), out) {
+                    Ok(()) => return Ok(()),
+                    Err(message) => {
+                        out.truncate(mark);
+                        last = message;
+                    }
+                }
+            }
+            Err(last)
+        }
+        PatternKind::Tagged { tag, inner } => match value {
+            Value::Tagged(tagged) if &*tagged.tag == tag => {
+                destructure(inner, tagged.value.clone(), out)
+            }
+            other => Err(format!(
+                "pattern match failed: expected {}(..), got {}",
+                tag,
+                other.repr()
+            )),
+        },
+    }
+}
+
+/// The value of a literal pattern.
+fn literal(expr: &Expr) -> Option<Value> {
+    Some(match &expr.kind {
+        ExprKind::Int(n) => Value::Int(*n),
+        ExprKind::Float(x) => Value::Float(*x),
+        ExprKind::Str(s) => Value::str(s),
+        ExprKind::Bool(b) => Value::Bool(*b),
+        ExprKind::Nil => Value::Nil,
+        ExprKind::Unary {
+            op: UnaryOp::Neg,
+            expr,
+        } => match literal(expr)? {
+            Value::Int(n) => Value::Int(-n),
+            Value::Float(x) => Value::Float(-x),
+            _ => return None,
+        },
+        _ => return None,
+    })
+}
+
+/// Whether `pattern` binds `name`.
+fn binds(pattern: &Pattern, name: &str) -> bool {
+    match &pattern.kind {
+        PatternKind::Bind { name: bound, .. } => bound == name,
+        PatternKind::List { items, rest } => {
+            items.iter().any(|p| binds(p, name)) || matches!(rest, Some(Some(r)) if r == name)
+        }
+        PatternKind::Object { fields, rest } => {
+            fields.iter().any(|(_, p)| binds(p, name)) || matches!(rest, Some(Some(r)) if r == name)
+        }
+        PatternKind::Or(alternatives) => alternatives.iter().any(|p| binds(p, name)),
+        PatternKind::Tagged { inner, .. } => binds(inner, name),
+        PatternKind::Wildcard | PatternKind::Literal(_) => false,
+    }
+}
+
+fn arg_count(expected: usize, got: usize, span: Span) -> Error {
+    error(
+        format!(
+            "expected {} argument{}, got {}",
+            expected,
+            if expected == 1 { "" } else { "s" },
+            got
+        ),
+        span,
+    )
+}
+
+/// The error for a jump that reached the bottom of the stack, or a
+/// function boundary, without finding its target.
+fn stray(unwind: Unwind) -> Error {
+    match unwind {
+        Unwind::Break(_, span) => error("`break` outside of a loop", span),
+        Unwind::Continue(span) => error("`continue` outside of a loop", span),
+        Unwind::Return(_, span) => error("`return` outside of a function", span),
+        Unwind::Panic(error) => error,
+        Unwind::Abort { effect, span, .. } => {
+            error(format!("unhandled effect: {}", effect.name), span)
+        }
+        Unwind::Abandon => unreachable!("an abandoned continuation unwinds to its own frame"),
+    }
+}
+
+/// The scope a `defer` body runs in. The `let mut` variables it reads
+/// are captured by value, so it sees them as they were when the `defer`
+/// ran; those it assigns to stay shared with the enclosing block.
+fn capture(env: &Env, assigned: &[String]) -> Env {
+    let mut seen: Vec<&str> = Vec::new();
+    let mut captured = env.clone();
+    for binding in env.bindings() {
+        if seen.contains(&&*binding.name) {
+            continue;
+        }
+        seen.push(&binding.name);
+        if let Slot::Mut(cell) = &binding.slot
+            && !assigned.iter().any(|name| **name == *binding.name)
+        {
+            captured = captured.bind(&binding.name, cell.borrow().clone());
+        }
+    }
+    captured
+}
+
+/// The candidate closest to `name` by edit distance, if any is close.
+fn closest<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let limit = (name.chars().count() / 3).max(1);
+    candidates
+        .filter(|c| *c != SUBJECT)
+        .map(|c| (edit_distance(name, c), c))
+        .filter(|(d, _)| *d <= limit)
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, c)| c)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cost = usize::from(ca != *cb);
+            let next = (row[j] + 1).min(row[j + 1] + 1).min(prev + cost);
+            prev = row[j + 1];
+            row[j + 1] = next;
+        }
+    }
+    row[b.len()]
+}
+
+/// Create a new symbol. Exposed for the `symbol` builtin.
+fn new_symbol(name: Option<Rc<str>>) -> Value {
+    Value::Symbol(Rc::new(Symbol { name }))
+}
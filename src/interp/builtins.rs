@@ -0,0 +1,523 @@
+//! Built-in functions
+//!
+//! Builtins are plain Rust functions over evaluated arguments. Anything
+//! that calls back into a Later function lives in `prelude.later`
+//! instead, so the call runs on the machine.
+
+use std::rc::Rc;
+
+use super::tasks::{Combinator, Mutex, Wait};
+use super::value::{Key, Object, Resource, Value};
+use super::{Fault, Interp, Panic, Result, error, new_symbol, thrown};
+use crate::diagnostic::{Diagnostic, Span};
+
+pub struct Builtin {
+    pub name: &'static str,
+    /// The number of arguments the builtin expects. In a postfix
+    /// application with fewer arguments, the subject is passed first.
+    pub arity: usize,
+    pub func: fn(&mut Interp, Vec<Value>, Span) -> Result<Value>,
+}
+
+macro_rules! builtins {
+    ($($name:literal / $arity:literal => $func:ident,)*) => {
+        static BUILTINS: &[Builtin] = &[
+            $(Builtin { name: $name, arity: $arity, func: $func },)*
+        ];
+    };
+}
+
+builtins! {
+    "print" / 1 => print,
+    "debug" / 1 => debug,
+    "len" / 1 => len,
+    "push" / 2 => push,
+    "pop" / 1 => pop,
+    "keys" / 1 => keys,
+    "values" / 1 => values,
+    "has-key" / 2 => has_key,
+    "join" / 2 => join,
+    "range" / 1 => range,
+    "to-string" / 1 => to_string,
+    "to-int" / 1 => to_int,
+    "to-float" / 1 => to_float,
+    "typeof" / 1 => type_of,
+    "symbol" / 0 => symbol,
+    "assert" / 1 => assert,
+    "panic" / 1 => panic,
+    "throw" / 1 => throw,
+    "Ok" / 1 => ok,
+    "Err" / 1 => err,
+    "compute-something" / 0 => compute_something,
+    "open" / 1 => open,
+    "open-resource" / 0 => open_resource,
+    "acquire-resource" / 0 => open_resource,
+    "create-resource" / 0 => open_resource,
+    "open-connection" / 0 => open_connection,
+    "close" / 1 => close,
+    "drop" / 1 => drop,
+    "read" / 1 => read,
+    "inspect" / 1 => inspect,
+    "read-result" / 1 => read_result,
+    "some-condition" / 0 => some_condition,
+    "await" / 1 => await_task,
+    "await-cancelled" / 1 => await_cancelled,
+    "cancel" / 1 => cancel,
+    "cleanup-deadline" / 1 => cleanup_deadline,
+    "all" / 1 => all,
+    "race" / 1 => race,
+    "any" / 1 => any,
+    "settle" / 1 => settle,
+    "sleep" / 1 => sleep,
+    "mutex" / 0 => mutex,
+    "acquire" / 1 => acquire,
+    "release" / 1 => release,
+}
+
+pub fn lookup(name: &str) -> Option<&'static Builtin> {
+    BUILTINS.iter().find(|builtin| builtin.name == name)
+}
+
+pub fn names() -> impl Iterator<Item = &'static str> {
+    BUILTINS.iter().map(|builtin| builtin.name)
+}
+
+/// Check the argument count and return the arguments as an array.
+fn expect<const N: usize>(args: Vec<Value>, span: Span) -> Result<[Value; N]> {
+    let got = args.len();
+    args.try_into().map_err(|_| super::arg_count(N, got, span))
+}
+
+fn print(interp: &mut Interp, args: Vec<Value>, _: Span) -> Result<Value> {
+    let line: Vec<String> = args.iter().map(Value::to_string).collect();
+    interp.print(&line.join(" "));
+    Ok(Value::Nil)
+}
+
+/// Print a value with a `debug:` prefix and pass it through.
+fn debug(interp: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [value] = expect(args, span)?;
+    interp.print(&format!("debug: {}", value.repr()));
+    Ok(value)
+}
+
+fn len(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [value] = expect(args, span)?;
+    let n = match &value {
+        Value::Str(s) => s.chars().count(),
+        Value::List(items) => items.len(),
+        Value::Object(object) => object.fields.len(),
+        other => return Err(error(format!("{} has no length", other.type_name()), span)),
+    };
+    Ok(Value::Int(n as i64))
+}
+
+fn push(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [list, item] = expect(args, span)?;
+    let Value::List(mut items) = list else {
+        return Err(error(
+            format!("expected list, got {}", list.type_name()),
+            span,
+        ));
+    };
+    Rc::make_mut(&mut items).push(item);
+    Ok(Value::List(items))
+}
+
+/// The list without its last element.
+fn pop(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [list] = expect(args, span)?;
+    let Value::List(mut items) = list else {
+        return Err(error(
+            format!("expected list, got {}", list.type_name()),
+            span,
+        ));
+    };
+    Rc::make_mut(&mut items).pop();
+    Ok(Value::List(items))
+}
+
+fn keys(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [object] = expect(args, span)?;
+    let Value::Object(object) = object else {
+        return Err(error(
+            format!("expected object, got {}", object.type_name()),
+            span,
+        ));
+    };
+    let keys = object
+        .fields
+        .iter()
+        .map(|(key, _)| match key {
+            Key::Name(name) => Value::Str(name.clone()),
+            Key::Symbol(symbol) => Value::Symbol(symbol.clone()),
+        })
+        .collect();
+    Ok(Value::list(keys))
+}
+
+fn values(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [object] = expect(args, span)?;
+    let Value::Object(object) = object else {
+        return Err(error(
+            format!("expected object, got {}", object.type_name()),
+            span,
+        ));
+    };
+    Ok(Value::list(
+        object.fields.iter().map(|(_, v)| v.clone()).collect(),
+    ))
+}
+
+fn has_key(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [object, key] = expect(args, span)?;
+    let Value::Object(object) = object else {
+        return Err(error(
+            format!("expected object, got {}", object.type_name()),
+            span,
+        ));
+    };
+    let found = match key {
+        Value::Str(name) => object.get_name(&name).is_some(),
+        Value::Symbol(symbol) => object.get(&Key::Symbol(symbol)).is_some(),
+        _ => false,
+    };
+    Ok(Value::Bool(found))
+}
+
+fn join(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [list, separator] = expect(args, span)?;
+    let Value::List(items) = list else {
+        return Err(error(
+            format!("expected list, got {}", list.type_name()),
+            span,
+        ));
+    };
+    let parts: Vec<String> = items.iter().map(Value::to_string).collect();
+    Ok(Value::str(&parts.join(&separator.to_string())))
+}
+
+/// `range(n)` is `[0, ..., n - 1]`; `range(a, b)` is `[a, ..., b - 1]`.
+fn range(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let (start, end) = match args.as_slice() {
+        [Value::Int(end)] => (0, *end),
+        [Value::Int(start), Value::Int(end)] => (*start, *end),
+        _ => return Err(error("`range` expects one or two integers", span)),
+    };
+    Ok(Value::list((start..end).map(Value::Int).collect()))
+}
+
+fn to_string(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [value] = expect(args, span)?;
+    match value {
+        Value::Str(_) => Ok(value),
+        Value::Symbol(_) => Err(error("cannot convert symbol to string", span)),
+        other => Ok(Value::str(&other.to_string())),
+    }
+}
+
+fn to_int(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [value] = expect(args, span)?;
+    match &value {
+        Value::Int(_) => Ok(value),
+        // The bounds are exactly representable; anything outside them
+        // would saturate rather than convert.
+        Value::Float(x) if (-9223372036854775808.0..9223372036854775808.0).contains(x) => {
+            Ok(Value::Int(*x as i64))
+        }
+        Value::Str(s) => s
+            .trim()
+            .parse()
+            .map(Value::Int)
+            .map_err(|_| error(format!("cannot convert {:?} to int", s), span)),
+        other => Err(error(
+            format!("cannot convert {} to int", other.type_name()),
+            span,
+        )),
+    }
+}
+
+fn to_float(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [value] = expect(args, span)?;
+    match &value {
+        Value::Int(n) => Ok(Value::Float(*n as f64)),
+        Value::Float(_) => Ok(value),
+        Value::Str(s) => s
+            .trim()
+            .parse()
+            .map(Value::Float)
+            .map_err(|_| error(format!("cannot convert {:?} to float", s), span)),
+        other => Err(error(
+            format!("cannot convert {} to float", other.type_name()),
+            span,
+        )),
+    }
+}
+
+fn type_of(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [value] = expect(args, span)?;
+    Ok(Value::str(value.type_name()))
+}
+
+/// A fresh symbol, optionally named for display.
+fn symbol(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    match args.as_slice() {
+        [] => Ok(new_symbol(None)),
+        [Value::Str(name)] => Ok(new_symbol(Some(name.clone()))),
+        _ => Err(error("`symbol` expects an optional name", span)),
+    }
+}
+
+fn assert(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    match args.as_slice() {
+        [Value::Bool(true)] | [Value::Bool(true), _] => Ok(Value::Nil),
+        [Value::Bool(false)] => Err(error("assertion failed", span)),
+        [Value::Bool(false), message] => Err(error(format!("assertion failed: {}", message), span)),
+        _ => Err(error("`assert` expects a bool", span)),
+    }
+}
+
+/// Raise the built-in `panic` effect with the argument as its payload.
+fn panic(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [value] = expect(args, span)?;
+    Err(Box::new(Panic {
+        fault: Fault::Panic,
+        diagnostic: Diagnostic::error(format!("panic: {}", value), span),
+        value,
+        suppressed: Vec::new(),
+    }))
+}
+
+/// Raise the built-in `error` effect.
+fn throw(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [value] = expect(args, span)?;
+    Err(thrown(value, span))
+}
+
+fn ok(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [value] = expect(args, span)?;
+    Ok(Value::tagged("Ok", value))
+}
+
+fn err(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [value] = expect(args, span)?;
+    Ok(Value::tagged("Err", value))
+}
+
+/// A stand-in for real work in examples.
+fn compute_something(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [] = expect(args, span)?;
+    Ok(Value::Int(42))
+}
+
+// -------------------------------------------------------------------------
+// Resources
+//
+// Stand-ins for files and connections, used by the examples. Which of
+// these take ownership is known to the linearity checker; see
+// `types::linearity`.
+// -------------------------------------------------------------------------
+
+fn open(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [path] = expect(args, span)?;
+    Ok(Value::resource("File", &path.to_string()))
+}
+
+fn open_resource(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [] = expect(args, span)?;
+    Ok(Value::resource("Resource", "resource"))
+}
+
+fn open_connection(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [] = expect(args, span)?;
+    Ok(Value::resource("Connection", "connection"))
+}
+
+/// The resource in `value`, which must still be open.
+fn open_handle(value: &Value, span: Span) -> Result<&Resource> {
+    match value {
+        Value::Resource(resource) if resource.open.get() => Ok(resource),
+        Value::Resource(resource) => Err(error(
+            format!("{} `{}` is already closed", resource.kind, resource.name),
+            span,
+        )),
+        other => Err(error(
+            format!("expected a resource, got {}", other.type_name()),
+            span,
+        )),
+    }
+}
+
+fn close(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [value] = expect(args, span)?;
+    open_handle(&value, span)?.open.set(false);
+    Ok(Value::Nil)
+}
+
+/// Consume any value, releasing it if it is a resource.
+fn drop(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [value] = expect(args, span)?;
+    if let Value::Resource(resource) = value {
+        resource.open.set(false);
+    }
+    Ok(Value::Nil)
+}
+
+fn read(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [value] = expect(args, span)?;
+    let resource = open_handle(&value, span)?;
+    Ok(Value::str(&format!("contents of {}", resource.name)))
+}
+
+fn inspect(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [value] = expect(args, span)?;
+    open_handle(&value, span)?;
+    Ok(Value::Nil)
+}
+
+/// Read from a resource, handing it back inside `Ok({ resource })`.
+fn read_result(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [value] = expect(args, span)?;
+    open_handle(&value, span)?;
+    let mut object = Object::default();
+    object.insert(Key::Name(Rc::from("resource")), value);
+    Ok(Value::tagged("Ok", Value::Object(Rc::new(object))))
+}
+
+fn some_condition(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [] = expect(args, span)?;
+    Ok(Value::Bool(true))
+}
+
+/// Wait for a task to finish, and take its value, or its failure.
+fn await_task(interp: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    match expect(args, span)? {
+        [Value::Task(task)] => interp.suspend(Wait::Task(task), span),
+        [other] => {
+            let message = format!("expected a task to await, got {}", other.type_name());
+            return Err(error(message, span));
+        }
+    }
+    Ok(Value::Nil)
+}
+
+/// Wait for a task to finish, and take whether it was cancelled. A task
+/// that failed raises its failure, as with `await`.
+fn await_cancelled(interp: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    match expect(args, span)? {
+        [Value::Task(task)] => interp.suspend(Wait::Cancelled(task), span),
+        [other] => {
+            let message = format!("expected a task to await, got {}", other.type_name());
+            return Err(error(message, span));
+        }
+    }
+    Ok(Value::Nil)
+}
+
+/// Cancel a task: `cancel` is raised in it at its next cancellation point.
+/// Cancelling a task that has finished does nothing.
+fn cancel(interp: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    match expect(args, span)? {
+        [Value::Task(task)] => interp.cancel(&task, span),
+        [other] => {
+            let message = format!("expected a task to cancel, got {}", other.type_name());
+            return Err(error(message, span));
+        }
+    }
+    Ok(Value::Nil)
+}
+
+/// Let the `defer` running be cancelled once `ticks` have passed, rather
+/// than being shielded from cancellation until it finishes.
+fn cleanup_deadline(interp: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    match expect(args, span)? {
+        [Value::Int(ticks)] => interp.deadline(ticks, span)?,
+        [other] => {
+            let message = format!("expected int for a deadline, got {}", other.type_name());
+            return Err(error(message, span));
+        }
+    }
+    Ok(Value::Nil)
+}
+
+fn all(interp: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    combine(interp, Combinator::All, args, span)
+}
+
+fn race(interp: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    combine(interp, Combinator::Race, args, span)
+}
+
+fn any(interp: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    combine(interp, Combinator::Any, args, span)
+}
+
+fn settle(interp: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    combine(interp, Combinator::Settle, args, span)
+}
+
+/// A task combining the list of tasks in `args`, to be awaited for their
+/// combined value.
+fn combine(
+    interp: &mut Interp,
+    combinator: Combinator,
+    args: Vec<Value>,
+    span: Span,
+) -> Result<Value> {
+    let items = match expect(args, span)? {
+        [Value::List(items)] => items,
+        [other] => {
+            let message = format!("expected a list of tasks, got {}", other.type_name());
+            return Err(error(message, span));
+        }
+    };
+    let tasks = items.iter().map(|item| match item {
+        Value::Task(task) => Ok(task.clone()),
+        other => Err(error(
+            format!("expected a list of tasks, holding {}", other.type_name()),
+            span,
+        )),
+    });
+    interp.combine(combinator, tasks.collect::<Result<_>>()?, span)
+}
+
+/// Let other tasks run for `ticks` of the scheduler's virtual clock.
+fn sleep(interp: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    match expect(args, span)? {
+        [Value::Int(ticks)] => interp.suspend(Wait::Sleep(ticks), span),
+        [other] => {
+            let message = format!("expected int for sleep, got {}", other.type_name());
+            return Err(error(message, span));
+        }
+    }
+    Ok(Value::Nil)
+}
+
+fn mutex(_: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [] = expect(args, span)?;
+    Ok(Value::Mutex(Rc::new(Mutex::default())))
+}
+
+/// The mutex in `value`.
+fn lock(value: Value, span: Span) -> Result<Rc<Mutex>> {
+    match value {
+        Value::Mutex(mutex) => Ok(mutex),
+        other => Err(error(
+            format!("expected a mutex, got {}", other.type_name()),
+            span,
+        )),
+    }
+}
+
+fn acquire(interp: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [value] = expect(args, span)?;
+    interp.suspend(Wait::Lock(lock(value, span)?), span);
+    Ok(Value::Nil)
+}
+
+fn release(interp: &mut Interp, args: Vec<Value>, span: Span) -> Result<Value> {
+    let [value] = expect(args, span)?;
+    let mutex = lock(value, span)?;
+    interp.release(&mutex, span)?;
+    Ok(Value::Nil)
+}
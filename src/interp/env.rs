@@ -0,0 +1,115 @@
+//! Lexical environments
+//!
+//! An environment is a persistent linked list of bindings, so closures and
+//! captured continuations can share it without copying. Only `let mut`
+//! bindings and hoisted functions need a cell; everything else is bound by
+//! value.
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use super::value::Value;
+
+#[derive(Clone, Default)]
+pub struct Env(Option<Rc<Binding>>);
+
+pub struct Binding {
+    pub name: Rc<str>,
+    pub slot: Slot,
+    /// When the binding was made, in the order of [`mark`].
+    pub serial: u64,
+    next: Env,
+}
+
+pub enum Slot {
+    Value(Value),
+    /// A `let mut` binding.
+    Mut(Rc<RefCell<Value>>),
+    /// A named function, declared when its block is entered and defined
+    /// when its statement runs.
+    Hoisted(Rc<RefCell<Option<Value>>>),
+}
+
+thread_local! {
+    static NEXT: Cell<u64> = const { Cell::new(0) };
+}
+
+/// The serial number the next binding will get: every binding made from
+/// now on is at or after the mark.
+pub fn mark() -> u64 {
+    NEXT.get()
+}
+
+impl Env {
+    fn push(&self, name: &str, slot: Slot) -> Env {
+        let serial = NEXT.get();
+        NEXT.set(serial + 1);
+        Env(Some(Rc::new(Binding {
+            name: Rc::from(name),
+            slot,
+            serial,
+            next: self.clone(),
+        })))
+    }
+
+    pub fn bind(&self, name: &str, value: Value) -> Env {
+        self.push(name, Slot::Value(value))
+    }
+
+    pub fn bind_mut(&self, name: &str, value: Value) -> Env {
+        self.push(name, Slot::Mut(Rc::new(RefCell::new(value))))
+    }
+
+    pub fn hoist(&self, name: &str) -> Env {
+        self.push(name, Slot::Hoisted(Rc::new(RefCell::new(None))))
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<&Slot> {
+        let mut env = self;
+        while let Some(binding) = &env.0 {
+            if &*binding.name == name {
+                return Some(&binding.slot);
+            }
+            env = &binding.next;
+        }
+        None
+    }
+
+    /// Every bound name, innermost first (shadowed names repeat).
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.bindings().map(|binding| &*binding.name)
+    }
+
+    /// The innermost binding, if any.
+    pub fn binding(&self) -> Option<&Rc<Binding>> {
+        self.0.as_ref()
+    }
+
+    /// A copy of `binding` on top of this environment, holding `slot`. It
+    /// keeps the binding's place in the order of [`mark`].
+    pub fn rebind(&self, binding: &Binding, slot: Slot) -> Env {
+        Env(Some(Rc::new(Binding {
+            name: binding.name.clone(),
+            slot,
+            serial: binding.serial,
+            next: self.clone(),
+        })))
+    }
+
+    /// Every binding, innermost first.
+    pub fn bindings(&self) -> impl Iterator<Item = &Binding> {
+        let mut env = self;
+        std::iter::from_fn(move || {
+            let binding = env.0.as_deref()?;
+            env = &binding.next;
+            Some(binding)
+        })
+    }
+}
+
+impl Binding {
+    /// The bindings made before this one.
+    pub fn next(&self) -> &Env {
+        &self.next
+    }
+}
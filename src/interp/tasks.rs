@@ -0,0 +1,793 @@
+//! Tasks and the scheduler
+//!
+//! Tasks are cooperative: one runs at a time, on the machine's stack, until
+//! it has to wait for something (`await`, `sleep`, a held lock). Then its
+//! stack is put aside as a [`Runnable`] and the next ready task's stack is
+//! swapped in. Ready tasks run in the order they became ready, except that
+//! those woken to be cancelled go first, so cleanup is prompt.
+//!
+//! Time is virtual. The clock ticks once per time slice, [`SLICE`] loop
+//! iterations or calls, at which point a busy task lets the others run; when
+//! every task is asleep it skips ahead to the next to wake. So a program's
+//! schedule is the same on every run.
+//!
+//! Every task is owned by a [`Scope`]: the innermost `nursery` around its
+//! `spawn`, or else the body of the task that spawned it. A scope is not
+//! left until all its children have finished, so no task outlives the
+//! task that spawned it. When one child fails, or the scope itself is
+//! left by a failure, the other children are cancelled.
+//!
+//! Cancelling a task sets a flag on it, which the task checks at its
+//! cancellation points: each `await`, `sleep` or `acquire`, each call, and
+//! each time a loop goes round. There the built-in `cancel` effect is
+//! raised, a `Never` effect that unwinds the task, running its `defer`s. A
+//! task blocked in a wait is woken to raise it straight away.
+//!
+//! A `defer` body is shielded: cancellation waits until it has finished,
+//! so a cleanup that waits is not cut short halfway through. A `defer`
+//! can opt into a `cleanup-deadline`, after which it can be cancelled
+//! like any other code.
+//!
+//! The combinators `all`, `race`, `any` and `settle` are tasks too. One
+//! takes over the tasks it is given as its own children, and finishes once
+//! they all have, so the losers it cancels have finished their cleanup by
+//! the time its value is awaited.
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, VecDeque};
+use std::rc::Rc;
+
+use super::env::Env;
+use super::value::{Key, Value};
+use super::{Control, Error, Exit, Fault, Frame, Interp, Panic, Result, Unwind, error, thrown};
+use crate::ast::Block;
+use crate::diagnostic::Span;
+
+/// The number of loop iterations and calls in a tick of the virtual clock.
+const SLICE: usize = 100;
+
+/// A task, from `spawn`. Its handle is a [`Value::Task`].
+pub struct Task {
+    /// The task's place in the tree: `3.1` is the first task spawned by the
+    /// program's third. The program itself is the root, with no label.
+    pub label: Rc<str>,
+    /// The scope that owns the task: a `nursery`, the body of the task
+    /// that spawned it, or a combinator that took it over. Only the program
+    /// has none.
+    owner: RefCell<Option<Rc<Scope>>>,
+    /// The scope of the task's own body.
+    scope: Rc<Scope>,
+    /// How many tasks this one has spawned, to label the next.
+    spawned: Cell<usize>,
+    /// How the task finished, once it has.
+    outcome: RefCell<Option<Result<Value>>>,
+    /// Tasks blocked awaiting this one, each with whether it asked for
+    /// `await-cancelled`.
+    waiters: RefCell<Vec<(Runnable, bool)>>,
+    /// What the task last waited for, to find it there if it is cancelled.
+    waiting: RefCell<Option<Wait>>,
+    cancel: Cell<Cancel>,
+    /// Set aside because its time slice ran out, part way round a loop.
+    preempted: Cell<bool>,
+}
+
+/// How far a task's cancellation has got.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Cancel {
+    Running,
+    /// `cancel` is raised at the task's next cancellation point, for the
+    /// scope or `cancel` call at this span.
+    Requested(Span),
+    Raised,
+}
+
+/// A `nursery`, or the body of a task: the tasks spawned in it, which it
+/// waits for before it is left.
+pub struct Scope {
+    span: Span,
+    /// Children still running.
+    children: RefCell<Vec<Rc<Task>>>,
+    /// Children that failed without anyone awaiting them. The first fails
+    /// the scope when it is left.
+    failures: RefCell<Vec<(Rc<Task>, Error)>>,
+    /// The task waiting at the end of the scope for its children.
+    joining: RefCell<Option<Runnable>>,
+    /// The combinator whose task's scope this is, if any.
+    combinator: Cell<Option<Combinator>>,
+    /// For `race` and `any`, the child whose value the combinator gives.
+    winner: RefCell<Option<Rc<Task>>>,
+}
+
+impl Scope {
+    pub fn new(span: Span) -> Rc<Scope> {
+        Rc::new(Scope {
+            span,
+            children: RefCell::default(),
+            failures: RefCell::default(),
+            joining: RefCell::default(),
+            combinator: Cell::new(None),
+            winner: RefCell::default(),
+        })
+    }
+}
+
+/// How a combinator's task combines the tasks it is given.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Combinator {
+    /// Every value, in order; the first failure cancels the rest.
+    All,
+    /// The outcome of the first to finish; the rest are cancelled.
+    Race,
+    /// The value of the first to succeed, which cancels the rest. Fails
+    /// only if every task does.
+    Any,
+    /// Every outcome, as `Ok(value)` or `Err(failure)`. Nothing is
+    /// cancelled.
+    Settle,
+}
+
+/// A lock shared between tasks, from `mutex()`. `acquire` waits while
+/// another task holds it; waiting tasks get it in the order they asked.
+#[derive(Default)]
+pub struct Mutex {
+    locked: Cell<bool>,
+    waiters: RefCell<VecDeque<Runnable>>,
+}
+
+/// What a task is waiting for, in a [`Frame::Wait`].
+#[derive(Clone)]
+pub enum Wait {
+    Task(Rc<Task>),
+    /// A task, for whether it ended by being cancelled.
+    Cancelled(Rc<Task>),
+    /// A number of ticks of the virtual clock.
+    Sleep(i64),
+    Lock(Rc<Mutex>),
+    /// The children of a scope, at its end.
+    Join(Rc<Scope>),
+}
+
+/// A task that isn't running: the stack to continue it with, and what to
+/// do next on it.
+pub struct Runnable {
+    task: Rc<Task>,
+    stack: Vec<Frame>,
+    control: Control,
+}
+
+/// Tasks that are ready to run, or asleep.
+#[derive(Default)]
+pub struct Scheduler {
+    ready: VecDeque<Runnable>,
+    /// Tasks woken to raise `cancel`, which run before the others.
+    cancelled: VecDeque<Runnable>,
+    /// Sleeping tasks by wake-up time, then by when they went to sleep.
+    timers: BTreeMap<(i64, usize), Runnable>,
+    clock: i64,
+    /// Loop iterations since the clock last ticked.
+    steps: usize,
+    slept: usize,
+    /// Where the task that waited last is waiting, for a deadlock.
+    blocked: Span,
+    /// Cleanup deadlines, when each task's `defer` stops being shielded.
+    deadlines: Vec<(i64, Rc<Task>)>,
+}
+
+impl Task {
+    fn new(label: Rc<str>, owner: Option<Rc<Scope>>, span: Span) -> Rc<Task> {
+        Rc::new(Task {
+            label,
+            owner: RefCell::new(owner),
+            scope: Scope::new(span),
+            spawned: Cell::new(0),
+            outcome: RefCell::new(None),
+            waiters: RefCell::default(),
+            waiting: RefCell::new(None),
+            cancel: Cell::new(Cancel::Running),
+            preempted: Cell::new(false),
+        })
+    }
+
+    /// The task that runs the program.
+    pub fn root() -> Rc<Task> {
+        Task::new("".into(), None, Span::default())
+    }
+
+    /// The scope of the task's body, which the bottom frame of its stack
+    /// waits on.
+    pub fn scope(&self) -> &Rc<Scope> {
+        &self.scope
+    }
+
+    /// Whether the task raised `cancel` and ended with it, rather than
+    /// handling it.
+    fn cancelled(&self, outcome: &Result<Value>) -> bool {
+        matches!(outcome, Err(failure) if failure.fault == Fault::Cancel)
+            && self.cancel.get() == Cancel::Raised
+    }
+
+    /// How the task failed, if it did. One that was cancelled has only
+    /// failed if its cleanup did.
+    fn failure(&self, outcome: &Result<Value>) -> Option<Error> {
+        match outcome {
+            Err(failure) if self.cancelled(outcome) => {
+                let mut suppressed = failure.suppressed.clone().into_iter();
+                suppressed.next().map(|first| {
+                    Box::new(Panic {
+                        suppressed: suppressed.collect(),
+                        ..first
+                    })
+                })
+            }
+            Err(failure) => Some(failure.clone()),
+            Ok(_) => None,
+        }
+    }
+
+    /// What awaiting the task gives once it has finished: its value, or
+    /// `nil` if it was cancelled, or with `await-cancelled`, whether it was
+    /// cancelled. A failure is raised in the awaiter either way.
+    fn awaited(&self, cancelled: bool) -> Option<Result<Value>> {
+        let outcome = self.outcome.borrow().clone()?;
+        if let Some(failure) = self.failure(&outcome) {
+            return Some(Err(failure));
+        }
+        Some(Ok(match outcome {
+            _ if cancelled => Value::Bool(self.cancelled(&outcome)),
+            Ok(value) => value,
+            Err(_) => Value::Nil,
+        }))
+    }
+}
+
+impl Interp {
+    /// Start `body` as a child task owned by the innermost scope, and
+    /// carry on with the current task. The child runs once the current
+    /// task waits.
+    pub(super) fn spawn(&mut self, body: Rc<Block>, env: Env, span: Span) -> Value {
+        let task = self.child(span);
+        let frames = vec![Frame::Nursery(task.scope.clone())];
+        let stack = std::mem::replace(&mut self.stack, frames);
+        self.enter_block(body, env);
+        let child = Runnable {
+            task: task.clone(),
+            stack: std::mem::replace(&mut self.stack, stack),
+            control: std::mem::replace(&mut self.control, Control::Value(Value::Nil)),
+        };
+        self.scheduler.ready.push_back(child);
+        Value::Task(task)
+    }
+
+    /// A new task spawned by the current one, owned by the innermost scope.
+    fn child(&mut self, span: Span) -> Rc<Task> {
+        let parent = self.task.clone();
+        let owner = self
+            .stack
+            .iter()
+            .rev()
+            .find_map(|frame| match frame {
+                Frame::Nursery(scope) => Some(scope.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| parent.scope.clone());
+        let n = parent.spawned.get() + 1;
+        parent.spawned.set(n);
+        let label = match &*parent.label {
+            "" => n.to_string(),
+            outer => format!("{}.{}", outer, n),
+        };
+        let task = Task::new(label.into(), Some(owner.clone()), span);
+        owner.children.borrow_mut().push(task.clone());
+        task
+    }
+
+    /// Start a task that combines `tasks`, taking them over from the
+    /// scopes that own them.
+    pub(super) fn combine(
+        &mut self,
+        combinator: Combinator,
+        tasks: Vec<Rc<Task>>,
+        span: Span,
+    ) -> Result<Value> {
+        if tasks.is_empty() && matches!(combinator, Combinator::Race | Combinator::Any) {
+            return Err(error("there must be at least one task to wait for", span));
+        }
+        for (i, task) in tasks.iter().enumerate() {
+            let owner = task.owner.borrow();
+            let combined = owner.as_ref().is_some_and(|o| o.combinator.get().is_some());
+            if combined || tasks[..i].iter().any(|other| Rc::ptr_eq(other, task)) {
+                let message = format!("task {} is already being combined", task.label);
+                return Err(error(message, span));
+            }
+        }
+        let combining = self.child(span);
+        let scope = combining.scope.clone();
+        scope.combinator.set(Some(combinator));
+        let mut done = Vec::new();
+        for task in &tasks {
+            // A failure no one has awaited moves with the task.
+            let failure = task.owner.replace(Some(scope.clone())).and_then(|owner| {
+                owner
+                    .children
+                    .borrow_mut()
+                    .retain(|child| !Rc::ptr_eq(child, task));
+                let mut failures = owner.failures.borrow_mut();
+                let i = failures
+                    .iter()
+                    .position(|(failed, _)| Rc::ptr_eq(failed, task));
+                i.map(|i| failures.remove(i).1)
+            });
+            if task.outcome.borrow().is_some() {
+                done.push((task.clone(), failure));
+            } else {
+                scope.children.borrow_mut().push(task.clone());
+            }
+        }
+        for (task, failure) in done {
+            self.finished(&scope, &task, failure);
+        }
+        self.scheduler.ready.push_back(Runnable {
+            task: combining.clone(),
+            stack: vec![
+                Frame::Combine(combinator, tasks, span),
+                Frame::Nursery(scope),
+            ],
+            control: Control::Value(Value::Nil),
+        });
+        Ok(Value::Task(combining))
+    }
+
+    /// A combinator's tasks have all finished, none failing it: give its
+    /// value.
+    pub(super) fn combined(&mut self, combinator: Combinator, tasks: &[Rc<Task>], span: Span) {
+        let awaited = |task: &Rc<Task>| {
+            task.awaited(false)
+                .expect("a combinator's tasks have finished")
+        };
+        match combinator {
+            Combinator::All => {
+                let values: Result<Vec<Value>> = tasks.iter().map(awaited).collect();
+                self.finish(values.map(Value::list));
+            }
+            Combinator::Race | Combinator::Any => match self.task.scope.winner.take() {
+                Some(winner) => self.finish(awaited(&winner)),
+                None => self.raise(error("no task succeeded", span)),
+            },
+            Combinator::Settle => {
+                let outcomes = tasks.iter().map(|task| {
+                    let outcome = task.outcome.borrow().clone();
+                    let outcome = outcome.expect("a combinator's tasks have finished");
+                    match task.failure(&outcome).map(Err).unwrap_or(outcome) {
+                        Ok(value) => Value::tagged("Ok", value),
+                        Err(failure) => Value::tagged("Err", report(task, &failure)),
+                    }
+                });
+                self.ret(Value::list(outcomes.collect()));
+            }
+        }
+    }
+
+    /// Make the running task wait for `wait`. Builtins that block push
+    /// this frame and return `nil` to it, so the task is set aside with
+    /// the builtin's call finished.
+    pub(super) fn suspend(&mut self, wait: Wait, span: Span) {
+        self.stack.push(Frame::Wait(wait, span));
+    }
+
+    /// A task reached its [`Frame::Wait`]: continue it now if it can,
+    /// or set it aside until it can.
+    pub(super) fn wait(&mut self, wait: Wait, span: Span) {
+        if let Err(error) = self.checkpoint() {
+            return self.raise(error);
+        }
+        self.scheduler.blocked = span;
+        match wait {
+            Wait::Task(ref task) | Wait::Cancelled(ref task) => {
+                let cancelled = matches!(wait, Wait::Cancelled(_));
+                match task.awaited(cancelled) {
+                    Some(result) => {
+                        // An awaited failure is the awaiter's to handle,
+                        // not the scope's.
+                        if let Some(owner) = &*task.owner.borrow() {
+                            owner
+                                .failures
+                                .borrow_mut()
+                                .retain(|(failed, _)| !Rc::ptr_eq(failed, task));
+                        }
+                        self.finish(result);
+                    }
+                    None => {
+                        let task = task.clone();
+                        let waiter = self.block(wait);
+                        task.waiters.borrow_mut().push((waiter, cancelled));
+                    }
+                }
+            }
+            Wait::Sleep(ticks) => {
+                let waiter = self.block(Wait::Sleep(ticks));
+                let scheduler = &mut self.scheduler;
+                let wake = scheduler.clock + ticks.max(0);
+                scheduler.timers.insert((wake, scheduler.slept), waiter);
+                scheduler.slept += 1;
+            }
+            Wait::Lock(mutex) => {
+                if mutex.locked.replace(true) {
+                    let waiter = self.block(Wait::Lock(mutex.clone()));
+                    mutex.waiters.borrow_mut().push_back(waiter);
+                } else {
+                    self.ret(Value::Nil);
+                }
+            }
+            Wait::Join(_) => unreachable!("only `join` waits on a scope"),
+        }
+    }
+
+    /// Set the running task aside while it waits for `wait`.
+    fn block(&mut self, wait: Wait) -> Runnable {
+        *self.task.waiting.borrow_mut() = Some(wait);
+        self.park()
+    }
+
+    /// Set the running task aside, to be continued with `nil`, and switch
+    /// to another.
+    fn park(&mut self) -> Runnable {
+        Runnable {
+            task: self.task.clone(),
+            stack: std::mem::take(&mut self.stack),
+            control: std::mem::replace(&mut self.control, Control::Switch),
+        }
+    }
+
+    /// A cancellation point: raise `cancel` if the running task has been
+    /// cancelled since it last passed one, and isn't in a shielded `defer`.
+    pub(super) fn checkpoint(&self) -> Result<()> {
+        match self.task.cancel.get() {
+            Cancel::Requested(span) if !shielded(&self.stack, self.scheduler.clock) => {
+                self.task.cancel.set(Cancel::Raised);
+                Err(cancelled(&self.task, span))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Let the `defer` running be cancelled once `ticks` have passed.
+    pub(super) fn deadline(&mut self, ticks: i64, span: Span) -> Result<()> {
+        let at = self.scheduler.clock + ticks.max(0);
+        let deadline = self.stack.iter_mut().rev().find_map(|frame| match frame {
+            Frame::Defer { deadline, .. } => Some(deadline),
+            _ => None,
+        });
+        match deadline {
+            Some(deadline) => *deadline = Some(at),
+            None => {
+                return Err(error(
+                    "a cleanup deadline needs a `defer` to apply to",
+                    span,
+                ));
+            }
+        }
+        self.scheduler.deadlines.push((at, self.task.clone()));
+        Ok(())
+    }
+
+    /// The clock has moved on: wake any cancelled task whose cleanup
+    /// deadline has passed.
+    fn expire(&mut self) {
+        let clock = self.scheduler.clock;
+        let (due, pending) = std::mem::take(&mut self.scheduler.deadlines)
+            .into_iter()
+            .partition(|(at, _)| *at <= clock);
+        self.scheduler.deadlines = pending;
+        for (_, task) in due {
+            if let Cancel::Requested(span) = task.cancel.get() {
+                self.wake(&task, span);
+            }
+        }
+    }
+
+    /// A loop went round again, which is a cancellation point and a step.
+    pub(super) fn tick(&mut self) {
+        if let Err(error) = self.checkpoint() {
+            return self.raise(error);
+        }
+        self.step();
+    }
+
+    /// A step of the running task: a loop going round or a call. Once its
+    /// time slice is used up, the clock ticks and any other task ready to
+    /// run goes first.
+    pub(super) fn step(&mut self) {
+        let scheduler = &mut self.scheduler;
+        scheduler.steps += 1;
+        if scheduler.steps < SLICE {
+            return;
+        }
+        scheduler.steps = 0;
+        scheduler.clock += 1;
+        while let Some(entry) = scheduler.timers.first_entry()
+            && entry.key().0 <= scheduler.clock
+        {
+            scheduler.ready.push_back(entry.remove());
+        }
+        self.expire();
+        if !self.scheduler.ready.is_empty() || !self.scheduler.cancelled.is_empty() {
+            self.task.preempted.set(true);
+            let waiter = self.park();
+            self.scheduler.ready.push_back(waiter);
+        }
+    }
+
+    /// Hand `mutex` to the next task waiting for it, or unlock it.
+    pub(super) fn release(&mut self, mutex: &Mutex, span: Span) -> Result<()> {
+        if !mutex.locked.get() {
+            return Err(error("cannot release a mutex that isn't held", span));
+        }
+        match mutex.waiters.borrow_mut().pop_front() {
+            Some(next) => self.scheduler.ready.push_back(next),
+            None => mutex.locked.set(false),
+        }
+        Ok(())
+    }
+
+    /// Leave `scope` by `exit` once its children have finished, first
+    /// cancelling them if the scope is failing. If children failed with no
+    /// one awaiting them, the scope fails instead: with the failure, or
+    /// with a `Group` of them if there were several.
+    pub(super) fn join(&mut self, scope: Rc<Scope>, exit: Exit) {
+        let failing = matches!(
+            exit,
+            Exit::Unwind(Unwind::Panic(_) | Unwind::Abort { .. } | Unwind::Abandon)
+        );
+        if failing {
+            self.cancel_all(&scope);
+        }
+        if !scope.children.borrow().is_empty() {
+            self.stack.push(Frame::Nursery(scope.clone()));
+            if !failing && let Err(error) = self.checkpoint() {
+                return self.raise(error);
+            }
+            let mut waiter = self.block(Wait::Join(scope.clone()));
+            waiter.control = match exit {
+                Exit::Value(value) => Control::Value(value),
+                Exit::Unwind(unwind) => Control::Unwind(unwind),
+            };
+            *scope.joining.borrow_mut() = Some(waiter);
+            return;
+        }
+        let mut failures = std::mem::take(&mut *scope.failures.borrow_mut());
+        match exit {
+            Exit::Unwind(unwind) => self.control = Control::Unwind(unwind),
+            Exit::Value(value) => match failures.len() {
+                0 => self.ret(value),
+                1 => self.raise(failures.remove(0).1),
+                _ => self.raise(group(&failures, scope.span)),
+            },
+        }
+    }
+
+    /// Cancel `task`, unless it has finished or is already cancelled. A
+    /// task that is waiting is woken to raise `cancel`; any other raises
+    /// it at its next cancellation point.
+    pub(super) fn cancel(&mut self, task: &Rc<Task>, span: Span) {
+        if task.cancel.get() != Cancel::Running || task.outcome.borrow().is_some() {
+            return;
+        }
+        task.cancel.set(Cancel::Requested(span));
+        self.wake(task, span);
+    }
+
+    /// Cancel the children of `scope` that are still running.
+    fn cancel_all(&mut self, scope: &Scope) {
+        let children = scope.children.borrow().clone();
+        for child in &children {
+            self.cancel(child, scope.span);
+        }
+    }
+
+    /// Wake `task` to raise `cancel`, if it is waiting outside a shielded
+    /// `defer`.
+    fn wake(&mut self, task: &Rc<Task>, span: Span) {
+        let clock = self.scheduler.clock;
+        let wakes =
+            |waiter: &Runnable| Rc::ptr_eq(&waiter.task, task) && !shielded(&waiter.stack, clock);
+        let waiting = task.waiting.borrow().clone();
+        let woken = match waiting {
+            Some(Wait::Task(other) | Wait::Cancelled(other)) => {
+                let mut waiters = other.waiters.borrow_mut();
+                let i = waiters.iter().position(|(waiter, _)| wakes(waiter));
+                i.map(|i| waiters.remove(i).0)
+            }
+            Some(Wait::Lock(mutex)) => {
+                let mut waiters = mutex.waiters.borrow_mut();
+                let i = waiters.iter().position(wakes);
+                i.and_then(|i| waiters.remove(i))
+            }
+            Some(Wait::Join(scope)) => scope.joining.borrow_mut().take_if(|waiter| wakes(waiter)),
+            Some(Wait::Sleep(_)) => {
+                let timers = &mut self.scheduler.timers;
+                let key = timers
+                    .iter()
+                    .find(|(_, waiter)| wakes(waiter))
+                    .map(|(key, _)| *key);
+                key.and_then(|key| timers.remove(&key))
+            }
+            None => None,
+        };
+        if let Some(mut woken) = woken {
+            task.waiting.take();
+            task.cancel.set(Cancel::Raised);
+            woken.control = Control::Unwind(Unwind::Panic(cancelled(task, span)));
+            self.scheduler.cancelled.push_back(woken);
+        }
+    }
+
+    /// The running task has run out of frames. Returns the program's
+    /// result once the program's own task is done.
+    pub(super) fn complete(&mut self, outcome: Result<Value>) -> Option<Result<Value>> {
+        let task = self.task.clone();
+        let owner = task.owner.borrow().clone();
+        let Some(owner) = owner else {
+            return Some(outcome);
+        };
+        let waiters = std::mem::take(&mut *task.waiters.borrow_mut());
+        owner
+            .children
+            .borrow_mut()
+            .retain(|child| !Rc::ptr_eq(child, &task));
+        // A task that failed, with no one to await it, fails its scope.
+        let failure = task.failure(&outcome).filter(|_| waiters.is_empty());
+        *task.outcome.borrow_mut() = Some(outcome);
+        self.finished(&owner, &task, failure);
+        for (mut waiter, cancelled) in waiters {
+            waiter.control = match task.awaited(cancelled) {
+                Some(Ok(value)) => Control::Value(value),
+                Some(Err(failure)) => Control::Unwind(Unwind::Panic(failure)),
+                None => unreachable!("the task has finished"),
+            };
+            self.scheduler.ready.push_back(waiter);
+        }
+        if owner.children.borrow().is_empty()
+            && let Some(joining) = owner.joining.take()
+        {
+            self.scheduler.ready.push_back(joining);
+        }
+        self.control = Control::Switch;
+        None
+    }
+
+    /// `task` has finished in `scope`, with `failure` if it failed and no
+    /// one awaits it. Its siblings are cancelled when it fails, or for
+    /// `race` when it finishes at all, or for `any` when it succeeds; a
+    /// failure in `settle` is data rather than the scope's failure.
+    fn finished(&mut self, scope: &Scope, task: &Rc<Task>, failure: Option<Error>) {
+        let combinator = scope.combinator.get();
+        let wins = match combinator {
+            Some(Combinator::Race) => true,
+            Some(Combinator::Any) => task.awaited(false).is_some_and(|result| result.is_ok()),
+            _ => false,
+        };
+        if wins && scope.winner.borrow().is_none() {
+            // Once one has won, the failures of those that lost first don't
+            // matter.
+            scope.failures.borrow_mut().clear();
+            *scope.winner.borrow_mut() = Some(task.clone());
+            self.cancel_all(scope);
+        }
+        if let Some(failure) = failure
+            && combinator != Some(Combinator::Settle)
+        {
+            scope.failures.borrow_mut().push((task.clone(), failure));
+            if combinator != Some(Combinator::Any) {
+                self.cancel_all(scope);
+            }
+        }
+    }
+
+    /// Continue the next ready task. If none is ready, the clock skips
+    /// ahead to the next one asleep; if none is asleep, every task is
+    /// waiting on another and none ever will run.
+    pub(super) fn switch(&mut self) -> Result<()> {
+        let scheduler = &mut self.scheduler;
+        let next = match scheduler
+            .cancelled
+            .pop_front()
+            .or_else(|| scheduler.ready.pop_front())
+        {
+            Some(next) => next,
+            None => match self.scheduler.timers.pop_first() {
+                Some(((wake, _), next)) => {
+                    self.scheduler.clock = wake;
+                    self.expire();
+                    next
+                }
+                None => {
+                    let span = self.scheduler.blocked;
+                    return Err(error("deadlock: every task is waiting", span)
+                        .with_note("this is where the last task to wait is waiting"));
+                }
+            },
+        };
+        self.task = next.task;
+        self.stack = next.stack;
+        self.control = next.control;
+        // Carrying on after its time slice ran out is a cancellation point
+        // too, as the loop it was in went round.
+        if self.task.preempted.take()
+            && let Err(error) = self.checkpoint()
+        {
+            self.raise(error);
+        }
+        Ok(())
+    }
+}
+
+/// Whether `stack` is running a `defer` that cancellation waits for: one
+/// without a cleanup deadline, or whose deadline is after `clock`.
+fn shielded(stack: &[Frame], clock: i64) -> bool {
+    stack.iter().any(|frame| {
+        matches!(frame, Frame::Defer { deadline, .. } if deadline.is_none_or(|at| clock < at))
+    })
+}
+
+/// The built-in `cancel` effect, raised in `task` on behalf of the scope
+/// or `cancel` call at `span`.
+fn cancelled(task: &Task, span: Span) -> Error {
+    let message = format!("task {} was cancelled", task.label);
+    Box::new(Panic {
+        fault: Fault::Cancel,
+        value: Value::str(&message),
+        diagnostic: crate::diagnostic::Diagnostic::error(message, span),
+        suppressed: Vec::new(),
+    })
+}
+
+/// The error a scope fails with when several of its children failed: a
+/// `Group` of their records, each naming the task, with `children` for a
+/// task that failed with a group in turn.
+fn group(failures: &[(Rc<Task>, Error)], span: Span) -> Error {
+    let records: Vec<Value> = failures
+        .iter()
+        .map(|(task, failure)| report(task, failure))
+        .collect();
+    let mut error = thrown(Value::tagged("Group", Value::list(records.clone())), span);
+    error.diagnostic.message = tree(&records, 1);
+    error
+}
+
+/// How `task` failed, as data: the failure's record, naming the task, with
+/// `children` for a task that failed with a group in turn.
+fn report(task: &Task, failure: &Panic) -> Value {
+    let children = match &failure.value {
+        Value::Tagged(tagged) if failure.fault == Fault::Error && &*tagged.tag == "Group" => {
+            tagged.value.clone()
+        }
+        _ => Value::list(Vec::new()),
+    };
+    let mut record = failure.record();
+    if let Value::Object(object) = &mut record {
+        let object = Rc::make_mut(object);
+        object.insert(Key::Name("task".into()), Value::str(&task.label));
+        object.insert(Key::Name("children".into()), children);
+    }
+    record
+}
+
+/// A group of failure records as an indented task tree, headed by how
+/// many tasks failed.
+fn tree(records: &[Value], depth: usize) -> String {
+    let mut out = format!("{} tasks failed", records.len());
+    for record in records {
+        let Value::Object(object) = record else {
+            continue;
+        };
+        let field = |name| object.get_name(name).cloned().unwrap_or(Value::Nil);
+        out.push_str(&format!("\n{}task {}: ", "  ".repeat(depth), field("task")));
+        match field("children") {
+            Value::List(children) if !children.is_empty() => {
+                out.push_str(&tree(&children, depth + 1));
+            }
+            _ => out.push_str(&format!("{}: {}", field("effect"), field("error"))),
+        }
+    }
+    out
+}
@@ -0,0 +1,328 @@
+//! Runtime values
+//!
+//! Values are cheap to clone: anything larger than a number sits behind an
+//! `Rc`. Lists and objects are persistent from the program's point of view;
+//! updating one (`push`, `{ ...obj, x: 1 }`) builds a new value, copying
+//! the old one if anything else still refers to it. A `let mut` variable
+//! it was read from counts, so `x = x push(item)` takes the list out of
+//! `x` first to append in place.
+
+use std::cell::Cell;
+use std::fmt;
+use std::rc::Rc;
+
+use super::env::Env;
+use super::tasks::{Mutex, Task};
+use super::{Builtin, Continuation, Handlers};
+use crate::ast::{FnDef, ResumeKind};
+
+#[derive(Clone)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(Rc<str>),
+    List(Rc<Vec<Value>>),
+    Object(Rc<Object>),
+    Symbol(Rc<Symbol>),
+    /// A tagged value such as `Ok(data)` or `Err(e)`.
+    Tagged(Rc<Tagged>),
+    Func(Rc<Closure>),
+    Builtin(&'static Builtin),
+    /// A function with its first argument already supplied, produced by
+    /// method-style access such as `"hello".len`.
+    Bound(Rc<(Value, Value)>),
+    /// A handle to an external resource such as a file.
+    Resource(Rc<Resource>),
+    /// An effect declared with `effect`. Calling it performs the effect.
+    Effect(Rc<Effect>),
+    /// A handler's `resume`: calling it continues the computation that
+    /// performed the effect.
+    Resume(Rc<Continuation>),
+    /// A `handler { ... }` value, installed with `with`.
+    Handler(Rc<Handlers>),
+    /// A handle to a task started by `spawn`.
+    Task(Rc<Task>),
+    Mutex(Rc<Mutex>),
+}
+
+/// An object: fields in insertion order.
+#[derive(Clone, Default)]
+pub struct Object {
+    pub fields: Vec<(Key, Value)>,
+}
+
+#[derive(Clone)]
+pub enum Key {
+    Name(Rc<str>),
+    Symbol(Rc<Symbol>),
+}
+
+/// A unique symbol created by `symbol()`. Symbols compare by identity.
+pub struct Symbol {
+    pub name: Option<Rc<str>>,
+}
+
+pub struct Tagged {
+    pub tag: Rc<str>,
+    pub value: Value,
+}
+
+/// An external resource. `kind` is its type (`File`); closing it is
+/// recorded so that use after close is caught at runtime too.
+pub struct Resource {
+    pub kind: &'static str,
+    pub name: Rc<str>,
+    pub open: Cell<bool>,
+}
+
+/// An effect. Each `effect` declaration makes a new one, and handlers
+/// match effects by identity, not by name.
+pub struct Effect {
+    pub name: Rc<str>,
+    /// The number of arguments the effect takes.
+    pub arity: usize,
+    pub resume: ResumeKind,
+}
+
+pub struct Closure {
+    pub def: Rc<FnDef>,
+    pub env: Env,
+}
+
+impl Key {
+    pub fn same(&self, other: &Key) -> bool {
+        match (self, other) {
+            (Key::Name(a), Key::Name(b)) => a == b,
+            (Key::Symbol(a), Key::Symbol(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Key::Name(name) => write!(f, "{}", name),
+            Key::Symbol(symbol) => write!(f, "[{}]", symbol),
+        }
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "symbol({})", name),
+            None => write!(f, "symbol()"),
+        }
+    }
+}
+
+impl Object {
+    pub fn get(&self, key: &Key) -> Option<&Value> {
+        self.fields
+            .iter()
+            .find(|(k, _)| k.same(key))
+            .map(|(_, value)| value)
+    }
+
+    pub fn get_name(&self, name: &str) -> Option<&Value> {
+        self.fields.iter().find_map(|(k, value)| match k {
+            Key::Name(n) if &**n == name => Some(value),
+            _ => None,
+        })
+    }
+
+    /// Set a field, replacing an existing one in place.
+    pub fn insert(&mut self, key: Key, value: Value) {
+        match self.fields.iter_mut().find(|(k, _)| k.same(&key)) {
+            Some((_, slot)) => *slot = value,
+            None => self.fields.push((key, value)),
+        }
+    }
+}
+
+impl Value {
+    pub fn str(s: &str) -> Value {
+        Value::Str(Rc::from(s))
+    }
+
+    pub fn list(items: Vec<Value>) -> Value {
+        Value::List(Rc::new(items))
+    }
+
+    /// An object with the named fields, in order.
+    pub fn object<'a>(fields: impl IntoIterator<Item = (&'a str, Value)>) -> Value {
+        Value::Object(Rc::new(Object {
+            fields: fields
+                .into_iter()
+                .map(|(name, value)| (Key::Name(Rc::from(name)), value))
+                .collect(),
+        }))
+    }
+
+    pub fn tagged(tag: &str, value: Value) -> Value {
+        Value::Tagged(Rc::new(Tagged {
+            tag: Rc::from(tag),
+            value,
+        }))
+    }
+
+    pub fn resource(kind: &'static str, name: &str) -> Value {
+        Value::Resource(Rc::new(Resource {
+            kind,
+            name: Rc::from(name),
+            open: Cell::new(true),
+        }))
+    }
+
+    /// An open resource held by this value, directly or inside a list,
+    /// object or tagged value.
+    pub fn open_resource(&self) -> Option<&Resource> {
+        match self {
+            Value::Resource(resource) if resource.open.get() => Some(resource),
+            Value::List(items) => items.iter().find_map(Value::open_resource),
+            Value::Object(object) => object.fields.iter().find_map(|(_, v)| v.open_resource()),
+            Value::Tagged(tagged) => tagged.value.open_resource(),
+            Value::Bound(bound) => bound.1.open_resource(),
+            _ => None,
+        }
+    }
+
+    /// The name used for this value's type in messages and by `typeof`.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "bool",
+            Value::Int(_) => "int",
+            Value::Float(_) => "float",
+            Value::Str(_) => "string",
+            Value::List(_) => "list",
+            Value::Object(_) => "object",
+            Value::Symbol(_) => "symbol",
+            Value::Tagged(_) => "tagged",
+            Value::Func(_) | Value::Builtin(_) | Value::Bound(_) => "function",
+            Value::Resource(resource) => resource.kind,
+            Value::Effect(_) => "effect",
+            Value::Resume(_) => "function",
+            Value::Handler(_) => "handler",
+            Value::Task(_) => "task",
+            Value::Mutex(_) => "mutex",
+        }
+    }
+
+    pub fn is_callable(&self) -> bool {
+        matches!(
+            self,
+            Value::Func(_)
+                | Value::Builtin(_)
+                | Value::Bound(_)
+                | Value::Effect(_)
+                | Value::Resume(_)
+        )
+    }
+
+    /// Structural equality. Numbers compare across `Int` and `Float`;
+    /// symbols and functions compare by identity.
+    pub fn equals(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Float(a), Value::Float(b)) => a == b,
+            (Value::Int(a), Value::Float(b)) | (Value::Float(b), Value::Int(a)) => *a as f64 == *b,
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::List(a), Value::List(b)) => {
+                a.len() == b.len() && a.iter().zip(b.iter()).all(|(x, y)| x.equals(y))
+            }
+            (Value::Object(a), Value::Object(b)) => {
+                a.fields.len() == b.fields.len()
+                    && a.fields
+                        .iter()
+                        .all(|(k, v)| b.get(k).is_some_and(|w| v.equals(w)))
+            }
+            (Value::Symbol(a), Value::Symbol(b)) => Rc::ptr_eq(a, b),
+            (Value::Tagged(a), Value::Tagged(b)) => a.tag == b.tag && a.value.equals(&b.value),
+            (Value::Func(a), Value::Func(b)) => Rc::ptr_eq(a, b),
+            (Value::Builtin(a), Value::Builtin(b)) => std::ptr::eq(*a, *b),
+            (Value::Resource(a), Value::Resource(b)) => Rc::ptr_eq(a, b),
+            (Value::Effect(a), Value::Effect(b)) => Rc::ptr_eq(a, b),
+            (Value::Resume(a), Value::Resume(b)) => Rc::ptr_eq(a, b),
+            (Value::Handler(a), Value::Handler(b)) => Rc::ptr_eq(a, b),
+            (Value::Task(a), Value::Task(b)) => Rc::ptr_eq(a, b),
+            (Value::Mutex(a), Value::Mutex(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+
+    /// The value as it appears inside a list or object: strings quoted.
+    pub fn repr(&self) -> String {
+        match self {
+            Value::Str(s) => format!("{:?}", s),
+            _ => self.to_string(),
+        }
+    }
+}
+
+/// Displays a value the way `print` shows it: strings without quotes.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Float(x) => fmt_float(*x, f),
+            Value::Str(s) => write!(f, "{}", s),
+            Value::List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item.repr())?;
+                }
+                write!(f, "]")
+            }
+            Value::Object(object) => {
+                if object.fields.is_empty() {
+                    return write!(f, "{{}}");
+                }
+                write!(f, "{{ ")?;
+                for (i, (key, value)) in object.fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", key, value.repr())?;
+                }
+                write!(f, " }}")
+            }
+            Value::Symbol(symbol) => write!(f, "{}", symbol),
+            Value::Tagged(tagged) => write!(f, "{}({})", tagged.tag, tagged.value.repr()),
+            Value::Func(closure) => match &closure.def.name {
+                Some(name) => write!(f, "<fn {}>", name),
+                None => write!(f, "<fn>"),
+            },
+            Value::Builtin(builtin) => write!(f, "<builtin {}>", builtin.name),
+            Value::Bound(bound) => write!(f, "{}", bound.0),
+            Value::Resource(resource) => write!(f, "<{} {}>", resource.kind, resource.name),
+            Value::Effect(effect) => write!(f, "<effect {}>", effect.name),
+            Value::Resume(_) => write!(f, "<resume>"),
+            Value::Handler(_) => write!(f, "<handler>"),
+            Value::Task(task) => write!(f, "<task {}>", task.label),
+            Value::Mutex(_) => write!(f, "<mutex>"),
+        }
+    }
+}
+
+fn fmt_float(x: f64, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if x.is_nan() {
+        write!(f, "nan")
+    } else if x.is_infinite() {
+        write!(f, "{}", if x > 0.0 { "inf" } else { "-inf" })
+    } else if x.fract() == 0.0 && x.abs() < 1e16 {
+        write!(f, "{:.1}", x)
+    } else {
+        write!(f, "{}", x)
+    }
+}
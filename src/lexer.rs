@@ -0,0 +1,704 @@
+//! Lexer: source text to spanned tokens
+//!
+//! Comments follow the `#` family from VISION.md §7: `#` line comments,
+//! nested `#* ... *#` block comments and a leading `#!` shebang are skipped,
+//! while `##`-style doc comments (and `##* ... *##` blocks) are kept as
+//! tokens so the parser can attach them to items.
+//!
+//! Whitespace is not a token, but every token records whether it was
+//! preceded by whitespace or a newline. The parser relies on this for
+//! juxtaposition (`f(x)` is a call, `f (x)` is not) and statement breaks.
+
+use crate::diagnostic::{Diagnostic, Span};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Keyword {
+    Let,
+    Mut,
+    Fn,
+    If,
+    Else,
+    Loop,
+    While,
+    Break,
+    Continue,
+    Return,
+    Match,
+    True,
+    False,
+    Nil,
+    And,
+    Or,
+    Not,
+    As,
+    Defer,
+    Spawn,
+    Nursery,
+    With,
+    Handle,
+    Effect,
+    Type,
+    Import,
+    Export,
+    From,
+    Send,
+}
+
+impl Keyword {
+    pub fn lookup(s: &str) -> Option<Keyword> {
+        Some(match s {
+            "let" => Keyword::Let,
+            "mut" => Keyword::Mut,
+            "fn" => Keyword::Fn,
+            "if" => Keyword::If,
+            "else" => Keyword::Else,
+            "loop" => Keyword::Loop,
+            "while" => Keyword::While,
+            "break" => Keyword::Break,
+            "continue" => Keyword::Continue,
+            "return" => Keyword::Return,
+            "match" => Keyword::Match,
+            "true" => Keyword::True,
+            "false" => Keyword::False,
+            "nil" => Keyword::Nil,
+            "and" => Keyword::And,
+            "or" => Keyword::Or,
+            "not" => Keyword::Not,
+            "as" => Keyword::As,
+            "defer" => Keyword::Defer,
+            "spawn" => Keyword::Spawn,
+            "nursery" => Keyword::Nursery,
+            "with" => Keyword::With,
+            "handle" => Keyword::Handle,
+            "effect" => Keyword::Effect,
+            "type" => Keyword::Type,
+            "import" => Keyword::Import,
+            "export" => Keyword::Export,
+            "from" => Keyword::From,
+            "send" => Keyword::Send,
+            _ => return None,
+        })
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Keyword::Let => "let",
+            Keyword::Mut => "mut",
+            Keyword::Fn => "fn",
+            Keyword::If => "if",
+            Keyword::Else => "else",
+            Keyword::Loop => "loop",
+            Keyword::While => "while",
+            Keyword::Break => "break",
+            Keyword::Continue => "continue",
+            Keyword::Return => "return",
+            Keyword::Match => "match",
+            Keyword::True => "true",
+            Keyword::False => "false",
+            Keyword::Nil => "nil",
+            Keyword::And => "and",
+            Keyword::Or => "or",
+            Keyword::Not => "not",
+            Keyword::As => "as",
+            Keyword::Defer => "defer",
+            Keyword::Spawn => "spawn",
+            Keyword::Nursery => "nursery",
+            Keyword::With => "with",
+            Keyword::Handle => "handle",
+            Keyword::Effect => "effect",
+            Keyword::Type => "type",
+            Keyword::Import => "import",
+            Keyword::Export => "export",
+            Keyword::From => "from",
+            Keyword::Send => "send",
+        }
+    }
+}
+
+/// A piece of a string literal: literal text or an interpolated expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StrPart {
+    Text(String),
+    /// The tokens between `{` and `}`, terminated by an `Eof` token.
+    Interp(Vec<Token>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    /// Integer literal. Kept unsigned so `-9223372036854775808` can be
+    /// folded by the parser.
+    Int(u64),
+    Float(f64),
+    Str(Vec<StrPart>),
+    Ident(String),
+    Keyword(Keyword),
+    /// `##` doc comment; `level` is the number of `#`s.
+    Doc {
+        level: u8,
+        text: String,
+    },
+
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    LBracket,
+    RBracket,
+    Comma,
+    Colon,
+    Semi,
+    Dot,
+    Ellipsis,
+    Question,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    EqEq,
+    NotEq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Pipe,
+    Amp,
+    At,
+    Arrow,
+    FatArrow,
+
+    Eof,
+}
+
+impl TokenKind {
+    /// How the token is written, for diagnostics.
+    pub fn describe(&self) -> String {
+        let s = match self {
+            TokenKind::Int(n) => return format!("`{}`", n),
+            TokenKind::Float(x) => return format!("`{}`", x),
+            TokenKind::Str(_) => return "string literal".to_string(),
+            TokenKind::Ident(name) => return format!("`{}`", name),
+            TokenKind::Keyword(kw) => return format!("`{}`", kw.as_str()),
+            TokenKind::Doc { .. } => return "doc comment".to_string(),
+            TokenKind::Eof => return "end of file".to_string(),
+            TokenKind::LParen => "(",
+            TokenKind::RParen => ")",
+            TokenKind::LBrace => "{",
+            TokenKind::RBrace => "}",
+            TokenKind::LBracket => "[",
+            TokenKind::RBracket => "]",
+            TokenKind::Comma => ",",
+            TokenKind::Colon => ":",
+            TokenKind::Semi => ";",
+            TokenKind::Dot => ".",
+            TokenKind::Ellipsis => "...",
+            TokenKind::Question => "?",
+            TokenKind::Plus => "+",
+            TokenKind::Minus => "-",
+            TokenKind::Star => "*",
+            TokenKind::Slash => "/",
+            TokenKind::Percent => "%",
+            TokenKind::EqEq => "==",
+            TokenKind::NotEq => "!=",
+            TokenKind::Lt => "<",
+            TokenKind::Le => "<=",
+            TokenKind::Gt => ">",
+            TokenKind::Ge => ">=",
+            TokenKind::Eq => "=",
+            TokenKind::Pipe => "|",
+            TokenKind::Amp => "&",
+            TokenKind::At => "@",
+            TokenKind::Arrow => "->",
+            TokenKind::FatArrow => "=>",
+        };
+        format!("`{}`", s)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+    /// Whitespace (or a comment) separates this token from the previous one.
+    pub space_before: bool,
+    /// A line break separates this token from the previous one.
+    pub newline_before: bool,
+}
+
+/// Lex a whole file. Errors are collected rather than aborting, so the
+/// token stream is always complete and ends with `Eof`.
+pub fn lex(source: &str) -> (Vec<Token>, Vec<Diagnostic>) {
+    let mut lexer = Lexer::new(source);
+    lexer.skip_shebang();
+    let mut tokens = Vec::new();
+    loop {
+        let token = lexer.next_token();
+        let done = token.kind == TokenKind::Eof;
+        tokens.push(token);
+        if done {
+            break;
+        }
+    }
+    (tokens, lexer.diagnostics)
+}
+
+/// Identifier characters. A `-` also joins an identifier when it sits
+/// between an identifier character and a letter (`my-value`), which is how
+/// kebab-case is told apart from subtraction (`a - b`, `n-1`).
+fn is_ident_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_'
+}
+
+fn is_ident_continue(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+struct Lexer<'a> {
+    src: &'a str,
+    pos: usize,
+    line: u32,
+    col: u32,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(src: &'a str) -> Self {
+        Lexer {
+            src,
+            pos: 0,
+            line: 0,
+            col: 0,
+            diagnostics: Vec::new(),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn peek_at(&self, n: usize) -> Option<char> {
+        self.src[self.pos..].chars().nth(n)
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        self.src[self.pos..].starts_with(s)
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.col = 0;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+
+    fn bump_n(&mut self, n: usize) {
+        for _ in 0..n {
+            self.bump();
+        }
+    }
+
+    fn here(&self) -> Span {
+        Span::new(self.pos, self.pos, self.line, self.col)
+    }
+
+    fn span_from(&self, start: Span) -> Span {
+        Span::new(start.start, self.pos, start.line, start.col)
+    }
+
+    fn error(&mut self, message: impl Into<String>, span: Span) {
+        self.diagnostics.push(Diagnostic::error(message, span));
+    }
+
+    fn skip_shebang(&mut self) {
+        if self.starts_with("#!") {
+            while let Some(c) = self.peek() {
+                if c == '\n' {
+                    break;
+                }
+                self.bump();
+            }
+        }
+    }
+
+    /// Skip whitespace and non-doc comments, reporting what was skipped.
+    fn skip_trivia(&mut self) -> (bool, bool) {
+        let mut space = false;
+        let mut newline = false;
+        loop {
+            match self.peek() {
+                Some('\n') => {
+                    newline = true;
+                    space = true;
+                    self.bump();
+                }
+                Some(c) if c.is_whitespace() => {
+                    space = true;
+                    self.bump();
+                }
+                Some('#') if self.is_doc_comment() => break,
+                Some('#') if self.starts_with("#*") => {
+                    space = true;
+                    let start = self.here();
+                    if !self.skip_block_comment() {
+                        self.error("unterminated block comment", self.span_from(start));
+                    }
+                }
+                Some('#') => {
+                    space = true;
+                    while let Some(c) = self.peek() {
+                        if c == '\n' {
+                            break;
+                        }
+                        self.bump();
+                    }
+                }
+                _ => break,
+            }
+        }
+        (space, newline)
+    }
+
+    fn is_doc_comment(&self) -> bool {
+        self.starts_with("##")
+    }
+
+    /// Skip a (possibly nested) `#* ... *#` comment. Returns false if it
+    /// never closes.
+    fn skip_block_comment(&mut self) -> bool {
+        self.bump_n(2);
+        let mut depth = 1;
+        while self.peek().is_some() {
+            if self.starts_with("#*") {
+                self.bump_n(2);
+                depth += 1;
+            } else if self.starts_with("*#") {
+                self.bump_n(2);
+                depth -= 1;
+                if depth == 0 {
+                    return true;
+                }
+            } else {
+                self.bump();
+            }
+        }
+        false
+    }
+
+    fn next_token(&mut self) -> Token {
+        let (space_before, newline_before) = self.skip_trivia();
+        let start = self.here();
+        let kind = match self.peek() {
+            None => TokenKind::Eof,
+            Some('#') => self.lex_doc_comment(start),
+            Some('"') => self.lex_string(start),
+            Some(c) if c.is_ascii_digit() => self.lex_number(start),
+            Some(c) if is_ident_start(c) => self.lex_word(),
+            Some(_) => match self.lex_punct() {
+                Some(kind) => kind,
+                None => {
+                    let c = self.bump().unwrap_or_default();
+                    let span = self.span_from(start);
+                    if c == '!' {
+                        self.diagnostics.push(
+                            Diagnostic::error("unexpected character `!`", span)
+                                .with_note("use `not` for boolean negation"),
+                        );
+                    } else {
+                        self.error(format!("unexpected character `{}`", c), span);
+                    }
+                    return self.next_token_after_error(space_before, newline_before);
+                }
+            },
+        };
+        Token {
+            kind,
+            span: self.span_from(start),
+            space_before,
+            newline_before,
+        }
+    }
+
+    /// Continue lexing after an invalid character, keeping the spacing
+    /// flags of the discarded character.
+    fn next_token_after_error(&mut self, space: bool, newline: bool) -> Token {
+        let mut token = self.next_token();
+        token.space_before |= space;
+        token.newline_before |= newline;
+        token
+    }
+
+    fn lex_doc_comment(&mut self, start: Span) -> TokenKind {
+        if self.starts_with("##*") {
+            self.bump_n(3);
+            let text_start = self.pos;
+            let mut depth = 1;
+            let mut text_end = self.pos;
+            while self.peek().is_some() {
+                if self.starts_with("*##") {
+                    text_end = self.pos;
+                    self.bump_n(3);
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                } else if self.starts_with("##*") {
+                    self.bump_n(3);
+                    depth += 1;
+                } else {
+                    self.bump();
+                    text_end = self.pos;
+                }
+            }
+            if depth != 0 {
+                self.error("unterminated doc comment", self.span_from(start));
+            }
+            let text = self.src[text_start..text_end].trim().to_string();
+            return TokenKind::Doc { level: 2, text };
+        }
+        let mut level = 0u8;
+        while self.peek() == Some('#') {
+            self.bump();
+            level = level.saturating_add(1);
+        }
+        let text_start = self.pos;
+        while let Some(c) = self.peek() {
+            if c == '\n' {
+                break;
+            }
+            self.bump();
+        }
+        let text = self.src[text_start..self.pos].trim().to_string();
+        TokenKind::Doc { level, text }
+    }
+
+    fn lex_word(&mut self) -> TokenKind {
+        let start = self.pos;
+        loop {
+            match self.peek() {
+                Some(c) if is_ident_continue(c) => {
+                    self.bump();
+                }
+                Some('-') if self.peek_at(1).is_some_and(char::is_alphabetic) => {
+                    self.bump();
+                }
+                _ => break,
+            }
+        }
+        let word = &self.src[start..self.pos];
+        match Keyword::lookup(word) {
+            Some(kw) => TokenKind::Keyword(kw),
+            None if word == "inf" => TokenKind::Float(f64::INFINITY),
+            None => TokenKind::Ident(word.to_string()),
+        }
+    }
+
+    fn lex_number(&mut self, start: Span) -> TokenKind {
+        let mut text = String::new();
+        let mut is_float = false;
+        self.eat_digits(&mut text);
+        if self.peek() == Some('.') && self.peek_at(1).is_some_and(|c| c.is_ascii_digit()) {
+            is_float = true;
+            self.bump();
+            text.push('.');
+            self.eat_digits(&mut text);
+        }
+        if matches!(self.peek(), Some('e' | 'E')) {
+            let sign = matches!(self.peek_at(1), Some('+' | '-'));
+            let digit_at = if sign { 2 } else { 1 };
+            if self.peek_at(digit_at).is_some_and(|c| c.is_ascii_digit()) {
+                is_float = true;
+                text.push('e');
+                self.bump();
+                if sign {
+                    text.push(self.bump().unwrap_or('+'));
+                }
+                self.eat_digits(&mut text);
+            }
+        }
+        if self.peek().is_some_and(is_ident_start) {
+            while self.peek().is_some_and(is_ident_continue) {
+                self.bump();
+            }
+            let span = self.span_from(start);
+            self.error(
+                format!(
+                    "invalid number literal `{}`",
+                    &self.src[span.start..span.end]
+                ),
+                span,
+            );
+            return TokenKind::Int(0);
+        }
+        if is_float {
+            TokenKind::Float(text.parse().unwrap_or(f64::NAN))
+        } else {
+            match text.parse::<u64>() {
+                Ok(n) => TokenKind::Int(n),
+                Err(_) => {
+                    self.error("integer literal is too large", self.span_from(start));
+                    TokenKind::Int(0)
+                }
+            }
+        }
+    }
+
+    fn eat_digits(&mut self, text: &mut String) {
+        while let Some(c) = self.peek() {
+            if c.is_ascii_digit() {
+                text.push(c);
+                self.bump();
+            } else if c == '_' && self.peek_at(1).is_some_and(|c| c.is_ascii_digit()) {
+                self.bump();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn lex_string(&mut self, start: Span) -> TokenKind {
+        self.bump();
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        loop {
+            let Some(c) = self.peek() else {
+                self.error("unterminated string literal", self.span_from(start));
+                break;
+            };
+            match c {
+                '"' => {
+                    self.bump();
+                    break;
+                }
+                '\\' => {
+                    let escape_start = self.here();
+                    self.bump();
+                    match self.bump() {
+                        Some('n') => text.push('\n'),
+                        Some('t') => text.push('\t'),
+                        Some('r') => text.push('\r'),
+                        Some('0') => text.push('\0'),
+                        Some(c @ ('\\' | '"' | '{' | '}')) => text.push(c),
+                        Some('u') if self.peek() == Some('{') => {
+                            self.lex_unicode_escape(escape_start, &mut text)
+                        }
+                        Some(c) => {
+                            let span = self.span_from(escape_start);
+                            self.error(format!("unknown escape sequence `\\{}`", c), span);
+                        }
+                        None => {}
+                    }
+                }
+                '{' => {
+                    if !text.is_empty() {
+                        parts.push(StrPart::Text(std::mem::take(&mut text)));
+                    }
+                    let open = self.here();
+                    self.bump();
+                    parts.push(StrPart::Interp(self.lex_interpolation(open)));
+                }
+                _ => {
+                    text.push(c);
+                    self.bump();
+                }
+            }
+        }
+        if !text.is_empty() || parts.is_empty() {
+            parts.push(StrPart::Text(text));
+        }
+        TokenKind::Str(parts)
+    }
+
+    fn lex_unicode_escape(&mut self, escape_start: Span, text: &mut String) {
+        self.bump();
+        let mut hex = String::new();
+        while let Some(c) = self.peek() {
+            self.bump();
+            if c == '}' {
+                break;
+            }
+            hex.push(c);
+        }
+        match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+            Some(c) => text.push(c),
+            None => {
+                let span = self.span_from(escape_start);
+                self.error(format!("invalid unicode escape `\\u{{{}}}`", hex), span);
+            }
+        }
+    }
+
+    /// Lex the expression inside `{ ... }` of a string, up to the matching
+    /// close brace. Nested braces (object literals, blocks) are balanced.
+    fn lex_interpolation(&mut self, open: Span) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        let mut depth = 0usize;
+        loop {
+            let token = self.next_token();
+            match token.kind {
+                TokenKind::Eof => {
+                    self.error("unclosed `{` in string interpolation", open);
+                    tokens.push(token);
+                    return tokens;
+                }
+                TokenKind::LBrace => depth += 1,
+                TokenKind::RBrace if depth == 0 => {
+                    tokens.push(Token {
+                        kind: TokenKind::Eof,
+                        ..token
+                    });
+                    return tokens;
+                }
+                TokenKind::RBrace => depth -= 1,
+                _ => {}
+            }
+            tokens.push(token);
+        }
+    }
+
+    fn lex_punct(&mut self) -> Option<TokenKind> {
+        const PUNCT: &[(&str, TokenKind)] = &[
+            ("...", TokenKind::Ellipsis),
+            ("==", TokenKind::EqEq),
+            ("!=", TokenKind::NotEq),
+            ("<=", TokenKind::Le),
+            (">=", TokenKind::Ge),
+            ("->", TokenKind::Arrow),
+            ("=>", TokenKind::FatArrow),
+            ("(", TokenKind::LParen),
+            (")", TokenKind::RParen),
+            ("{", TokenKind::LBrace),
+            ("}", TokenKind::RBrace),
+            ("[", TokenKind::LBracket),
+            ("]", TokenKind::RBracket),
+            (",", TokenKind::Comma),
+            (":", TokenKind::Colon),
+            (";", TokenKind::Semi),
+            (".", TokenKind::Dot),
+            ("?", TokenKind::Question),
+            ("+", TokenKind::Plus),
+            ("-", TokenKind::Minus),
+            ("*", TokenKind::Star),
+            ("/", TokenKind::Slash),
+            ("%", TokenKind::Percent),
+            ("<", TokenKind::Lt),
+            (">", TokenKind::Gt),
+            ("=", TokenKind::Eq),
+            ("|", TokenKind::Pipe),
+            ("&", TokenKind::Amp),
+            ("@", TokenKind::At),
+        ];
+        for (text, kind) in PUNCT {
+            if self.starts_with(text) {
+                self.bump_n(text.len());
+                return Some(kind.clone());
+            }
+        }
+        None
+    }
+}
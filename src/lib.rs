@@ -3,9 +3,9 @@
 //! This crate provides the lexer, parser, type checker, and interpreter
 //! for the Later programming language.
 
-// Modules will be added as implementation progresses
-// pub mod lexer;
-// pub mod parser;
-// pub mod ast;
-// pub mod types;
-// pub mod interp;
+pub mod ast;
+pub mod diagnostic;
+pub mod interp;
+pub mod lexer;
+pub mod parser;
+pub mod types;
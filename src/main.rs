@@ -1,21 +1,55 @@
+use later::diagnostic::{Source, render_all};
+
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
+    let mut args: Vec<String> = std::env::args().collect();
+
+    // `--signatures` prints each top-level function's signature, with its
+    // inferred effects, instead of running the program.
+    let signatures = args.len() > 2 && args[1] == "--signatures";
+    if signatures {
+        args.remove(1);
+    }
 
     if args.len() < 2 {
-        eprintln!("Usage: later <file.later>");
+        eprintln!("Usage: later [--signatures] <file.later>");
         std::process::exit(1);
     }
 
     let filename = &args[1];
-    let _source = match std::fs::read_to_string(filename) {
+    let text = match std::fs::read_to_string(filename) {
         Ok(s) => s,
         Err(e) => {
             eprintln!("Error reading {}: {}", filename, e);
             std::process::exit(1);
         }
     };
+    let source = Source::new(filename.as_str(), text);
+
+    let (tokens, mut diagnostics) = later::lexer::lex(&source.text);
+    let (program, parse_diagnostics) = later::parser::parse(tokens);
+    diagnostics.extend(parse_diagnostics);
+    // A program that doesn't parse isn't checked.
+    if !diagnostics.iter().any(|d| d.is_error()) {
+        diagnostics.extend(later::types::check(&program));
+    }
+
+    // Warnings are shown whether or not there are errors to stop at.
+    if !diagnostics.is_empty() {
+        eprint!("{}", render_all(&diagnostics, &source));
+    }
+    if diagnostics.iter().any(|d| d.is_error()) {
+        std::process::exit(1);
+    }
 
-    // TODO: Implement lexer, parser, interpreter
-    eprintln!("later: not yet implemented");
-    std::process::exit(1);
+    if signatures {
+        for signature in later::types::signatures(&program) {
+            println!("{}", signature);
+        }
+        return;
+    }
+
+    if let Err(error) = later::interp::run(&program, &source) {
+        eprint!("{}", error.diagnostic.render(&source));
+        std::process::exit(1);
+    }
 }
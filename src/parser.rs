@@ -0,0 +1,2153 @@
+//! Parser: tokens to AST, with error recovery
+//!
+//! The parser never stops at the first mistake. A failed statement is
+//! replaced by an `Error` expression and the parser resynchronises at the
+//! next statement boundary (a new line or `;` at the same bracket depth),
+//! so one run reports every syntax error in the file. Unclosed brackets
+//! are reported at the opening bracket.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::ast::*;
+use crate::diagnostic::{Diagnostic, Span};
+use crate::lexer::{Keyword, StrPart, Token, TokenKind};
+
+/// Parse a token stream into a (possibly partial) program plus the
+/// syntax errors found along the way.
+pub fn parse(tokens: Vec<Token>) -> (Program, Vec<Diagnostic>) {
+    let mut parser = Parser::new(tokens);
+    let start = parser.peek().span;
+    let stmts = parser.parse_stmts(None);
+    let span = start.to(parser.peek().span);
+    let program = Program {
+        body: Rc::new(Block { stmts, span }),
+    };
+    (program, parser.diagnostics)
+}
+
+/// Marker for a failed parse. The diagnostic has already been recorded.
+#[derive(Debug)]
+struct Failed;
+
+type PResult<T> = Result<T, Failed>;
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// Doc comments, keyed by the index of the token that follows them.
+    docs: HashMap<usize, String>,
+    /// Set while parsing an `if`/`while`/`match` condition, where `{`
+    /// opens the body rather than a block applied to the chain.
+    no_block: bool,
+    /// Set when the function body being parsed refers to its implicit
+    /// subject.
+    uses_subject: bool,
+    /// The variables assigned to so far, collected for `defer` blocks.
+    assigned: Vec<String>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Parser {
+    fn new(tokens: Vec<Token>) -> Self {
+        let mut kept = Vec::with_capacity(tokens.len());
+        let mut docs: HashMap<usize, String> = HashMap::new();
+        for token in tokens {
+            match token.kind {
+                TokenKind::Doc { text, .. } => {
+                    let doc = docs.entry(kept.len()).or_default();
+                    if !doc.is_empty() {
+                        doc.push('\n');
+                    }
+                    doc.push_str(&text);
+                }
+                _ => kept.push(token),
+            }
+        }
+        Parser {
+            tokens: kept,
+            pos: 0,
+            docs,
+            no_block: false,
+            uses_subject: false,
+            assigned: Vec::new(),
+            diagnostics: Vec::new(),
+        }
+    }
+
+    // ---------------------------------------------------------------------
+    // Token helpers
+    // ---------------------------------------------------------------------
+
+    fn peek(&self) -> &Token {
+        self.nth(0)
+    }
+
+    fn nth(&self, n: usize) -> &Token {
+        let last = self.tokens.len() - 1;
+        &self.tokens[(self.pos + n).min(last)]
+    }
+
+    fn kind(&self) -> &TokenKind {
+        &self.peek().kind
+    }
+
+    fn at(&self, kind: &TokenKind) -> bool {
+        self.kind() == kind
+    }
+
+    fn at_kw(&self, kw: Keyword) -> bool {
+        self.kind() == &TokenKind::Keyword(kw)
+    }
+
+    fn at_eof(&self) -> bool {
+        self.at(&TokenKind::Eof)
+    }
+
+    fn bump(&mut self) -> Token {
+        let token = self.peek().clone();
+        if token.kind != TokenKind::Eof {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn eat(&mut self, kind: &TokenKind) -> bool {
+        if self.at(kind) {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_kw(&mut self, kw: Keyword) -> bool {
+        self.eat(&TokenKind::Keyword(kw))
+    }
+
+    /// The span of the most recently consumed token.
+    fn prev_span(&self) -> Span {
+        if self.pos == 0 {
+            self.peek().span
+        } else {
+            self.tokens[self.pos - 1].span
+        }
+    }
+
+    fn span_from(&self, start: Span) -> Span {
+        start.to(self.prev_span())
+    }
+
+    /// True when the next token starts a new line.
+    fn at_line_start(&self) -> bool {
+        self.peek().newline_before
+    }
+
+    fn error(&mut self, message: impl Into<String>, span: Span) -> Failed {
+        self.diagnostics.push(Diagnostic::error(message, span));
+        Failed
+    }
+
+    fn unexpected(&mut self, expected: &str) -> Failed {
+        let token = self.peek().clone();
+        if token.kind == TokenKind::Eof {
+            return self.error(
+                format!("unexpected end of file, expected {}", expected),
+                token.span,
+            );
+        }
+        self.error(
+            format!(
+                "unexpected token {}, expected {}",
+                token.kind.describe(),
+                expected
+            ),
+            token.span,
+        )
+    }
+
+    fn expect(&mut self, kind: &TokenKind, expected: &str) -> PResult<Span> {
+        if self.at(kind) {
+            Ok(self.bump().span)
+        } else {
+            Err(self.unexpected(expected))
+        }
+    }
+
+    fn expect_ident(&mut self, expected: &str) -> PResult<(String, Span)> {
+        match self.kind().clone() {
+            TokenKind::Ident(name) => {
+                let span = self.bump().span;
+                Ok((name, span))
+            }
+            _ => Err(self.unexpected(expected)),
+        }
+    }
+
+    /// Report an unclosed delimiter, pointing at the opening bracket.
+    fn unclosed(&mut self, open: &str, close: &str, open_span: Span) -> Failed {
+        let here = self.peek().span;
+        let found = self.peek().kind.describe();
+        self.diagnostics.push(
+            Diagnostic::error(format!("unclosed `{}`", open), open_span)
+                .with_label(here, format!("expected `{}`, found {}", close, found)),
+        );
+        Failed
+    }
+
+    // ---------------------------------------------------------------------
+    // Recovery
+    // ---------------------------------------------------------------------
+
+    /// Skip to the next statement boundary at the current bracket depth.
+    /// Closing brackets that belong to an enclosing construct are left in
+    /// place.
+    fn synchronize(&mut self) {
+        let mut depth = 0usize;
+        let start = self.pos;
+        loop {
+            let token = self.peek();
+            if self.pos > start && depth == 0 && token.newline_before {
+                return;
+            }
+            match token.kind {
+                TokenKind::Eof => return,
+                TokenKind::Semi if depth == 0 => {
+                    self.bump();
+                    return;
+                }
+                TokenKind::LParen | TokenKind::LBracket | TokenKind::LBrace => depth += 1,
+                TokenKind::RParen | TokenKind::RBracket | TokenKind::RBrace => {
+                    if depth == 0 {
+                        return;
+                    }
+                    depth -= 1;
+                }
+                _ => {}
+            }
+            self.bump();
+        }
+    }
+
+    /// Skip to the next `,` or closing bracket at the current depth, used
+    /// inside lists, objects and argument lists.
+    fn recover_in_list(&mut self) {
+        let mut depth = 0usize;
+        loop {
+            match self.kind() {
+                TokenKind::Eof => return,
+                TokenKind::Comma if depth == 0 => return,
+                TokenKind::LParen | TokenKind::LBracket | TokenKind::LBrace => depth += 1,
+                TokenKind::RParen | TokenKind::RBracket | TokenKind::RBrace => {
+                    if depth == 0 {
+                        return;
+                    }
+                    depth -= 1;
+                }
+                _ => {}
+            }
+            self.bump();
+        }
+    }
+
+    // ---------------------------------------------------------------------
+    // Statements
+    // ---------------------------------------------------------------------
+
+    /// Parse statements until `close` (or end of file when `None`).
+    fn parse_stmts(&mut self, close: Option<&TokenKind>) -> Vec<Stmt> {
+        let mut stmts = Vec::new();
+        let mut separated = true;
+        loop {
+            while self.eat(&TokenKind::Semi) {
+                separated = true;
+                if let Some(Stmt {
+                    kind: StmtKind::Expr { semi, .. },
+                    ..
+                }) = stmts.last_mut()
+                {
+                    *semi = true;
+                }
+            }
+            if self.at_eof() || close.is_some_and(|c| self.at(c)) {
+                break;
+            }
+            // `(a, b)`: the comma makes a tuple, finished by the caller.
+            if close == Some(&TokenKind::RParen) && self.at(&TokenKind::Comma) {
+                break;
+            }
+            if close.is_none() && self.is_closer() {
+                let token = self.bump();
+                self.error(
+                    format!("unexpected closing {}", token.kind.describe()),
+                    token.span,
+                );
+                continue;
+            }
+            if let Some(close) = close
+                && self.is_closer()
+            {
+                let token = self.bump();
+                self.error(
+                    format!(
+                        "mismatched closing {}, expected {}",
+                        token.kind.describe(),
+                        close.describe()
+                    ),
+                    token.span,
+                );
+                continue;
+            }
+            if !separated && !self.at_line_start() {
+                self.unexpected("a new line or `;` between statements");
+                self.synchronize();
+                separated = true;
+                continue;
+            }
+            match self.parse_stmt() {
+                Ok(stmt) => stmts.push(stmt),
+                Err(Failed) => {
+                    self.synchronize();
+                    let span = self.prev_span();
+                    stmts.push(Stmt {
+                        kind: StmtKind::Expr {
+                            expr: Rc::new(Expr::new(ExprKind::Error, span)),
+                            semi: false,
+                        },
+                        span,
+                    });
+                }
+            }
+            separated = false;
+        }
+        stmts
+    }
+
+    fn is_closer(&self) -> bool {
+        matches!(
+            self.kind(),
+            TokenKind::RParen | TokenKind::RBracket | TokenKind::RBrace
+        )
+    }
+
+    fn parse_stmt(&mut self) -> PResult<Stmt> {
+        let start = self.peek().span;
+        let doc = self.docs.get(&self.pos).cloned();
+        let kind = match self.kind() {
+            TokenKind::Keyword(Keyword::Let) => self.parse_let()?,
+            TokenKind::Keyword(Keyword::Fn) if matches!(self.nth(1).kind, TokenKind::Ident(_)) => {
+                let mut def = self.parse_fn()?;
+                def.doc = doc;
+                StmtKind::Fn(Rc::new(def))
+            }
+            TokenKind::Keyword(Keyword::Effect) => self.parse_effect_decl()?,
+            TokenKind::Keyword(Keyword::With) => self.parse_with()?,
+            TokenKind::Keyword(Keyword::Type) => self.parse_type_def()?,
+            TokenKind::Keyword(Keyword::Import) => self.parse_import()?,
+            TokenKind::Keyword(Keyword::Export) => {
+                self.bump();
+                return self.parse_stmt();
+            }
+            TokenKind::Keyword(Keyword::Defer) => {
+                self.bump();
+                let outer = std::mem::take(&mut self.assigned);
+                let body = self.parse_braced_block();
+                let assigned = std::mem::replace(&mut self.assigned, outer);
+                self.assigned.extend(assigned.iter().cloned());
+                StmtKind::Defer {
+                    body: body?,
+                    assigned,
+                }
+            }
+            TokenKind::At if matches!(self.nth(2).kind, TokenKind::Keyword(Keyword::Let)) => {
+                let stage = self.parse_stage()?;
+                let mut stmt = self.parse_let()?;
+                if let StmtKind::Let { value, .. } = &mut stmt {
+                    let span = value.span;
+                    *value = Rc::new(Expr::new(
+                        ExprKind::Stage {
+                            stage,
+                            expr: value.clone(),
+                        },
+                        span,
+                    ));
+                }
+                stmt
+            }
+            _ => {
+                let expr = self.parse_expr_stmt()?;
+                StmtKind::Expr {
+                    expr: Rc::new(expr),
+                    semi: false,
+                }
+            }
+        };
+        Ok(Stmt {
+            kind,
+            span: self.span_from(start),
+        })
+    }
+
+    fn parse_let(&mut self) -> PResult<StmtKind> {
+        self.bump();
+        let mutable = self.eat_kw(Keyword::Mut);
+        let mut pattern = self.parse_pattern()?;
+        if mutable && let PatternKind::Bind { mutable, .. } = &mut pattern.kind {
+            *mutable = true;
+        }
+        let ty = if self.eat(&TokenKind::Colon) {
+            Some(self.parse_type()?)
+        } else {
+            None
+        };
+        self.expect(&TokenKind::Eq, "`=`")?;
+        // `let x = mut value` is an older spelling of `let mut x = value`.
+        if self.eat_kw(Keyword::Mut)
+            && let PatternKind::Bind { mutable, .. } = &mut pattern.kind
+        {
+            *mutable = true;
+        }
+        let value = self.parse_expr()?;
+        Ok(StmtKind::Let {
+            pattern,
+            ty,
+            value: Rc::new(value),
+        })
+    }
+
+    fn parse_stage(&mut self) -> PResult<Stage> {
+        let at = self.bump().span;
+        let (name, span) = self.expect_ident("a stage name after `@`")?;
+        match name.as_str() {
+            "comptime" => Ok(Stage::Comptime),
+            "startup" => Ok(Stage::Startup),
+            _ => Err(self.error(
+                format!(
+                    "unknown stage `@{}`, expected `@comptime` or `@startup`",
+                    name
+                ),
+                at.to(span),
+            )),
+        }
+    }
+
+    fn parse_effect_decl(&mut self) -> PResult<StmtKind> {
+        let start = self.bump().span;
+        let (name, _) = self.expect_ident("an effect name")?;
+        let params = if self.at(&TokenKind::LParen) {
+            self.parse_params()?
+        } else {
+            Vec::new()
+        };
+        let ret = if self.eat(&TokenKind::Colon) {
+            Some(self.parse_type()?)
+        } else {
+            None
+        };
+        let never = ret.as_ref().and_then(TypeExpr::simple_name) == Some("Never");
+        let mut resume = if never {
+            ResumeKind::Never
+        } else {
+            ResumeKind::Once
+        };
+        // `where resume: Many`
+        if matches!(self.kind(), TokenKind::Ident(word) if word == "where") {
+            self.bump();
+            match self.expect_ident("`resume` after `where`")? {
+                (word, _) if word == "resume" => {}
+                (_, span) => return Err(self.error("expected `resume` after `where`", span)),
+            }
+            self.expect(&TokenKind::Colon, "`:`")?;
+            let (kind, span) = self.expect_ident("a resume kind")?;
+            resume = match kind.as_str() {
+                "Once" => ResumeKind::Once,
+                "Many" => ResumeKind::Many,
+                "Fork" => ResumeKind::Fork,
+                _ => {
+                    return Err(self.error(
+                        format!(
+                            "unknown resume kind `{}`, expected `Once`, `Many` or `Fork`",
+                            kind
+                        ),
+                        span,
+                    ));
+                }
+            };
+            if never {
+                return Err(self.error("a `Never` effect cannot resume", span));
+            }
+        }
+        Ok(StmtKind::Effect(Rc::new(EffectDecl {
+            name,
+            params,
+            ret,
+            resume,
+            span: self.span_from(start),
+        })))
+    }
+
+    /// `with effect(params) { ... }`, `with return(x) { ... }`, or
+    /// `with value` to install a handler value.
+    fn parse_with(&mut self) -> PResult<StmtKind> {
+        let start = self.bump().span;
+        if self.at_kw(Keyword::Return) {
+            let ret = self.parse_return_clause()?;
+            let span = self.span_from(start);
+            let def = HandlerDef {
+                clauses: Vec::new(),
+                ret: Some(ret),
+                span,
+            };
+            let value = Expr::new(ExprKind::Handler(Rc::new(def)), span);
+            return Ok(StmtKind::Install(Rc::new(value)));
+        }
+        if !self.clause_ahead() {
+            let value = self.parse_expr()?;
+            return Ok(StmtKind::Install(Rc::new(value)));
+        }
+        Ok(StmtKind::With(self.parse_clause(start)?))
+    }
+
+    /// Whether a handler clause follows: `[shallow] effect(...) {`, with
+    /// the `{` on the same line. Anything else after `with` is a handler
+    /// value.
+    fn clause_ahead(&self) -> bool {
+        let mut n = 0;
+        if matches!(self.nth(0).kind, TokenKind::Ident(ref name) if name == "shallow")
+            && matches!(self.nth(1).kind, TokenKind::Ident(_))
+        {
+            n = 1;
+        }
+        if !matches!(self.nth(n).kind, TokenKind::Ident(_)) {
+            return false;
+        }
+        n += 1;
+        if self.nth(n).kind == TokenKind::LParen {
+            let mut depth = 0;
+            loop {
+                match self.nth(n).kind {
+                    TokenKind::LParen => depth += 1,
+                    TokenKind::RParen => depth -= 1,
+                    TokenKind::Eof => return false,
+                    _ => {}
+                }
+                n += 1;
+                if depth == 0 {
+                    break;
+                }
+            }
+        }
+        self.nth(n).kind == TokenKind::LBrace && !self.nth(n).newline_before
+    }
+
+    /// `[shallow] effect(params) { ... }`, starting at `start`.
+    fn parse_clause(&mut self, start: Span) -> PResult<Rc<Handler>> {
+        let shallow = self.eat_shallow();
+        let (effect, _) = self.expect_ident("an effect name")?;
+        let params = if self.at(&TokenKind::LParen) {
+            self.parse_params()?
+        } else {
+            Vec::new()
+        };
+        let body = self.parse_braced_block()?;
+        Ok(Rc::new(Handler {
+            effect,
+            shallow,
+            params,
+            body,
+            span: self.span_from(start),
+        }))
+    }
+
+    /// `return(x) { ... }`
+    fn parse_return_clause(&mut self) -> PResult<Rc<ReturnClause>> {
+        let start = self.bump().span;
+        if !self.at(&TokenKind::LParen) {
+            return Err(self.unexpected("`(` after `return`"));
+        }
+        let mut params = self.parse_params()?;
+        let head = self.span_from(start);
+        if params.len() != 1 {
+            return Err(self.error(
+                format!(
+                    "a `return` clause takes one parameter, the value of the handled block, but has {}",
+                    params.len()
+                ),
+                head,
+            ));
+        }
+        let param = params.remove(0);
+        let body = self.parse_braced_block()?;
+        Ok(Rc::new(ReturnClause {
+            param,
+            body,
+            span: self.span_from(start),
+        }))
+    }
+
+    /// `handler { effect(params) { ... } ... return(x) { ... } }`
+    fn parse_handler(&mut self) -> PResult<Expr> {
+        let start = self.bump().span;
+        let open = self.bump().span;
+        let mut clauses = Vec::new();
+        let mut ret = None;
+        loop {
+            while self.eat(&TokenKind::Semi) {}
+            if self.eat(&TokenKind::RBrace) {
+                break;
+            }
+            if self.at_eof() {
+                return Err(self.unclosed("{", "}", open));
+            }
+            let clause_start = self.peek().span;
+            if self.at_kw(Keyword::Return) {
+                let clause = self.parse_return_clause()?;
+                if ret.is_some() {
+                    self.error("a handler has only one `return` clause", clause.span);
+                }
+                ret = Some(clause);
+            } else {
+                clauses.push(self.parse_clause(clause_start)?);
+            }
+        }
+        let span = self.span_from(start);
+        let def = HandlerDef { clauses, ret, span };
+        Ok(Expr::new(ExprKind::Handler(Rc::new(def)), span))
+    }
+
+    /// The contextual keyword `shallow` before a handler's effect name.
+    fn eat_shallow(&mut self) -> bool {
+        let shallow = matches!(self.kind(), TokenKind::Ident(name) if name == "shallow")
+            && matches!(self.nth(1).kind, TokenKind::Ident(_));
+        if shallow {
+            self.bump();
+        }
+        shallow
+    }
+
+    fn parse_type_def(&mut self) -> PResult<StmtKind> {
+        self.bump();
+        let (name, _) = self.expect_ident("a type name")?;
+        if self.at(&TokenKind::Lt) {
+            self.skip_generics()?;
+        }
+        self.expect(&TokenKind::Eq, "`=`")?;
+        let ty = self.parse_type()?;
+        Ok(StmtKind::TypeDef { name, ty })
+    }
+
+    fn parse_import(&mut self) -> PResult<StmtKind> {
+        self.bump();
+        if self.at(&TokenKind::LBrace) {
+            let binding = self.parse_pattern()?;
+            self.expect(&TokenKind::Keyword(Keyword::From), "`from`")?;
+            let path = self.parse_import_path()?;
+            return Ok(StmtKind::Import { path, binding });
+        }
+        let path = self.parse_import_path()?;
+        self.expect(&TokenKind::Keyword(Keyword::As), "`as`")?;
+        let binding = self.parse_pattern()?;
+        Ok(StmtKind::Import { path, binding })
+    }
+
+    fn parse_import_path(&mut self) -> PResult<String> {
+        if let TokenKind::Str(parts) = self.kind().clone()
+            && let [StrPart::Text(path)] = parts.as_slice()
+        {
+            self.bump();
+            return Ok(path.clone());
+        }
+        Err(self.unexpected("an import path string"))
+    }
+
+    // ---------------------------------------------------------------------
+    // Functions
+    // ---------------------------------------------------------------------
+
+    /// `fn name(params) body` or `fn(params) body`.
+    fn parse_fn(&mut self) -> PResult<FnDef> {
+        let start = self.bump().span;
+        let name = match self.kind().clone() {
+            TokenKind::Ident(name) => {
+                self.bump();
+                Some(name)
+            }
+            _ => None,
+        };
+        if self.at(&TokenKind::Lt) {
+            self.skip_generics()?;
+        }
+        let params = if self.at(&TokenKind::LParen) {
+            self.parse_params()?
+        } else {
+            Vec::new()
+        };
+        let ret = if self.eat(&TokenKind::Colon) || self.eat(&TokenKind::Arrow) {
+            Some(self.parse_type()?)
+        } else {
+            None
+        };
+        let outer = std::mem::replace(&mut self.uses_subject, false);
+        let body = if self.at(&TokenKind::LBrace) {
+            self.parse_braced_block()?
+        } else if self.at_line_start() || self.at_eof() {
+            return Err(self.unexpected("a function body"));
+        } else {
+            let expr = self.parse_expr()?;
+            let span = expr.span;
+            Rc::new(Block {
+                stmts: vec![Stmt {
+                    kind: StmtKind::Expr {
+                        expr: Rc::new(expr),
+                        semi: false,
+                    },
+                    span,
+                }],
+                span,
+            })
+        };
+        Ok(FnDef {
+            name,
+            params,
+            ret,
+            body,
+            uses_subject: std::mem::replace(&mut self.uses_subject, outer),
+            doc: None,
+            span: self.span_from(start),
+        })
+    }
+
+    /// Skip a generic parameter list such as `<T: Display>`; generics are
+    /// accepted but not checked.
+    fn skip_generics(&mut self) -> PResult<()> {
+        let open = self.bump().span;
+        let mut depth = 1;
+        while depth > 0 {
+            match self.bump().kind {
+                TokenKind::Lt => depth += 1,
+                TokenKind::Gt => depth -= 1,
+                TokenKind::Eof => return Err(self.unclosed("<", ">", open)),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_params(&mut self) -> PResult<Vec<Param>> {
+        let open = self.bump().span;
+        let mut params = Vec::new();
+        loop {
+            if self.eat(&TokenKind::RParen) {
+                break;
+            }
+            if self.at_eof() {
+                return Err(self.unclosed("(", ")", open));
+            }
+            match self.parse_param() {
+                Ok(param) => params.push(param),
+                Err(Failed) => self.recover_in_list(),
+            }
+            if self.eat(&TokenKind::Comma) {
+                continue;
+            }
+            if self.eat(&TokenKind::RParen) {
+                break;
+            }
+            if self.at_eof() {
+                return Err(self.unclosed("(", ")", open));
+            }
+            return Err(self.unexpected("`,` or `)`"));
+        }
+        Ok(params)
+    }
+
+    fn parse_param(&mut self) -> PResult<Param> {
+        let start = self.peek().span;
+        let rest = self.eat(&TokenKind::Ellipsis);
+        let pattern = self.parse_pattern()?;
+        let ty = if self.eat(&TokenKind::Colon) {
+            Some(self.parse_type()?)
+        } else {
+            None
+        };
+        let default = if self.eat(&TokenKind::Eq) {
+            Some(Rc::new(self.parse_expr()?))
+        } else {
+            None
+        };
+        Ok(Param {
+            pattern,
+            ty,
+            default,
+            rest,
+            span: self.span_from(start),
+        })
+    }
+
+    /// `{ stmts }` after a keyword.
+    fn parse_braced_block(&mut self) -> PResult<Rc<Block>> {
+        if !self.at(&TokenKind::LBrace) {
+            return Err(self.unexpected("`{`"));
+        }
+        let open = self.bump().span;
+        let stmts = self.unrestricted(|p| p.parse_stmts(Some(&TokenKind::RBrace)));
+        if !self.eat(&TokenKind::RBrace) {
+            return Err(self.unclosed("{", "}", open));
+        }
+        Ok(Rc::new(Block {
+            stmts,
+            span: self.span_from(open),
+        }))
+    }
+
+    // ---------------------------------------------------------------------
+    // Legacy effect syntax
+    // ---------------------------------------------------------------------
+    //
+    // Older examples wrote handlers several ways before `with` was settled
+    // on. Each form is parsed in full, so the rest of the file still
+    // parses, and reported with the `with` handler it becomes.
+
+    /// `handle ask(q, resume) { ... }`, or `handle { ... } error e { ... }`.
+    fn parse_legacy_handle(&mut self) -> PResult<Expr> {
+        let start = self.bump().span;
+        // Reported ahead of anything inside, to keep source order.
+        let at = self.diagnostics.len();
+        if self.at(&TokenKind::LBrace) {
+            self.parse_braced_block()?;
+            let clauses = self.parse_legacy_clauses(false)?;
+            let span = self.span_from(start);
+            let note = if clauses.is_empty() {
+                "without handlers this is a plain block: write `( ... )`".to_string()
+            } else {
+                format!(
+                    "a `with` handler handles the rest of its block: put `{}` at the start of a block holding the handled code",
+                    clauses.join(" ")
+                )
+            };
+            self.diagnostics.insert(
+                at,
+                Diagnostic::error("legacy `handle` syntax", start).with_note(note),
+            );
+            return Ok(Expr::new(ExprKind::Error, span));
+        }
+        let shallow = self.eat_shallow();
+        let (name, _) = self.expect_ident("an effect name or `{` after `handle`")?;
+        let params = if self.at(&TokenKind::LParen) {
+            self.parse_params()?
+        } else {
+            Vec::new()
+        };
+        let head = self.span_from(start);
+        let params: Vec<&str> = params
+            .iter()
+            .map(|param| param.name().unwrap_or("_"))
+            .filter(|name| *name != "resume")
+            .collect();
+        self.diagnostics.push(
+            Diagnostic::error("legacy `handle` syntax", head).with_note(format!(
+                "write `with {}{}({}) {{ ... resume(value) }}`: it handles the rest of the block, and the handler continues the computation by calling `resume`",
+                if shallow { "shallow " } else { "" },
+                name,
+                params.join(", ")
+            )),
+        );
+        self.parse_braced_block()?;
+        Ok(Expr::new(ExprKind::Error, self.span_from(start)))
+    }
+
+    /// `work() | handle { panic msg { ... } }`
+    fn parse_legacy_pipe_handle(&mut self, expr: Expr) -> PResult<Expr> {
+        let start = self.bump().span;
+        if !self.at(&TokenKind::LBrace) {
+            return Err(self.unexpected("`{` after `handle`"));
+        }
+        let open = self.bump().span;
+        let at = self.diagnostics.len();
+        let clauses = self.unrestricted(|p| p.parse_legacy_clauses(true))?;
+        if clauses.is_empty() {
+            return Err(self.unexpected("an effect name"));
+        }
+        if !self.eat(&TokenKind::RBrace) {
+            return Err(self.unclosed("{", "}", open));
+        }
+        self.diagnostics.insert(
+            at,
+            Diagnostic::error("legacy `handle` syntax", start)
+                .with_label(expr.span, "this is the handled code")
+                .with_note(format!(
+                    "put `{}` on the line before the handled code: a `with` handler handles the rest of its block",
+                    clauses.join(" ")
+                )),
+        );
+        Ok(Expr::new(ExprKind::Error, self.span_from(expr.span)))
+    }
+
+    /// The `effect binding { ... }` clauses of a legacy handler, rendered
+    /// as `with` handlers. Inside braces (`multiline`) they may span lines.
+    fn parse_legacy_clauses(&mut self, multiline: bool) -> PResult<Vec<String>> {
+        let mut clauses = Vec::new();
+        while let TokenKind::Ident(name) = self.kind().clone() {
+            if !multiline && self.at_line_start() {
+                break;
+            }
+            self.bump();
+            let binding = match self.kind().clone() {
+                TokenKind::Ident(binding) => {
+                    self.bump();
+                    binding
+                }
+                _ => "_".to_string(),
+            };
+            self.parse_braced_block()?;
+            clauses.push(format!("with {}({}) {{ ... }}", name, binding));
+        }
+        Ok(clauses)
+    }
+
+    /// `send effect with value`
+    fn parse_legacy_send(&mut self) -> PResult<Expr> {
+        let start = self.bump().span;
+        let (name, _) = self.expect_ident("an effect name after `send`")?;
+        let value = if self.eat_kw(Keyword::With) {
+            Some(self.parse_expr()?)
+        } else {
+            None
+        };
+        let arg = match value.as_ref().map(|value| &value.kind) {
+            None => String::new(),
+            Some(ExprKind::Ident(name)) => name.clone(),
+            Some(ExprKind::Int(n)) => n.to_string(),
+            Some(ExprKind::Str(text)) => format!("{:?}", text),
+            Some(_) => "...".to_string(),
+        };
+        let span = self.span_from(start);
+        // The built-in `error` effect is performed with `throw`.
+        let func = if name == "error" { "throw" } else { &name };
+        self.diagnostics.push(
+            Diagnostic::error("legacy `send ... with` syntax", span).with_note(format!(
+                "effects are performed by calling them: write `{}({})`",
+                func, arg
+            )),
+        );
+        Ok(Expr::new(ExprKind::Error, span))
+    }
+
+    // ---------------------------------------------------------------------
+    // Expressions
+    // ---------------------------------------------------------------------
+
+    fn parse_expr(&mut self) -> PResult<Expr> {
+        self.parse_assign(false)
+    }
+
+    /// An expression in statement position, which may start with an
+    /// operator or `.field` to use the implicit subject.
+    fn parse_expr_stmt(&mut self) -> PResult<Expr> {
+        self.parse_assign(true)
+    }
+
+    fn parse_assign(&mut self, subject: bool) -> PResult<Expr> {
+        let start = self.peek().span;
+        let expr = self.parse_chain(subject)?;
+        if self.at(&TokenKind::Eq) && !self.at_line_start() {
+            self.bump();
+            let value = self.parse_expr()?;
+            let mut root = &expr;
+            while let ExprKind::Field { expr, .. } = &root.kind {
+                root = expr;
+            }
+            if let ExprKind::Ident(name) = &root.kind
+                && !self.assigned.contains(name)
+            {
+                self.assigned.push(name.clone());
+            }
+            return Ok(Expr::new(
+                ExprKind::Assign {
+                    target: Rc::new(expr),
+                    value: Rc::new(value),
+                },
+                self.span_from(start),
+            ));
+        }
+        Ok(expr)
+    }
+
+    /// An operation chain, evaluated strictly left to right: binary
+    /// operators, postfix application (`x f g(y)`), the legacy pipe
+    /// `x | f`, spaced `.field` / `.[n]`, postfix `?` and `as name`.
+    ///
+    /// A chain continues onto the next line only when that line starts
+    /// with a binary operator, `|` or `.`. A `-` touching its operand at
+    /// the start of a line is a unary minus and begins a new statement.
+    fn parse_chain(&mut self, subject: bool) -> PResult<Expr> {
+        let start = self.peek().span;
+        // The operator after an implicit subject sits at the start of the
+        // statement, so it is not subject to the line-continuation rule.
+        let mut leading = subject && self.subject_use_at(0);
+        let mut expr = if leading {
+            let here = Span::new(start.start, start.start, start.line, start.col);
+            self.uses_subject = true;
+            Expr::new(ExprKind::Subject, here)
+        } else {
+            self.parse_operand()?
+        };
+        // Set while the chain so far ends in a comparison, so that
+        // `a < b < c` extends the comparison instead of comparing a bool.
+        let mut comparing = false;
+        loop {
+            let token = self.peek();
+            let newline = token.newline_before && !std::mem::take(&mut leading);
+            if let Some(op) = binary_op(&token.kind) {
+                if newline && op == BinOp::Sub && !self.nth(1).space_before {
+                    return Ok(expr);
+                }
+                self.bump();
+                let rhs = Rc::new(self.parse_operand()?);
+                let span = expr.span.to(rhs.span);
+                let kind = match expr.kind {
+                    ExprKind::Compare { first, mut rest } if comparing && op.is_comparison() => {
+                        rest.push((op, rhs));
+                        ExprKind::Compare { first, rest }
+                    }
+                    ExprKind::Binary {
+                        op: prev,
+                        lhs,
+                        rhs: mid,
+                    } if comparing && op.is_comparison() => ExprKind::Compare {
+                        first: lhs,
+                        rest: vec![(prev, mid), (op, rhs)],
+                    },
+                    kind => ExprKind::Binary {
+                        op,
+                        lhs: Rc::new(Expr::new(kind, expr.span)),
+                        rhs,
+                    },
+                };
+                expr = Expr::new(kind, span);
+                comparing = op.is_comparison();
+                continue;
+            }
+            comparing = false;
+            expr = match token.kind {
+                TokenKind::Pipe => {
+                    self.bump();
+                    if self.at_kw(Keyword::Handle) {
+                        self.parse_legacy_pipe_handle(expr)?
+                    } else if self.at(&TokenKind::Dot) {
+                        self.parse_dot(expr)?
+                    } else if self.at_kw(Keyword::Fn) {
+                        let func = self.parse_operand()?;
+                        self.apply(expr, func, None)
+                    } else {
+                        let (func, args) = self.parse_callee()?;
+                        self.apply(expr, func, args)
+                    }
+                }
+                TokenKind::Dot => self.parse_dot(expr)?,
+                _ if newline => return Ok(expr),
+                TokenKind::Question => {
+                    self.bump();
+                    let span = self.span_from(expr.span);
+                    Expr::new(ExprKind::Try(Rc::new(expr)), span)
+                }
+                TokenKind::Keyword(Keyword::As) => {
+                    self.bump();
+                    let (name, _) = self.expect_ident("a name after `as`")?;
+                    let span = self.span_from(expr.span);
+                    Expr::new(
+                        ExprKind::As {
+                            expr: Rc::new(expr),
+                            name,
+                        },
+                        span,
+                    )
+                }
+                TokenKind::Ident(_) => {
+                    let (func, args) = self.parse_callee()?;
+                    self.apply(expr, func, args)
+                }
+                TokenKind::LBrace if !self.no_block => {
+                    let func = self.parse_block_lambda()?;
+                    self.apply(expr, func, None)
+                }
+                _ => return Ok(expr),
+            };
+            expr = self.parse_accessors(expr)?;
+        }
+    }
+
+    /// True when the `n`th token is an operator, `.field` or `as` that
+    /// opens a statement and so applies to the implicit subject.
+    fn subject_use_at(&self, n: usize) -> bool {
+        let token = self.nth(n);
+        match token.kind {
+            // `-x` is negation; `- x` subtracts from the subject.
+            TokenKind::Minus => self.nth(n + 1).space_before,
+            TokenKind::Dot | TokenKind::Pipe | TokenKind::Keyword(Keyword::As) => true,
+            ref kind => binary_op(kind).is_some(),
+        }
+    }
+
+    fn apply(&mut self, subject: Expr, func: Expr, args: Option<Vec<Arg>>) -> Expr {
+        let span = self.span_from(subject.span);
+        Expr::new(
+            ExprKind::Apply {
+                func: Rc::new(func),
+                subject: Rc::new(subject),
+                args,
+            },
+            span,
+        )
+    }
+
+    /// The function in a postfix application: a name, optionally with
+    /// touching field accesses (`x math.double`), followed by an optional
+    /// touching argument list.
+    fn parse_callee(&mut self) -> PResult<(Expr, Option<Vec<Arg>>)> {
+        let start = self.peek().span;
+        let (name, _) = self.expect_ident("a function name")?;
+        let mut func = Expr::new(ExprKind::Ident(name), start);
+        loop {
+            let token = self.peek();
+            if token.space_before {
+                return Ok((func, None));
+            }
+            match token.kind {
+                TokenKind::Dot => {
+                    self.bump();
+                    let name = self.parse_field_name()?;
+                    let span = self.span_from(start);
+                    func = Expr::new(
+                        ExprKind::Field {
+                            expr: Rc::new(func),
+                            name,
+                        },
+                        span,
+                    );
+                }
+                TokenKind::LParen => {
+                    let args = self.parse_args()?;
+                    return Ok((func, Some(args)));
+                }
+                _ => return Ok((func, None)),
+            }
+        }
+    }
+
+    /// `.field` or `.[index]` applied to `expr`.
+    fn parse_dot(&mut self, expr: Expr) -> PResult<Expr> {
+        self.bump();
+        if self.at(&TokenKind::LBracket) {
+            let open = self.bump().span;
+            let index = self.unrestricted(|p| p.parse_expr())?;
+            if !self.eat(&TokenKind::RBracket) {
+                return Err(self.unclosed("[", "]", open));
+            }
+            let span = self.span_from(expr.span);
+            return Ok(Expr::new(
+                ExprKind::Index {
+                    expr: Rc::new(expr),
+                    index: Rc::new(index),
+                },
+                span,
+            ));
+        }
+        let name = self.parse_field_name()?;
+        let span = self.span_from(expr.span);
+        Ok(Expr::new(
+            ExprKind::Field {
+                expr: Rc::new(expr),
+                name,
+            },
+            span,
+        ))
+    }
+
+    /// A `{ ... }` block used as a function of the implicit subject.
+    fn parse_block_lambda(&mut self) -> PResult<Expr> {
+        let start = self.peek().span;
+        let outer = std::mem::replace(&mut self.uses_subject, false);
+        let body = self.parse_braced_block()?;
+        let span = self.span_from(start);
+        Ok(Expr::new(
+            ExprKind::Lambda(Rc::new(FnDef {
+                name: None,
+                params: Vec::new(),
+                ret: None,
+                body,
+                uses_subject: std::mem::replace(&mut self.uses_subject, outer),
+                doc: None,
+                span,
+            })),
+            span,
+        ))
+    }
+
+    /// Parse with the block restriction lifted, inside brackets.
+    fn unrestricted<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        let saved = std::mem::replace(&mut self.no_block, false);
+        let result = f(self);
+        self.no_block = saved;
+        result
+    }
+
+    /// The condition of `if`, `while` or `match`, where a `{` ends the
+    /// expression instead of applying a block to it.
+    fn parse_condition(&mut self) -> PResult<Expr> {
+        let saved = std::mem::replace(&mut self.no_block, true);
+        let result = self.parse_expr();
+        self.no_block = saved;
+        result
+    }
+
+    /// A prefix expression followed by its accessors.
+    fn parse_operand(&mut self) -> PResult<Expr> {
+        let expr = self.parse_prefix()?;
+        self.parse_accessors(expr)
+    }
+
+    fn parse_prefix(&mut self) -> PResult<Expr> {
+        let start = self.peek().span;
+        match self.kind() {
+            TokenKind::Minus => {
+                self.bump();
+                if let TokenKind::Int(n) = *self.kind()
+                    && !self.peek().space_before
+                {
+                    self.bump();
+                    return self.negative_int_literal(n, self.span_from(start));
+                }
+                let expr = self.parse_operand()?;
+                Ok(Expr::new(
+                    ExprKind::Unary {
+                        op: UnaryOp::Neg,
+                        expr: Rc::new(expr),
+                    },
+                    self.span_from(start),
+                ))
+            }
+            TokenKind::Keyword(Keyword::Not) => {
+                self.bump();
+                let expr = self.parse_operand()?;
+                Ok(Expr::new(
+                    ExprKind::Unary {
+                        op: UnaryOp::Not,
+                        expr: Rc::new(expr),
+                    },
+                    self.span_from(start),
+                ))
+            }
+            TokenKind::Amp => {
+                self.bump();
+                let mutable = self.eat_kw(Keyword::Mut);
+                let expr = self.parse_operand()?;
+                Ok(Expr::new(
+                    ExprKind::Ref {
+                        mutable,
+                        expr: Rc::new(expr),
+                    },
+                    self.span_from(start),
+                ))
+            }
+            _ => self.parse_primary(),
+        }
+    }
+
+    fn negative_int_literal(&mut self, n: u64, span: Span) -> PResult<Expr> {
+        if n > i64::MAX as u64 + 1 {
+            return Err(self.error("integer literal is too large", span));
+        }
+        let value = (n as i128).wrapping_neg() as i64;
+        Ok(Expr::new(ExprKind::Int(value), span))
+    }
+
+    /// Accessors that touch the expression: direct calls `f(x)`, legacy
+    /// `x[i]` indexing, `.field`, `.[n]` and `?`. Spaced `.field` and `?`
+    /// apply to the whole chain instead (see [`Parser::parse_chain`]).
+    fn parse_accessors(&mut self, mut expr: Expr) -> PResult<Expr> {
+        loop {
+            let token = self.peek();
+            let touching = !token.space_before;
+            match token.kind {
+                TokenKind::LParen if touching => {
+                    let args = self.parse_args()?;
+                    let span = expr.span.to(self.prev_span());
+                    expr = Expr::new(
+                        ExprKind::Call {
+                            func: Rc::new(expr),
+                            args,
+                        },
+                        span,
+                    );
+                }
+                TokenKind::LBracket if touching => {
+                    let open = self.bump().span;
+                    let index = self.unrestricted(|p| p.parse_expr())?;
+                    if !self.eat(&TokenKind::RBracket) {
+                        return Err(self.unclosed("[", "]", open));
+                    }
+                    let span = expr.span.to(self.prev_span());
+                    expr = Expr::new(
+                        ExprKind::Index {
+                            expr: Rc::new(expr),
+                            index: Rc::new(index),
+                        },
+                        span,
+                    );
+                }
+                TokenKind::Dot if touching => expr = self.parse_dot(expr)?,
+                TokenKind::Question if touching => {
+                    self.bump();
+                    let span = expr.span.to(self.prev_span());
+                    expr = Expr::new(ExprKind::Try(Rc::new(expr)), span);
+                }
+                _ => return Ok(expr),
+            }
+        }
+    }
+
+    /// Field names may be keywords (`obj.let`).
+    fn parse_field_name(&mut self) -> PResult<String> {
+        match self.kind().clone() {
+            TokenKind::Ident(name) => {
+                self.bump();
+                Ok(name)
+            }
+            TokenKind::Keyword(kw) => {
+                self.bump();
+                Ok(kw.as_str().to_string())
+            }
+            _ => Err(self.unexpected("a field name")),
+        }
+    }
+
+    fn parse_args(&mut self) -> PResult<Vec<Arg>> {
+        self.unrestricted(|p| p.parse_arg_list())
+    }
+
+    fn parse_arg_list(&mut self) -> PResult<Vec<Arg>> {
+        let open = self.bump().span;
+        let mut args = Vec::new();
+        loop {
+            if self.eat(&TokenKind::RParen) {
+                break;
+            }
+            if self.at_eof() {
+                return Err(self.unclosed("(", ")", open));
+            }
+            match self.parse_arg() {
+                Ok(arg) => args.push(arg),
+                Err(Failed) => self.recover_in_list(),
+            }
+            if self.eat(&TokenKind::Comma) {
+                continue;
+            }
+            if self.eat(&TokenKind::RParen) {
+                break;
+            }
+            if self.at_eof() {
+                return Err(self.unclosed("(", ")", open));
+            }
+            return Err(self.unexpected("`,` or `)`"));
+        }
+        Ok(args)
+    }
+
+    fn parse_arg(&mut self) -> PResult<Arg> {
+        let spread = self.eat(&TokenKind::Ellipsis);
+        let name = match (self.kind(), &self.nth(1).kind) {
+            (TokenKind::Ident(name), TokenKind::Colon) if !spread => {
+                let name = name.clone();
+                self.bump();
+                self.bump();
+                Some(name)
+            }
+            _ => None,
+        };
+        let value = self.parse_expr()?;
+        Ok(Arg {
+            name,
+            spread,
+            value: Rc::new(value),
+        })
+    }
+
+    fn parse_primary(&mut self) -> PResult<Expr> {
+        let start = self.peek().span;
+        let kind = match self.kind().clone() {
+            TokenKind::Int(n) => {
+                self.bump();
+                if n > i64::MAX as u64 {
+                    return Err(self.error("integer literal is too large", start));
+                }
+                ExprKind::Int(n as i64)
+            }
+            TokenKind::Float(x) => {
+                self.bump();
+                ExprKind::Float(x)
+            }
+            TokenKind::Str(parts) => {
+                self.bump();
+                self.string_literal(parts)
+            }
+            TokenKind::Ident(name)
+                if name == "handler" && self.nth(1).kind == TokenKind::LBrace =>
+            {
+                return self.parse_handler();
+            }
+            TokenKind::Ident(name) => {
+                self.bump();
+                ExprKind::Ident(name)
+            }
+            TokenKind::Keyword(kw) => return self.parse_keyword_expr(kw),
+            TokenKind::LParen => return self.unrestricted(|p| p.parse_paren()),
+            TokenKind::LBracket => return self.unrestricted(|p| p.parse_list()),
+            // `{ * 2 }`, `{ as x ... }`: a function of the implicit subject.
+            TokenKind::LBrace if self.subject_use_at(1) => return self.parse_block_lambda(),
+            TokenKind::LBrace => return self.unrestricted(|p| p.parse_brace()),
+            TokenKind::At => {
+                let stage = self.parse_stage()?;
+                let expr = if self.at(&TokenKind::LBrace) {
+                    let block = self.parse_braced_block()?;
+                    let span = block.span;
+                    Expr::new(ExprKind::Block(block), span)
+                } else {
+                    self.parse_expr()?
+                };
+                ExprKind::Stage {
+                    stage,
+                    expr: Rc::new(expr),
+                }
+            }
+            _ => return Err(self.unexpected("an expression")),
+        };
+        Ok(Expr::new(kind, self.span_from(start)))
+    }
+
+    fn string_literal(&mut self, parts: Vec<StrPart>) -> ExprKind {
+        if let [StrPart::Text(text)] = parts.as_slice() {
+            return ExprKind::Str(text.clone());
+        }
+        let mut out = Vec::new();
+        for part in parts {
+            match part {
+                StrPart::Text(text) => out.push(InterpPart::Text(text)),
+                StrPart::Interp(tokens) => {
+                    let eof_span = tokens.last().map(|t| t.span).unwrap_or_default();
+                    let mut sub = Parser::new(tokens);
+                    let expr = match sub.parse_expr() {
+                        Ok(expr) if sub.at_eof() => expr,
+                        Ok(_) => {
+                            sub.unexpected("`}` to close the interpolation");
+                            Expr::new(ExprKind::Error, eof_span)
+                        }
+                        Err(Failed) => Expr::new(ExprKind::Error, eof_span),
+                    };
+                    self.diagnostics.append(&mut sub.diagnostics);
+                    out.push(InterpPart::Expr(Rc::new(expr)));
+                }
+            }
+        }
+        ExprKind::Interp(out)
+    }
+
+    fn parse_keyword_expr(&mut self, kw: Keyword) -> PResult<Expr> {
+        let start = self.peek().span;
+        let kind = match kw {
+            Keyword::True | Keyword::False => {
+                self.bump();
+                ExprKind::Bool(kw == Keyword::True)
+            }
+            Keyword::Nil => {
+                self.bump();
+                ExprKind::Nil
+            }
+            Keyword::Fn => ExprKind::Lambda(Rc::new(self.parse_fn()?)),
+            Keyword::If => return self.parse_if(),
+            Keyword::Match => return self.parse_match(),
+            Keyword::Loop => {
+                self.bump();
+                ExprKind::Loop(self.parse_braced_block()?)
+            }
+            Keyword::While => {
+                self.bump();
+                let cond = self.parse_condition()?;
+                let body = self.parse_braced_block()?;
+                ExprKind::While {
+                    cond: Rc::new(cond),
+                    body,
+                }
+            }
+            Keyword::Break => {
+                self.bump();
+                ExprKind::Break(self.parse_optional_value()?)
+            }
+            Keyword::Continue => {
+                self.bump();
+                ExprKind::Continue
+            }
+            Keyword::Return => {
+                self.bump();
+                ExprKind::Return(self.parse_optional_value()?)
+            }
+            Keyword::Spawn => {
+                self.bump();
+                ExprKind::Spawn(self.parse_braced_block()?)
+            }
+            Keyword::Nursery => {
+                self.bump();
+                ExprKind::Nursery(self.parse_braced_block()?)
+            }
+            Keyword::Handle => return self.parse_legacy_handle(),
+            Keyword::Send => return self.parse_legacy_send(),
+            _ => return Err(self.unexpected("an expression")),
+        };
+        Ok(Expr::new(kind, self.span_from(start)))
+    }
+
+    /// The value after `break` / `return`, if one follows on the same line.
+    fn parse_optional_value(&mut self) -> PResult<Option<Rc<Expr>>> {
+        if self.at_line_start()
+            || matches!(
+                self.kind(),
+                TokenKind::Eof | TokenKind::Semi | TokenKind::RBrace | TokenKind::RParen
+            )
+        {
+            return Ok(None);
+        }
+        Ok(Some(Rc::new(self.parse_expr()?)))
+    }
+
+    fn parse_if(&mut self) -> PResult<Expr> {
+        let start = self.bump().span;
+        let cond = self.parse_condition()?;
+        let then = self.parse_braced_block()?;
+        let otherwise = if self.eat_kw(Keyword::Else) {
+            if self.at_kw(Keyword::If) {
+                Some(Rc::new(self.parse_if()?))
+            } else {
+                let block = self.parse_braced_block()?;
+                let span = block.span;
+                Some(Rc::new(Expr::new(ExprKind::Block(block), span)))
+            }
+        } else {
+            None
+        };
+        Ok(Expr::new(
+            ExprKind::If {
+                cond: Rc::new(cond),
+                then,
+                otherwise,
+            },
+            self.span_from(start),
+        ))
+    }
+
+    fn parse_match(&mut self) -> PResult<Expr> {
+        let start = self.bump().span;
+        let subject = self.parse_condition()?;
+        let open = self.expect(&TokenKind::LBrace, "`{`")?;
+        let mut arms = Vec::new();
+        loop {
+            while self.eat(&TokenKind::Comma) || self.eat(&TokenKind::Semi) {}
+            if self.eat(&TokenKind::RBrace) {
+                break;
+            }
+            if self.at_eof() {
+                return Err(self.unclosed("{", "}", open));
+            }
+            match self.parse_arm() {
+                Ok(arm) => arms.push(arm),
+                Err(Failed) => self.synchronize(),
+            }
+        }
+        if arms.is_empty() {
+            let span = self.span_from(start);
+            return Err(self.error("match requires at least one arm", span));
+        }
+        Ok(Expr::new(
+            ExprKind::Match {
+                subject: Rc::new(subject),
+                arms,
+            },
+            self.span_from(start),
+        ))
+    }
+
+    fn parse_arm(&mut self) -> PResult<MatchArm> {
+        let start = self.peek().span;
+        let pattern = self.parse_or_pattern()?;
+        let guard = if self.eat_kw(Keyword::If) {
+            Some(Rc::new(self.parse_condition()?))
+        } else {
+            None
+        };
+        let body = if self.eat(&TokenKind::FatArrow) {
+            self.parse_expr()?
+        } else {
+            let block = self.parse_braced_block()?;
+            let span = block.span;
+            Expr::new(ExprKind::Block(block), span)
+        };
+        Ok(MatchArm {
+            pattern,
+            guard,
+            body: Rc::new(body),
+            span: self.span_from(start),
+        })
+    }
+
+    /// `( ... )`: grouping, a tuple (`(a, b)`, parsed as a list), or a
+    /// multi-statement expression.
+    fn parse_paren(&mut self) -> PResult<Expr> {
+        let open = self.bump().span;
+        if self.eat(&TokenKind::RParen) {
+            return Ok(Expr::new(ExprKind::Nil, self.span_from(open)));
+        }
+        let stmts = self.parse_stmts(Some(&TokenKind::RParen));
+        if self.at(&TokenKind::Comma) {
+            return self.finish_tuple(open, stmts);
+        }
+        if !self.eat(&TokenKind::RParen) {
+            return Err(self.unclosed("(", ")", open));
+        }
+        let span = self.span_from(open);
+        if let [
+            Stmt {
+                kind: StmtKind::Expr { expr, semi: false },
+                ..
+            },
+        ] = stmts.as_slice()
+        {
+            let mut expr = (**expr).clone();
+            expr.span = span;
+            return Ok(expr);
+        }
+        Ok(Expr::new(
+            ExprKind::Block(Rc::new(Block { stmts, span })),
+            span,
+        ))
+    }
+
+    fn finish_tuple(&mut self, open: Span, first: Vec<Stmt>) -> PResult<Expr> {
+        let mut items = Vec::new();
+        match first.as_slice() {
+            [
+                Stmt {
+                    kind: StmtKind::Expr { expr, .. },
+                    ..
+                },
+            ] => items.push(Item {
+                spread: false,
+                expr: expr.clone(),
+            }),
+            _ => return Err(self.unexpected("`)`")),
+        }
+        while self.eat(&TokenKind::Comma) {
+            if self.at(&TokenKind::RParen) {
+                break;
+            }
+            let expr = self.parse_expr()?;
+            items.push(Item {
+                spread: false,
+                expr: Rc::new(expr),
+            });
+        }
+        if !self.eat(&TokenKind::RParen) {
+            return Err(self.unclosed("(", ")", open));
+        }
+        Ok(Expr::new(ExprKind::List(items), self.span_from(open)))
+    }
+
+    fn parse_list(&mut self) -> PResult<Expr> {
+        let open = self.bump().span;
+        let mut items = Vec::new();
+        loop {
+            if self.eat(&TokenKind::RBracket) {
+                break;
+            }
+            if self.at_eof() {
+                return Err(self.unclosed("[", "]", open));
+            }
+            let spread = self.eat(&TokenKind::Ellipsis);
+            match self.parse_expr() {
+                Ok(expr) => items.push(Item {
+                    spread,
+                    expr: Rc::new(expr),
+                }),
+                Err(Failed) => self.recover_in_list(),
+            }
+            if self.eat(&TokenKind::Comma) {
+                continue;
+            }
+            if self.eat(&TokenKind::RBracket) {
+                break;
+            }
+            if self.at_eof() || self.at_line_start() && !self.is_closer() {
+                return Err(self.unclosed("[", "]", open));
+            }
+            return Err(self.unexpected("`,` or `]`"));
+        }
+        Ok(Expr::new(ExprKind::List(items), self.span_from(open)))
+    }
+
+    /// `{` in expression position: an object or a block, decided from the
+    /// first tokens. `{}`, `{ key: ...`, `{ ...spread`, `{ [key]: ...` and
+    /// `{ name, ...` are objects; anything else is a block evaluated in
+    /// place. `{ name }` could be either and is rejected.
+    fn parse_brace(&mut self) -> PResult<Expr> {
+        match self.brace_kind() {
+            BraceKind::Object => self.parse_object(),
+            BraceKind::Block => {
+                let block = self.parse_braced_block()?;
+                let span = block.span;
+                Ok(Expr::new(ExprKind::Block(block), span))
+            }
+            BraceKind::Ambiguous(name) => {
+                let start = self.bump().span;
+                self.bump();
+                self.bump();
+                let span = self.span_from(start);
+                self.diagnostics.push(
+                    Diagnostic::error(
+                        format!("ambiguous `{{ {} }}`: object shorthand or block", name),
+                        span,
+                    )
+                    .with_note(format!("for a block, use parentheses: `({})`", name))
+                    .with_note(format!(
+                        "for an object, write the field out: `{{ {}: {} }}`",
+                        name, name
+                    )),
+                );
+                Ok(Expr::new(ExprKind::Error, span))
+            }
+        }
+    }
+
+    fn brace_kind(&self) -> BraceKind {
+        let key = &self.nth(1).kind;
+        let after = &self.nth(2).kind;
+        match key {
+            TokenKind::RBrace | TokenKind::Ellipsis => BraceKind::Object,
+            TokenKind::Ident(name) => match after {
+                TokenKind::Colon | TokenKind::Comma => BraceKind::Object,
+                TokenKind::RBrace => BraceKind::Ambiguous(name.clone()),
+                _ => BraceKind::Block,
+            },
+            TokenKind::Str(_) | TokenKind::Keyword(_) if after == &TokenKind::Colon => {
+                BraceKind::Object
+            }
+            TokenKind::LBracket => {
+                // `{ [key]: value }`: find the matching `]`.
+                let mut depth = 0usize;
+                let mut n = 1;
+                loop {
+                    match self.nth(n).kind {
+                        TokenKind::LBracket => depth += 1,
+                        TokenKind::RBracket => {
+                            depth -= 1;
+                            if depth == 0 {
+                                break;
+                            }
+                        }
+                        TokenKind::Eof => return BraceKind::Block,
+                        _ => {}
+                    }
+                    n += 1;
+                }
+                if self.nth(n + 1).kind == TokenKind::Colon {
+                    BraceKind::Object
+                } else {
+                    BraceKind::Block
+                }
+            }
+            _ => BraceKind::Block,
+        }
+    }
+
+    fn parse_object(&mut self) -> PResult<Expr> {
+        let open = self.bump().span;
+        let mut items = Vec::new();
+        loop {
+            if self.eat(&TokenKind::RBrace) {
+                break;
+            }
+            if self.at_eof() {
+                return Err(self.unclosed("{", "}", open));
+            }
+            match self.parse_object_item() {
+                Ok(item) => items.push(item),
+                Err(Failed) => self.recover_in_list(),
+            }
+            if self.eat(&TokenKind::Comma) {
+                continue;
+            }
+            if self.eat(&TokenKind::RBrace) {
+                break;
+            }
+            if self.at_eof() || self.at_line_start() && !self.is_closer() {
+                return Err(self.unclosed("{", "}", open));
+            }
+            return Err(self.unexpected("`,` or `}`"));
+        }
+        Ok(Expr::new(ExprKind::Object(items), self.span_from(open)))
+    }
+
+    fn parse_object_item(&mut self) -> PResult<ObjectItem> {
+        if self.eat(&TokenKind::Ellipsis) {
+            let expr = self.parse_expr()?;
+            return Ok(ObjectItem::Spread(Rc::new(expr)));
+        }
+        let start = self.peek().span;
+        let key = match self.kind().clone() {
+            TokenKind::LBracket => {
+                let open = self.bump().span;
+                let expr = self.parse_expr()?;
+                if !self.eat(&TokenKind::RBracket) {
+                    return Err(self.unclosed("[", "]", open));
+                }
+                Key::Computed(Rc::new(expr))
+            }
+            TokenKind::Str(parts) => match parts.as_slice() {
+                [StrPart::Text(text)] => {
+                    self.bump();
+                    Key::Name(text.clone())
+                }
+                _ => return Err(self.error("object keys cannot be interpolated", start)),
+            },
+            _ => Key::Name(self.parse_field_name()?),
+        };
+        if self.eat(&TokenKind::Colon) {
+            let value = self.parse_expr()?;
+            return Ok(ObjectItem::Field {
+                key,
+                value: Rc::new(value),
+            });
+        }
+        // shorthand `{ x }` means `{ x: x }`
+        match key {
+            Key::Name(name) => {
+                let value = Expr::new(ExprKind::Ident(name.clone()), self.span_from(start));
+                Ok(ObjectItem::Field {
+                    key: Key::Name(name),
+                    value: Rc::new(value),
+                })
+            }
+            Key::Computed(_) => Err(self.unexpected("`:`")),
+        }
+    }
+
+    // ---------------------------------------------------------------------
+    // Patterns
+    // ---------------------------------------------------------------------
+
+    /// A pattern that may have `or` alternatives (match arms).
+    fn parse_or_pattern(&mut self) -> PResult<Pattern> {
+        let first = self.parse_pattern()?;
+        if !self.at_kw(Keyword::Or) {
+            return Ok(first);
+        }
+        let start = first.span;
+        let mut alternatives = vec![first];
+        while self.eat_kw(Keyword::Or) {
+            alternatives.push(self.parse_pattern()?);
+        }
+        Ok(Pattern {
+            kind: PatternKind::Or(alternatives),
+            span: self.span_from(start),
+        })
+    }
+
+    fn parse_pattern(&mut self) -> PResult<Pattern> {
+        let start = self.peek().span;
+        let kind = match self.kind().clone() {
+            TokenKind::Ident(name) if name == "_" => {
+                self.bump();
+                PatternKind::Wildcard
+            }
+            TokenKind::Ident(name) => {
+                self.bump();
+                if self.at(&TokenKind::LParen) && !self.peek().space_before {
+                    let open = self.bump().span;
+                    let inner = self.parse_pattern()?;
+                    if !self.eat(&TokenKind::RParen) {
+                        return Err(self.unclosed("(", ")", open));
+                    }
+                    PatternKind::Tagged {
+                        tag: name,
+                        inner: Box::new(inner),
+                    }
+                } else {
+                    PatternKind::Bind {
+                        name,
+                        mutable: false,
+                    }
+                }
+            }
+            TokenKind::Keyword(Keyword::Mut) => {
+                self.bump();
+                let (name, _) = self.expect_ident("a name after `mut`")?;
+                PatternKind::Bind {
+                    name,
+                    mutable: true,
+                }
+            }
+            TokenKind::LBracket => {
+                let open = self.bump().span;
+                let (items, rest) =
+                    self.parse_list_pattern_items(&TokenKind::RBracket, "[", "]", open)?;
+                PatternKind::List { items, rest }
+            }
+            TokenKind::LParen => {
+                let open = self.bump().span;
+                let (items, rest) =
+                    self.parse_list_pattern_items(&TokenKind::RParen, "(", ")", open)?;
+                PatternKind::List { items, rest }
+            }
+            TokenKind::LBrace => return self.parse_object_pattern(),
+            TokenKind::Int(_)
+            | TokenKind::Float(_)
+            | TokenKind::Str(_)
+            | TokenKind::Minus
+            | TokenKind::Keyword(Keyword::True | Keyword::False | Keyword::Nil) => {
+                let expr = self.parse_prefix()?;
+                PatternKind::Literal(Rc::new(expr))
+            }
+            _ => return Err(self.unexpected("a pattern")),
+        };
+        Ok(Pattern {
+            kind,
+            span: self.span_from(start),
+        })
+    }
+
+    fn parse_rest_name(&mut self) -> Option<String> {
+        match self.kind().clone() {
+            TokenKind::Ident(name) if name != "_" => {
+                self.bump();
+                Some(name)
+            }
+            TokenKind::Ident(_) => {
+                self.bump();
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Items of `[a, b, ...rest]` or `(a, b)` up to and including `close`.
+    fn parse_list_pattern_items(
+        &mut self,
+        close: &TokenKind,
+        open_text: &str,
+        close_text: &str,
+        open: Span,
+    ) -> PResult<(Vec<Pattern>, Option<Option<String>>)> {
+        let mut items = Vec::new();
+        let mut rest = None;
+        loop {
+            if self.eat(close) {
+                break;
+            }
+            if self.at_eof() {
+                return Err(self.unclosed(open_text, close_text, open));
+            }
+            if self.eat(&TokenKind::Ellipsis) {
+                rest = Some(self.parse_rest_name());
+            } else {
+                items.push(self.parse_pattern()?);
+            }
+            if self.eat(&TokenKind::Comma) {
+                continue;
+            }
+            if self.eat(close) {
+                break;
+            }
+            if self.at_eof() {
+                return Err(self.unclosed(open_text, close_text, open));
+            }
+            return Err(self.unexpected(&format!("`,` or `{}`", close_text)));
+        }
+        Ok((items, rest))
+    }
+
+    fn parse_object_pattern(&mut self) -> PResult<Pattern> {
+        let open = self.bump().span;
+        let mut fields = Vec::new();
+        let mut rest = None;
+        loop {
+            if self.eat(&TokenKind::RBrace) {
+                break;
+            }
+            if self.at_eof() {
+                return Err(self.unclosed("{", "}", open));
+            }
+            if self.eat(&TokenKind::Ellipsis) {
+                rest = Some(self.parse_rest_name());
+            } else {
+                let start = self.peek().span;
+                let name = self.parse_field_name()?;
+                let pattern = if self.eat(&TokenKind::Colon) {
+                    self.parse_pattern()?
+                } else {
+                    Pattern {
+                        kind: PatternKind::Bind {
+                            name: name.clone(),
+                            mutable: false,
+                        },
+                        span: self.span_from(start),
+                    }
+                };
+                fields.push((name, pattern));
+            }
+            if self.eat(&TokenKind::Comma) {
+                continue;
+            }
+            if self.eat(&TokenKind::RBrace) {
+                break;
+            }
+            if self.at_eof() {
+                return Err(self.unclosed("{", "}", open));
+            }
+            return Err(self.unexpected("`,` or `}`"));
+        }
+        Ok(Pattern {
+            kind: PatternKind::Object { fields, rest },
+            span: self.span_from(open),
+        })
+    }
+
+    // ---------------------------------------------------------------------
+    // Types
+    // ---------------------------------------------------------------------
+
+    fn parse_type(&mut self) -> PResult<TypeExpr> {
+        let start = self.peek().span;
+        let kind = match self.kind().clone() {
+            TokenKind::Ident(name) => {
+                self.bump();
+                let mut args = Vec::new();
+                if !self.peek().space_before
+                    && (self.at(&TokenKind::LParen) || self.at(&TokenKind::Lt))
+                {
+                    let close = if self.at(&TokenKind::LParen) {
+                        TokenKind::RParen
+                    } else {
+                        TokenKind::Gt
+                    };
+                    self.bump();
+                    while !self.eat(&close) {
+                        if self.at_eof() {
+                            return Err(self.unexpected("a closing bracket for the type arguments"));
+                        }
+                        args.push(self.parse_type_arg()?);
+                        if !self.eat(&TokenKind::Comma) {
+                            self.expect(&close, "`,` or a closing bracket")?;
+                            break;
+                        }
+                    }
+                }
+                TypeKind::Named { name, args }
+            }
+            TokenKind::LParen => {
+                self.bump();
+                self.expect(&TokenKind::RParen, "`)`")?;
+                TypeKind::Unit
+            }
+            TokenKind::LBracket => {
+                let open = self.bump().span;
+                let elem = self.parse_type()?;
+                let len = if self.eat(&TokenKind::Semi) {
+                    Some(Box::new(self.parse_type_arg()?))
+                } else {
+                    None
+                };
+                if !self.eat(&TokenKind::RBracket) {
+                    return Err(self.unclosed("[", "]", open));
+                }
+                TypeKind::List {
+                    elem: Box::new(elem),
+                    len,
+                }
+            }
+            TokenKind::LBrace => {
+                let open = self.bump().span;
+                let mut fields = Vec::new();
+                while !self.eat(&TokenKind::RBrace) {
+                    if self.at_eof() {
+                        return Err(self.unclosed("{", "}", open));
+                    }
+                    let name = self.parse_field_name()?;
+                    self.expect(&TokenKind::Colon, "`:`")?;
+                    fields.push((name, self.parse_type()?));
+                    if !self.eat(&TokenKind::Comma) {
+                        if !self.eat(&TokenKind::RBrace) {
+                            return Err(self.unexpected("`,` or `}`"));
+                        }
+                        break;
+                    }
+                }
+                TypeKind::Object(fields)
+            }
+            _ => return Err(self.unexpected("a type")),
+        };
+        let mut ty = TypeExpr {
+            kind,
+            span: self.span_from(start),
+        };
+        while self.at(&TokenKind::Question) && !self.peek().space_before {
+            self.bump();
+            ty = TypeExpr {
+                kind: TypeKind::Optional(Box::new(ty)),
+                span: self.span_from(start),
+            };
+        }
+        Ok(ty)
+    }
+
+    /// A type argument: a type, or a value such as `16` or `config.size`.
+    fn parse_type_arg(&mut self) -> PResult<TypeExpr> {
+        let starts_type = match self.kind() {
+            TokenKind::Ident(name) => name.starts_with(|c: char| c.is_uppercase()),
+            TokenKind::LParen | TokenKind::LBracket | TokenKind::LBrace => true,
+            _ => false,
+        };
+        if starts_type {
+            return self.parse_type();
+        }
+        let expr = self.parse_operand()?;
+        let span = expr.span;
+        Ok(TypeExpr {
+            kind: TypeKind::Value(Rc::new(expr)),
+            span,
+        })
+    }
+}
+
+/// What a `{` in expression position opens.
+enum BraceKind {
+    Object,
+    Block,
+    /// `{ name }`: object shorthand or a block returning `name`.
+    Ambiguous(String),
+}
+
+fn binary_op(kind: &TokenKind) -> Option<BinOp> {
+    Some(match kind {
+        TokenKind::Plus => BinOp::Add,
+        TokenKind::Minus => BinOp::Sub,
+        TokenKind::Star => BinOp::Mul,
+        TokenKind::Slash => BinOp::Div,
+        TokenKind::Percent => BinOp::Mod,
+        TokenKind::EqEq => BinOp::Eq,
+        TokenKind::NotEq => BinOp::Ne,
+        TokenKind::Lt => BinOp::Lt,
+        TokenKind::Le => BinOp::Le,
+        TokenKind::Gt => BinOp::Gt,
+        TokenKind::Ge => BinOp::Ge,
+        TokenKind::Keyword(Keyword::And) => BinOp::And,
+        TokenKind::Keyword(Keyword::Or) => BinOp::Or,
+        _ => return None,
+    })
+}
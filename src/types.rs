@@ -0,0 +1,26 @@
+//! Static checks
+//!
+//! These passes run over the parsed program before it is evaluated. Each
+//! reports everything it finds rather than stopping at the first problem,
+//! and the program only runs if none of them found an error.
+
+mod borrow;
+mod effects;
+mod linearity;
+mod resume;
+
+use crate::ast::Program;
+use crate::diagnostic::Diagnostic;
+
+/// Check `program`, returning its diagnostics in source order per pass.
+pub fn check(program: &Program) -> Vec<Diagnostic> {
+    let mut diagnostics = linearity::check(program);
+    diagnostics.extend(effects::check(program));
+    diagnostics
+}
+
+/// The signatures of the program's top-level functions, with their
+/// inferred effects, for hover and documentation.
+pub fn signatures(program: &Program) -> Vec<String> {
+    effects::signatures(program)
+}
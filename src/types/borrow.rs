@@ -0,0 +1,167 @@
+//! Borrow checking
+//!
+//! A reference (`&x`, `&mut x.field`) is a *loan* of a place: a variable
+//! and a path of fields within it. There are no lifetime annotations.
+//! Instead each reference carries its origin, the set of loans it may
+//! point into, and a loan is live for as long as a variable in scope
+//! holds a reference whose origin contains it. An access to a place
+//! conflicts with the live loans that overlap it:
+//!
+//! - reading conflicts with a mutable loan;
+//! - assigning, moving or consuming conflicts with any loan;
+//! - borrowing conflicts with a mutable loan, and borrowing mutably with
+//!   any loan.
+//!
+//! A loan must not outlive the variable it borrows. Functions borrow the
+//! variables they capture as well, but those loans are only checked for
+//! outliving: using a variable while a closure that captures it is alive
+//! is what closures are for.
+//!
+//! The linearity checker drives this module: it walks the program, works
+//! out which loans are live, and asks here whether an access conflicts.
+
+use crate::diagnostic::{Diagnostic, Span};
+
+/// A variable, or a field path within one.
+#[derive(Clone)]
+pub struct Place {
+    /// The variable's id.
+    pub var: usize,
+    pub path: Vec<String>,
+}
+
+impl Place {
+    /// Whether the two places share any part: one is a prefix of the other.
+    fn overlaps(&self, other: &Place) -> bool {
+        self.var == other.var && self.path.iter().zip(&other.path).all(|(a, b)| a == b)
+    }
+}
+
+/// Where a reference may point.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Origin {
+    Loan(usize),
+    /// Into the function's `n`th argument; the caller substitutes its own
+    /// loan of that argument.
+    Param(usize),
+}
+
+pub struct Loan {
+    pub place: Place,
+    /// The place as written, for messages.
+    pub name: String,
+    pub mutable: bool,
+    /// Made by a function for a variable it captures.
+    pub capture: bool,
+    pub span: Span,
+}
+
+/// What is being done to a place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Move,
+    Consume,
+    Borrow,
+    BorrowMut,
+}
+
+/// A live loan, with the variable that keeps it alive.
+pub struct Held<'a> {
+    pub loan: usize,
+    pub holder: &'a str,
+    pub span: Span,
+}
+
+#[derive(Default)]
+pub struct Loans {
+    loans: Vec<Loan>,
+}
+
+impl Loans {
+    pub fn is_empty(&self) -> bool {
+        self.loans.is_empty()
+    }
+
+    pub fn get(&self, id: usize) -> &Loan {
+        &self.loans[id]
+    }
+
+    pub fn borrow(&mut self, loan: Loan) -> usize {
+        self.loans.push(loan);
+        self.loans.len() - 1
+    }
+
+    /// Check an access to `place` (written `name`) against the live loans.
+    pub fn check(
+        &self,
+        place: &Place,
+        name: &str,
+        access: Access,
+        span: Span,
+        live: &[Held],
+    ) -> Option<Diagnostic> {
+        for held in live {
+            let loan = &self.loans[held.loan];
+            if loan.capture || !loan.place.overlaps(place) {
+                continue;
+            }
+            let message = match (access, loan.mutable) {
+                (Access::Read, false) | (Access::Borrow, false) => continue,
+                (Access::Read, true) => "cannot access while mutably borrowed".to_string(),
+                (Access::Write, _) => "cannot mutate while borrowed".to_string(),
+                (Access::Move, _) => "cannot move borrowed value".to_string(),
+                (Access::Consume, _) => format!("cannot consume `{}` while borrowed", name),
+                (Access::Borrow, true) => "cannot borrow while mutably borrowed".to_string(),
+                (Access::BorrowMut, true) => "cannot borrow mutably twice".to_string(),
+                (Access::BorrowMut, false) => "cannot borrow mutably while borrowed".to_string(),
+            };
+            let kind = if loan.mutable {
+                "mutably borrowed"
+            } else {
+                "borrowed"
+            };
+            return Some(
+                Diagnostic::error(message, span)
+                    .with_label(loan.span, format!("`{}` is {} here", loan.name, kind))
+                    .with_label(
+                        held.span,
+                        format!(
+                            "the borrow is held by `{}` until the end of its scope",
+                            held.holder
+                        ),
+                    ),
+            );
+        }
+        None
+    }
+
+    /// The error for loan `id`, whose variable goes out of scope at the
+    /// end of `scope` while the loan is still held.
+    pub fn outlives(&self, id: usize, scope: Span) -> Diagnostic {
+        let loan = &self.loans[id];
+        let root = loan.name.split('.').next().unwrap_or(&loan.name);
+        if loan.capture {
+            Diagnostic::error(
+                format!("captured variable `{}` does not live long enough", root),
+                loan.span,
+            )
+            .with_label(
+                scope,
+                format!("`{}` goes out of scope at the end of this", root),
+            )
+            .with_note("the function that captures it is used after that")
+        } else {
+            Diagnostic::error("borrow outlives borrowed value", loan.span)
+                .with_label(
+                    scope,
+                    format!("`{}` goes out of scope at the end of this", root),
+                )
+                .with_note(format!(
+                    "`{}` is still referenced after it goes out of scope",
+                    loan.name
+                ))
+        }
+    }
+}
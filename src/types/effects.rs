@@ -0,0 +1,919 @@
+//! Effect inference
+//!
+//! Every function has an effect row: the effects its body may perform and
+//! does not handle itself. Rows are inferred, never written. Calling a
+//! function adds its row at the call site, and a `with` handler removes
+//! its effect from the row of the rest of its block. A function that
+//! calls one of its parameters has a row that is open in that parameter
+//! ("whatever `f` does"), which each call site fills in with the row of
+//! the function it passes.
+//!
+//! Effects are identified by their declaration, not their name. An effect
+//! declared inside a function can only be handled where that declaration
+//! is in scope; anywhere else a handler of the same name is a different
+//! effect, so a private effect propagates past it. Whatever reaches the
+//! top of the program unhandled is an error, reported with the chain of
+//! calls that brought it there.
+//!
+//! A `handler { ... }` value handles its effects wherever `with`
+//! installs it, as long as the value can be traced back to the literal:
+//! directly, through a `let`, or through a function that returns it.
+//!
+//! The built-in effects (`error`, `panic`, `cancel`, `alloc`) are
+//! ambient: they may be handled, but never have to be.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::ast::{
+    Arg, Block, EffectDecl, Expr, ExprKind, FnDef, Handler, HandlerDef, InterpPart, Item, Key,
+    ObjectItem, Pattern, PatternKind, Program, ResumeKind, Stmt, StmtKind,
+};
+use crate::diagnostic::{Diagnostic, Span};
+
+use super::resume;
+
+pub fn check(program: &Program) -> Vec<Diagnostic> {
+    let mut inference = Inference::solve(program);
+    let row = inference.run(program);
+    for performed in row.effects {
+        let diagnostic = inference.unhandled(&performed);
+        inference.diagnostics.push(diagnostic);
+    }
+    inference.diagnostics
+}
+
+/// The signature of each top-level function, with its inferred effect
+/// row: `fn use-ask() : <ask>`, or `<log | f>` for a row that is open in
+/// parameter `f`.
+pub fn signatures(program: &Program) -> Vec<String> {
+    let inference = Inference::solve(program);
+    program
+        .body
+        .stmts
+        .iter()
+        .filter_map(|stmt| match &stmt.kind {
+            StmtKind::Fn(def) => Some(inference.signature(def)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The built-in effects, with how their handlers may resume.
+const BUILTIN_EFFECTS: &[(&str, ResumeKind)] = &[
+    ("error", ResumeKind::Never),
+    ("panic", ResumeKind::Never),
+    ("cancel", ResumeKind::Never),
+    ("alloc", ResumeKind::Once),
+];
+
+/// Effects the prelude declares, in scope in every program. Unlike the
+/// built-in effects, they must be handled.
+const PRELUDE_EFFECTS: &[(&str, ResumeKind)] = &[("yield", ResumeKind::Many)];
+
+/// Prelude functions that never return.
+const ABORTS: &[&str] = &["throw", "panic"];
+
+/// Prelude functions that call one of their arguments, by the index of
+/// that argument (counting a piped subject as the first).
+const CALLBACKS: &[(&str, usize)] = &[
+    ("map", 1),
+    ("filter", 1),
+    ("reduce", 2),
+    ("each", 1),
+    ("drain", 1),
+];
+
+/// Give up on a fixpoint after this many passes. Rows only grow, and each
+/// pass lets them flow through one more level of forward reference, so
+/// this is never reached by a real program.
+const MAX_PASSES: usize = 32;
+
+struct Effect {
+    name: String,
+    span: Span,
+    resume: ResumeKind,
+    /// The function whose body declares the effect, if any.
+    owner: Option<String>,
+}
+
+/// One step on the way from where an effect is performed to where it
+/// ends up.
+#[derive(Clone)]
+struct Step {
+    span: Span,
+    label: String,
+}
+
+/// An effect in a row, with the steps that brought it there, outermost
+/// first.
+#[derive(Clone)]
+struct Performed {
+    effect: usize,
+    chain: Vec<Step>,
+    /// The `defer` block it is performed from, if any.
+    deferred: Option<Span>,
+}
+
+/// Whatever the function's `param`th parameter does when called, less the
+/// effects handled around the call.
+#[derive(Clone)]
+struct Open {
+    param: usize,
+    handled: Vec<usize>,
+    chain: Vec<Step>,
+}
+
+#[derive(Clone, Default)]
+struct Row {
+    effects: Vec<Performed>,
+    open: Vec<Open>,
+}
+
+impl Row {
+    fn add(&mut self, other: Row) {
+        for performed in other.effects {
+            if !self.effects.iter().any(|p| p.effect == performed.effect) {
+                self.effects.push(performed);
+            }
+        }
+        for open in other.open {
+            let same = |o: &&mut Open| o.param == open.param;
+            match self.open.iter_mut().find(same) {
+                // Either call may perform what the other handles.
+                Some(existing) => existing.handled.retain(|e| open.handled.contains(e)),
+                None => self.open.push(open),
+            }
+        }
+    }
+
+    fn handle(&mut self, effect: usize) {
+        self.effects.retain(|p| p.effect != effect);
+        for open in &mut self.open {
+            if !open.handled.contains(&effect) {
+                open.handled.push(effect);
+            }
+        }
+    }
+
+    /// The row as seen from a call: every chain starts at `step`.
+    fn through(mut self, step: &Step) -> Row {
+        for performed in &mut self.effects {
+            performed.chain.insert(0, step.clone());
+        }
+        for open in &mut self.open {
+            open.chain.insert(0, step.clone());
+        }
+        self
+    }
+
+    /// The row of a `defer` block performed at `span`.
+    fn deferred(mut self, span: Span) -> Row {
+        for performed in &mut self.effects {
+            performed.deferred.get_or_insert(span);
+        }
+        self
+    }
+
+    /// What the fixpoint compares: the effects and parameters, without
+    /// how they got there.
+    fn key(&self) -> (Vec<usize>, Vec<(usize, Vec<usize>)>) {
+        let mut effects: Vec<_> = self.effects.iter().map(|p| p.effect).collect();
+        effects.sort_unstable();
+        let mut open: Vec<_> = self
+            .open
+            .iter()
+            .map(|o| {
+                let mut handled = o.handled.clone();
+                handled.sort_unstable();
+                (o.param, handled)
+            })
+            .collect();
+        open.sort_unstable();
+        (effects, open)
+    }
+}
+
+/// What installing a `handler { ... }` value does: the effects it
+/// handles for the rest of the block, and the row of its clauses.
+#[derive(Clone, Default)]
+struct Installed {
+    handled: Vec<usize>,
+    row: Row,
+}
+
+#[derive(Clone)]
+enum Binding {
+    Effect(usize),
+    Func(Rc<FnDef>),
+    Handler(Rc<HandlerDef>),
+    /// Parameter `index` of the function at nesting `depth`.
+    Param {
+        depth: usize,
+        index: usize,
+    },
+    Value,
+}
+
+/// What a call calls.
+enum Target {
+    Effect(usize),
+    Func(Rc<FnDef>),
+    Param(usize),
+    /// A prelude function that calls its `n`th argument.
+    Callback(usize),
+    Unknown,
+}
+
+#[derive(Default)]
+struct Inference {
+    names: Vec<(String, Binding)>,
+    effects: Vec<Effect>,
+    ids: HashMap<*const EffectDecl, usize>,
+    /// The inferred row of each function, from the latest pass.
+    rows: HashMap<*const FnDef, Row>,
+    /// What each `handler { ... }` value handles, from the latest pass.
+    handlers: HashMap<*const HandlerDef, Installed>,
+    /// The names of the functions being walked, innermost last.
+    fns: Vec<Option<String>>,
+    changed: bool,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Inference {
+    /// Infer every function's row. Functions can call each other before
+    /// their definitions are reached, so this repeats until no row
+    /// changes.
+    fn solve(program: &Program) -> Inference {
+        let mut inference = Inference::default();
+        for &(name, resume) in PRELUDE_EFFECTS {
+            inference.effects.push(Effect {
+                name: name.to_string(),
+                span: Span::default(),
+                resume,
+                owner: None,
+            });
+            let id = inference.effects.len() - 1;
+            inference
+                .names
+                .push((name.to_string(), Binding::Effect(id)));
+        }
+        for _ in 0..MAX_PASSES {
+            inference.changed = false;
+            inference.run(program);
+            if !inference.changed {
+                break;
+            }
+        }
+        inference
+    }
+
+    /// Walk the program once, returning its row.
+    fn run(&mut self, program: &Program) -> Row {
+        self.diagnostics.clear();
+        self.block(&program.body)
+    }
+
+    fn lookup(&self, name: &str) -> Option<&Binding> {
+        self.names
+            .iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .map(|(_, binding)| binding)
+    }
+
+    fn bind(&mut self, pattern: &Pattern) {
+        match &pattern.kind {
+            PatternKind::Bind { name, .. } => self.names.push((name.clone(), Binding::Value)),
+            PatternKind::List { items, rest } => {
+                for item in items {
+                    self.bind(item);
+                }
+                if let Some(Some(name)) = rest {
+                    self.names.push((name.clone(), Binding::Value));
+                }
+            }
+            PatternKind::Object { fields, rest } => {
+                for (_, field) in fields {
+                    self.bind(field);
+                }
+                if let Some(Some(name)) = rest {
+                    self.names.push((name.clone(), Binding::Value));
+                }
+            }
+            PatternKind::Or(alternatives) => {
+                if let Some(first) = alternatives.first() {
+                    self.bind(first);
+                }
+            }
+            PatternKind::Tagged { inner, .. } => self.bind(inner),
+            PatternKind::Wildcard | PatternKind::Literal(_) => {}
+        }
+    }
+
+    // ---------------------------------------------------------------------
+    // Statements
+    // ---------------------------------------------------------------------
+
+    fn block(&mut self, block: &Block) -> Row {
+        let mark = self.names.len();
+        // Functions and effects are visible throughout their block.
+        for stmt in &block.stmts {
+            match &stmt.kind {
+                StmtKind::Fn(def) => {
+                    if let Some(name) = &def.name {
+                        self.names.push((name.clone(), Binding::Func(def.clone())));
+                    }
+                }
+                StmtKind::Effect(decl) => {
+                    let id = self.declare(decl);
+                    self.names.push((decl.name.clone(), Binding::Effect(id)));
+                }
+                _ => {}
+            }
+        }
+        let row = self.stmts(&block.stmts);
+        self.names.truncate(mark);
+        row
+    }
+
+    fn declare(&mut self, decl: &Rc<EffectDecl>) -> usize {
+        let key = Rc::as_ptr(decl);
+        if let Some(&id) = self.ids.get(&key) {
+            return id;
+        }
+        self.effects.push(Effect {
+            name: decl.name.clone(),
+            span: decl.span,
+            resume: decl.resume,
+            owner: self.fns.last().map(|name| match name {
+                Some(name) => format!("`{}`", name),
+                None => "a function".to_string(),
+            }),
+        });
+        let id = self.effects.len() - 1;
+        self.ids.insert(key, id);
+        id
+    }
+
+    /// Check how `handler` calls `resume` against the effect's `kind`.
+    fn resumes(&mut self, handler: &Handler, kind: ResumeKind, declared: Option<Span>) {
+        let aborts = |name: &str| match self.lookup(name) {
+            Some(Binding::Effect(id)) => self.effects[*id].resume == ResumeKind::Never,
+            Some(_) => false,
+            None => ABORTS.contains(&name),
+        };
+        if let Some(diagnostic) = resume::check(handler, kind, declared, &aborts) {
+            self.diagnostics.push(diagnostic);
+        }
+    }
+
+    fn stmts(&mut self, stmts: &[Stmt]) -> Row {
+        let mut row = Row::default();
+        for (i, stmt) in stmts.iter().enumerate() {
+            match &stmt.kind {
+                StmtKind::Let { pattern, value, .. } => {
+                    row.add(self.expr(value));
+                    let name = match &pattern.kind {
+                        PatternKind::Bind { name, .. } => Some(name.clone()),
+                        _ => None,
+                    };
+                    match (name, self.function_value(value), self.handler_value(value)) {
+                        (Some(name), Some(def), _) => self.names.push((name, Binding::Func(def))),
+                        (Some(name), _, Some(def)) => {
+                            self.names.push((name, Binding::Handler(def)));
+                        }
+                        _ => self.bind(pattern),
+                    }
+                }
+                StmtKind::Fn(def) => self.function(def),
+                StmtKind::With(handler) => {
+                    let (effect, body) = self.clause(handler);
+                    row.add(body);
+                    let mut rest = self.stmts(&stmts[i + 1..]);
+                    if let Some(effect) = effect {
+                        rest.handle(effect);
+                    }
+                    row.add(rest);
+                    return row;
+                }
+                StmtKind::Install(value) => {
+                    row.add(self.expr(value));
+                    let installed = self
+                        .handler_value(value)
+                        .and_then(|def| self.handlers.get(&Rc::as_ptr(&def)).cloned());
+                    let mut rest = self.stmts(&stmts[i + 1..]);
+                    if let Some(installed) = installed {
+                        for &effect in &installed.handled {
+                            rest.handle(effect);
+                        }
+                        row.add(installed.row);
+                    }
+                    row.add(rest);
+                    return row;
+                }
+                StmtKind::Defer { body, .. } => row.add(self.block(body).deferred(stmt.span)),
+                StmtKind::Expr { expr, .. } => row.add(self.expr(expr)),
+                StmtKind::Effect(_) | StmtKind::TypeDef { .. } | StmtKind::Import { .. } => {}
+            }
+        }
+        row
+    }
+
+    /// Check a handler clause, returning the effect it handles for the
+    /// rest of its block, and the row of its body. The body runs outside
+    /// the handler: what it performs goes to the handlers further out.
+    fn clause(&mut self, handler: &Handler) -> (Option<usize>, Row) {
+        let builtin = BUILTIN_EFFECTS.iter().find(|(n, _)| *n == handler.effect);
+        let effect = match (self.lookup(&handler.effect).cloned(), builtin) {
+            (Some(Binding::Effect(id)), _) => {
+                let effect = &self.effects[id];
+                self.resumes(handler, effect.resume, Some(effect.span));
+                Some(id)
+            }
+            (None, Some((_, kind))) => {
+                self.resumes(handler, *kind, None);
+                None
+            }
+            _ => {
+                self.diagnostics.push(
+                    Diagnostic::error(
+                        format!("`{}` is not an effect", handler.effect),
+                        handler.span,
+                    )
+                    .with_note(format!(
+                        "declare it with `effect {}(...)` before handling it",
+                        handler.effect
+                    )),
+                );
+                None
+            }
+        };
+        let mark = self.names.len();
+        for param in &handler.params {
+            self.bind(&param.pattern);
+        }
+        self.names.push(("resume".to_string(), Binding::Value));
+        let row = self.block(&handler.body);
+        self.names.truncate(mark);
+        // A shallow handler only handles the first perform; any later ones
+        // go past it.
+        (effect.filter(|_| !handler.shallow), row)
+    }
+
+    /// Check a `handler { ... }` value and record what installing it
+    /// handles.
+    fn handler(&mut self, def: &Rc<HandlerDef>) {
+        let mut installed = Installed::default();
+        for handler in &def.clauses {
+            let (effect, row) = self.clause(handler);
+            installed.handled.extend(effect);
+            installed.row.add(row);
+        }
+        if let Some(ret) = &def.ret {
+            let mark = self.names.len();
+            self.bind(&ret.param.pattern);
+            installed.row.add(self.block(&ret.body));
+            self.names.truncate(mark);
+        }
+        // The clauses run wherever the value is installed, so they can't
+        // be open in the parameters of the function that made it.
+        installed.row.open.clear();
+        self.handlers.insert(Rc::as_ptr(def), installed);
+    }
+
+    /// The `handler { ... }` that `value` evaluates to, if it is one, a
+    /// name bound to one, or a call to a function that returns one.
+    fn handler_value(&self, value: &Expr) -> Option<Rc<HandlerDef>> {
+        let func = match &value.kind {
+            ExprKind::Handler(def) => return Some(def.clone()),
+            ExprKind::Ident(name) => match self.lookup(name) {
+                Some(Binding::Handler(def)) => return Some(def.clone()),
+                _ => return None,
+            },
+            ExprKind::Call { func, .. } | ExprKind::Apply { func, .. } => func,
+            _ => return None,
+        };
+        let Target::Func(def) = self.target(func) else {
+            return None;
+        };
+        match &def.body.stmts.last()?.kind {
+            StmtKind::Expr { expr, semi: false } => match &expr.kind {
+                ExprKind::Handler(def) => Some(def.clone()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Infer the row of `def`'s body and record it.
+    fn function(&mut self, def: &Rc<FnDef>) {
+        let mark = self.names.len();
+        let depth = self.fns.len() + 1;
+        for (index, param) in def.params.iter().enumerate() {
+            match param.name() {
+                Some(name) => self
+                    .names
+                    .push((name.to_string(), Binding::Param { depth, index })),
+                None => self.bind(&param.pattern),
+            }
+        }
+        self.fns.push(def.name.clone());
+        let row = self.block(&def.body);
+        self.fns.pop();
+        self.names.truncate(mark);
+
+        let key = Rc::as_ptr(def);
+        if self.rows.get(&key).map(Row::key) != Some(row.key()) {
+            self.changed = true;
+        }
+        self.rows.insert(key, row);
+    }
+
+    /// The function `value` evaluates to, when that is known.
+    fn function_value(&self, value: &Expr) -> Option<Rc<FnDef>> {
+        match &value.kind {
+            ExprKind::Lambda(def) => Some(def.clone()),
+            ExprKind::Ident(name) => match self.lookup(name) {
+                Some(Binding::Func(def)) => Some(def.clone()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // ---------------------------------------------------------------------
+    // Expressions
+    // ---------------------------------------------------------------------
+
+    fn expr(&mut self, expr: &Expr) -> Row {
+        let mut row = Row::default();
+        match &expr.kind {
+            ExprKind::Int(_)
+            | ExprKind::Float(_)
+            | ExprKind::Str(_)
+            | ExprKind::Bool(_)
+            | ExprKind::Nil
+            | ExprKind::Ident(_)
+            | ExprKind::Subject
+            | ExprKind::Continue
+            | ExprKind::Error => {}
+            ExprKind::Interp(parts) => {
+                for part in parts {
+                    if let InterpPart::Expr(expr) = part {
+                        row.add(self.expr(expr));
+                    }
+                }
+            }
+            ExprKind::List(items) => row.add(self.items(items)),
+            ExprKind::Object(items) => {
+                for item in items {
+                    match item {
+                        ObjectItem::Field { key, value } => {
+                            if let Key::Computed(key) = key {
+                                row.add(self.expr(key));
+                            }
+                            row.add(self.expr(value));
+                        }
+                        ObjectItem::Spread(expr) => row.add(self.expr(expr)),
+                    }
+                }
+            }
+            ExprKind::Unary { expr, .. }
+            | ExprKind::As { expr, .. }
+            | ExprKind::Try(expr)
+            | ExprKind::Field { expr, .. }
+            | ExprKind::Ref { expr, .. }
+            | ExprKind::Stage { expr, .. } => row.add(self.expr(expr)),
+            ExprKind::Binary { lhs, rhs, .. } => {
+                row.add(self.expr(lhs));
+                row.add(self.expr(rhs));
+            }
+            ExprKind::Compare { first, rest } => {
+                row.add(self.expr(first));
+                for (_, expr) in rest {
+                    row.add(self.expr(expr));
+                }
+            }
+            ExprKind::Index { expr, index } => {
+                row.add(self.expr(expr));
+                row.add(self.expr(index));
+            }
+            ExprKind::Assign { target, value } => {
+                row.add(self.expr(target));
+                row.add(self.expr(value));
+            }
+            ExprKind::Call { func, args } => {
+                let args: Vec<&Expr> = args.iter().map(|arg| &*arg.value).collect();
+                match &func.kind {
+                    // Method-style: `file.close()`.
+                    ExprKind::Field {
+                        expr: receiver,
+                        name,
+                    } if self.callable(name) => {
+                        let target = self.target_named(name);
+                        let args: Vec<&Expr> = [&**receiver].into_iter().chain(args).collect();
+                        row.add(self.call(target, name, &args, expr.span));
+                    }
+                    _ => {
+                        let target = self.target(func);
+                        if !matches!(func.kind, ExprKind::Ident(_)) {
+                            row.add(self.expr(func));
+                        }
+                        row.add(self.call(target, &callee_name(func), &args, expr.span));
+                    }
+                }
+            }
+            ExprKind::Apply {
+                func,
+                subject,
+                args,
+            } => {
+                let target = self.target(func);
+                if !matches!(func.kind, ExprKind::Ident(_)) {
+                    row.add(self.expr(func));
+                }
+                let args: Vec<&Expr> = [&**subject]
+                    .into_iter()
+                    .chain(args.iter().flatten().map(|arg: &Arg| &*arg.value))
+                    .collect();
+                row.add(self.call(target, &callee_name(func), &args, expr.span));
+            }
+            ExprKind::Block(block)
+            | ExprKind::Loop(block)
+            | ExprKind::Spawn(block)
+            | ExprKind::Nursery(block) => row.add(self.block(block)),
+            // A function literal performs nothing until it is called.
+            ExprKind::Lambda(def) => self.function(def),
+            ExprKind::Handler(def) => self.handler(def),
+            ExprKind::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                row.add(self.expr(cond));
+                row.add(self.block(then));
+                if let Some(otherwise) = otherwise {
+                    row.add(self.expr(otherwise));
+                }
+            }
+            ExprKind::Match { subject, arms } => {
+                row.add(self.expr(subject));
+                for arm in arms {
+                    let mark = self.names.len();
+                    self.bind(&arm.pattern);
+                    if let Some(guard) = &arm.guard {
+                        row.add(self.expr(guard));
+                    }
+                    row.add(self.expr(&arm.body));
+                    self.names.truncate(mark);
+                }
+            }
+            ExprKind::While { cond, body } => {
+                row.add(self.expr(cond));
+                row.add(self.block(body));
+            }
+            ExprKind::Break(value) | ExprKind::Return(value) => {
+                if let Some(value) = value {
+                    row.add(self.expr(value));
+                }
+            }
+        }
+        row
+    }
+
+    fn items(&mut self, items: &[Item]) -> Row {
+        let mut row = Row::default();
+        for item in items {
+            row.add(self.expr(&item.expr));
+        }
+        row
+    }
+
+    // ---------------------------------------------------------------------
+    // Calls
+    // ---------------------------------------------------------------------
+
+    fn callable(&self, name: &str) -> bool {
+        !matches!(self.target_named(name), Target::Unknown)
+    }
+
+    fn target(&self, func: &Expr) -> Target {
+        match &func.kind {
+            ExprKind::Ident(name) => self.target_named(name),
+            ExprKind::Lambda(def) => Target::Func(def.clone()),
+            _ => Target::Unknown,
+        }
+    }
+
+    fn target_named(&self, name: &str) -> Target {
+        match self.lookup(name) {
+            Some(Binding::Effect(id)) => Target::Effect(*id),
+            Some(Binding::Func(def)) => Target::Func(def.clone()),
+            // A parameter of an enclosing function is not part of this
+            // function's row.
+            Some(Binding::Param { depth, index }) if *depth == self.fns.len() => {
+                Target::Param(*index)
+            }
+            Some(_) => Target::Unknown,
+            None => match CALLBACKS.iter().find(|(n, _)| *n == name) {
+                Some((_, index)) => Target::Callback(*index),
+                None => Target::Unknown,
+            },
+        }
+    }
+
+    /// The row of a call to `target`, named `name`, with `args`.
+    fn call(&mut self, target: Target, name: &str, args: &[&Expr], span: Span) -> Row {
+        let mut row = self.items_of(args);
+        match target {
+            Target::Effect(effect) => row.add(Row {
+                effects: vec![Performed {
+                    effect,
+                    chain: vec![Step {
+                        span,
+                        label: format!("`{}` is performed here", name),
+                    }],
+                    deferred: None,
+                }],
+                open: Vec::new(),
+            }),
+            Target::Func(def) => {
+                let step = Step {
+                    span,
+                    label: format!("`{}` is called here", name),
+                };
+                let callee = self
+                    .rows
+                    .get(&Rc::as_ptr(&def))
+                    .cloned()
+                    .unwrap_or_default();
+                let mut called = Row {
+                    effects: callee.effects,
+                    open: Vec::new(),
+                };
+                for open in callee.open {
+                    if let Some(arg) = args.get(open.param) {
+                        let mut latent = self.latent(arg);
+                        for &effect in &open.handled {
+                            latent.handle(effect);
+                        }
+                        for step in open.chain.iter().rev() {
+                            latent = latent.through(step);
+                        }
+                        called.add(latent);
+                    }
+                }
+                row.add(called.through(&step));
+            }
+            Target::Param(param) => row.add(Row {
+                effects: Vec::new(),
+                open: vec![Open {
+                    param,
+                    handled: Vec::new(),
+                    chain: vec![Step {
+                        span,
+                        label: format!("`{}` is called here", name),
+                    }],
+                }],
+            }),
+            Target::Callback(index) => {
+                if let Some(arg) = args.get(index) {
+                    let step = Step {
+                        span,
+                        label: format!("`{}` calls it here", name),
+                    };
+                    row.add(self.latent(arg).through(&step));
+                }
+            }
+            Target::Unknown => {}
+        }
+        row
+    }
+
+    fn items_of(&mut self, args: &[&Expr]) -> Row {
+        let mut row = Row::default();
+        for arg in args {
+            row.add(self.expr(arg));
+        }
+        row
+    }
+
+    /// What calling the function that `arg` evaluates to performs.
+    fn latent(&self, arg: &Expr) -> Row {
+        let def = match &arg.kind {
+            ExprKind::Lambda(def) => def.clone(),
+            ExprKind::Ident(name) => match self.target_named(name) {
+                Target::Func(def) => def,
+                Target::Effect(effect) => {
+                    return Row {
+                        effects: vec![Performed {
+                            effect,
+                            chain: vec![Step {
+                                span: arg.span,
+                                label: format!("`{}` is passed here", name),
+                            }],
+                            deferred: None,
+                        }],
+                        open: Vec::new(),
+                    };
+                }
+                Target::Param(param) => {
+                    return Row {
+                        effects: Vec::new(),
+                        open: vec![Open {
+                            param,
+                            handled: Vec::new(),
+                            chain: Vec::new(),
+                        }],
+                    };
+                }
+                Target::Callback(_) | Target::Unknown => return Row::default(),
+            },
+            _ => return Row::default(),
+        };
+        // Its own parameters are filled in wherever it is called; that
+        // is not known here.
+        let row = self
+            .rows
+            .get(&Rc::as_ptr(&def))
+            .cloned()
+            .unwrap_or_default();
+        Row {
+            effects: row.effects,
+            open: Vec::new(),
+        }
+    }
+
+    // ---------------------------------------------------------------------
+    // Reporting
+    // ---------------------------------------------------------------------
+
+    fn unhandled(&self, performed: &Performed) -> Diagnostic {
+        let effect = &self.effects[performed.effect];
+        let span = performed
+            .chain
+            .first()
+            .map_or(effect.span, |step| step.span);
+        let mut diagnostic = match performed.deferred {
+            Some(deferred) => {
+                Diagnostic::error(format!("unhandled effect in defer: {}", effect.name), span)
+                    .with_label(deferred, "the `defer` runs when its block exits")
+            }
+            None => Diagnostic::error(format!("unhandled effect: {}", effect.name), span),
+        };
+        for step in performed.chain.iter().skip(1) {
+            diagnostic = diagnostic.with_label(step.span, step.label.clone());
+        }
+        let note = match &effect.owner {
+            Some(owner) => format!(
+                "effect '{}' is not handled: it is declared inside {}, so only a handler there can handle it",
+                effect.name, owner
+            ),
+            None => format!(
+                "effect '{}' is not handled: add `with {}(...) {{ ... }}` earlier in an enclosing block",
+                effect.name, effect.name
+            ),
+        };
+        diagnostic = diagnostic.with_note(note);
+        diagnostic
+    }
+
+    fn signature(&self, def: &FnDef) -> String {
+        let params: Vec<&str> = def
+            .params
+            .iter()
+            .map(|param| param.name().unwrap_or("_"))
+            .collect();
+        let row = self
+            .rows
+            .get(&(def as *const FnDef))
+            .cloned()
+            .unwrap_or_default();
+        let effects: Vec<&str> = row
+            .effects
+            .iter()
+            .map(|p| self.effects[p.effect].name.as_str())
+            .collect();
+        let open: Vec<&str> = row.open.iter().map(|o| params[o.param]).collect();
+        let row = match (effects.is_empty(), open.is_empty()) {
+            (_, true) => effects.join(", "),
+            (true, false) => open.join(", "),
+            (false, false) => format!("{} | {}", effects.join(", "), open.join(", ")),
+        };
+        format!(
+            "fn {}({}) : <{}>",
+            def.name.as_deref().unwrap_or("fn"),
+            params.join(", "),
+            row
+        )
+    }
+}
+
+/// How a callee is named in messages.
+fn callee_name(func: &Expr) -> String {
+    match &func.kind {
+        ExprKind::Ident(name) => name.clone(),
+        _ => "the function".to_string(),
+    }
+}
@@ -0,0 +1,2007 @@
+//! Linearity checking
+//!
+//! Every value belongs to one of three tiers:
+//!
+//! - **linear** values, such as an open `File`, must be consumed exactly
+//!   once: closed, dropped, or moved somewhere that takes ownership;
+//! - **affine** values have a `drop`, so they may be left unconsumed, but
+//!   they still move at most once;
+//! - **copyable** values (numbers, strings, bools, functions) can be used
+//!   any number of times.
+//!
+//! Objects and lists take the highest tier of what they hold.
+//!
+//! The checker walks the program in evaluation order, tracking for each
+//! variable whether it is still owned, consumed, or consumed on only some
+//! of the paths that reach the current point. Branches are checked
+//! separately and joined; jumps out of a scope (`break`, `continue`,
+//! `return` and `?`) are checked where they happen, so a leak is reported
+//! at the path that causes it.
+//!
+//! A function body is checked once, with each parameter standing for
+//! whatever the caller passes. Errors that only hold if a parameter is
+//! linear or affine are reported at the calls that pass such a value.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::ast::{
+    Arg, Block, EffectDecl, Expr, ExprKind, FnDef, InterpPart, Item, Key, ObjectItem, Pattern,
+    PatternKind, Program, ResumeKind, Stage, StmtKind,
+};
+use crate::diagnostic::{Diagnostic, Span};
+
+use super::borrow::{Access, Held, Loan, Loans, Origin, Place};
+
+pub fn check(program: &Program) -> Vec<Diagnostic> {
+    let mut checker = Checker {
+        live: true,
+        ..Checker::default()
+    };
+    let value = checker.stmts(&program.body);
+    checker.end_scope(0);
+    // The program's final value is printed, not consumed.
+    if let Some(stmt) = program.body.stmts.last() {
+        checker.discard(&value, stmt.span);
+    }
+    checker.diagnostics
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Tier {
+    Copy,
+    Affine,
+    Linear,
+}
+
+/// What the checker knows about a value.
+#[derive(Clone)]
+enum Shape {
+    /// Nothing that needs tracking.
+    Copy,
+    /// An external resource such as a `File`.
+    Resource { ty: &'static str, tier: Tier },
+    /// An object holding owned values, by field.
+    Object(Vec<(String, Shape)>),
+    /// A list holding owned values, by position.
+    List(Vec<Shape>),
+    /// A reference, and the loans it may point into.
+    Ref(Vec<Origin>),
+    /// A function, so that calls to it can be followed, and the loans of
+    /// the variables it captures.
+    Func(Rc<FnDef>, Vec<Origin>),
+    /// An effect, so that performing it can be checked.
+    Effect(Rc<EffectDecl>),
+    /// A handle to the task spawned at this span, which must not outlive
+    /// the scope that owns the task.
+    Task(Span),
+    /// Whatever is passed to the parameter with this variable id. A body
+    /// treats its parameters as linear; what that costs is checked at
+    /// each call, against the value actually passed.
+    Param(usize),
+}
+
+impl Shape {
+    fn tier(&self) -> Tier {
+        match self {
+            Shape::Copy | Shape::Ref(_) | Shape::Func(..) | Shape::Effect(_) | Shape::Task(_) => {
+                Tier::Copy
+            }
+            Shape::Resource { tier, .. } => *tier,
+            Shape::Param(_) => Tier::Linear,
+            Shape::Object(fields) => fields
+                .iter()
+                .map(|(_, s)| s.tier())
+                .max()
+                .unwrap_or(Tier::Copy),
+            Shape::List(items) => items.iter().map(Shape::tier).max().unwrap_or(Tier::Copy),
+        }
+    }
+
+    /// The type that gives this value its tier, for messages.
+    fn ty(&self) -> &'static str {
+        match self {
+            Shape::Resource { ty, .. } => ty,
+            Shape::Object(fields) => fields
+                .iter()
+                .map(|(_, s)| s)
+                .max_by_key(|s| s.tier())
+                .map_or("object", Shape::ty),
+            Shape::List(items) => items
+                .iter()
+                .max_by_key(|s| s.tier())
+                .map_or("list", Shape::ty),
+            Shape::Copy
+            | Shape::Ref(_)
+            | Shape::Func(..)
+            | Shape::Effect(_)
+            | Shape::Task(_)
+            | Shape::Param(_) => "value",
+        }
+    }
+
+    /// Where a task whose handle is in this value was spawned.
+    fn spawned(&self) -> Option<Span> {
+        match self {
+            Shape::Task(span) => Some(*span),
+            Shape::Object(fields) => fields.iter().find_map(|(_, s)| s.spawned()),
+            Shape::List(items) => items.iter().find_map(Shape::spawned),
+            _ => None,
+        }
+    }
+
+    /// A value that may be either of two shapes, as after an `if`.
+    fn join(self, other: Shape) -> Shape {
+        match (self, other) {
+            (Shape::Ref(mut a), Shape::Ref(b)) => {
+                a.extend(b);
+                Shape::Ref(a)
+            }
+            (Shape::Copy, other) => other,
+            (this, other) if other.tier() > this.tier() => other,
+            (this, _) => this,
+        }
+    }
+
+    /// Every loan a reference in this value may point into.
+    fn origins(&self) -> Vec<&Origin> {
+        match self {
+            Shape::Ref(origins) | Shape::Func(_, origins) => origins.iter().collect(),
+            Shape::Object(fields) => fields.iter().flat_map(|(_, s)| s.origins()).collect(),
+            Shape::List(items) => items.iter().flat_map(Shape::origins).collect(),
+            Shape::Copy
+            | Shape::Resource { .. }
+            | Shape::Effect(_)
+            | Shape::Task(_)
+            | Shape::Param(_) => Vec::new(),
+        }
+    }
+
+    /// The ids of the parameters whose values this may hold.
+    fn params(&self) -> Vec<usize> {
+        match self {
+            Shape::Param(id) => vec![*id],
+            Shape::Object(fields) => fields.iter().flat_map(|(_, s)| s.params()).collect(),
+            Shape::List(items) => items.iter().flat_map(Shape::params).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Replace each parameter in this value with the shape `f` gives it.
+    fn map_params(self, f: &mut impl FnMut(usize) -> Shape) -> Shape {
+        match self {
+            Shape::Param(id) => f(id),
+            Shape::Object(fields) => Shape::Object(
+                fields
+                    .into_iter()
+                    .map(|(name, s)| (name, s.map_params(f)))
+                    .collect(),
+            ),
+            Shape::List(items) => Shape::list(items.into_iter().map(|s| s.map_params(f)).collect()),
+            shape => shape,
+        }
+    }
+
+    /// Rewrite the origins of every reference in this value.
+    fn map_origins(self, f: &mut impl FnMut(Origin) -> Option<Origin>) -> Shape {
+        match self {
+            Shape::Ref(origins) => Shape::Ref(origins.into_iter().filter_map(&mut *f).collect()),
+            Shape::Func(def, origins) => {
+                Shape::Func(def, origins.into_iter().filter_map(&mut *f).collect())
+            }
+            Shape::Object(fields) => Shape::Object(
+                fields
+                    .into_iter()
+                    .map(|(name, s)| (name, s.map_origins(f)))
+                    .collect(),
+            ),
+            Shape::List(items) => {
+                Shape::List(items.into_iter().map(|s| s.map_origins(f)).collect())
+            }
+            shape => shape,
+        }
+    }
+
+    /// The part of this value at field `path`.
+    fn field(&self, path: &[String]) -> Option<&Shape> {
+        let Some((first, rest)) = path.split_first() else {
+            return Some(self);
+        };
+        match self {
+            Shape::Object(fields) => fields.iter().find(|(name, _)| name == first)?.1.field(rest),
+            _ => None,
+        }
+    }
+
+    /// A list of values.
+    fn list(items: Vec<Shape>) -> Shape {
+        if items.iter().all(|s| matches!(s, Shape::Copy)) {
+            Shape::Copy
+        } else {
+            Shape::List(items)
+        }
+    }
+
+    /// Bundle several values together, as `push` and `Ok` do.
+    fn bundle(shapes: Vec<Shape>) -> Shape {
+        match shapes.len() {
+            0 => Shape::Copy,
+            1 => shapes.into_iter().next().unwrap_or(Shape::Copy),
+            _ if shapes.iter().all(|s| matches!(s, Shape::Copy)) => Shape::Copy,
+            _ => Shape::List(shapes),
+        }
+    }
+}
+
+/// How an expression's value is used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Read without taking ownership.
+    Use,
+    /// Moved into a binding, a collection or out of a scope.
+    Move,
+    /// Passed to a function, which takes ownership.
+    Consume,
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Owned,
+    Moved {
+        at: Span,
+        mode: Mode,
+    },
+    /// Moved on some paths but not others; `kept` is a branch that keeps
+    /// the value.
+    Maybe {
+        at: Span,
+        kept: Span,
+    },
+}
+
+type Fields = Vec<(Vec<String>, State)>;
+
+struct Var {
+    /// Unique for the whole program, unlike the variable's index.
+    id: usize,
+    name: String,
+    shape: Shape,
+    state: State,
+    /// The states of fields consumed apart from the whole value, by path.
+    fields: Fields,
+    span: Span,
+    /// The number of enclosing functions.
+    depth: usize,
+    /// Where a `defer` consumes the value when its scope exits.
+    deferred: Option<Span>,
+}
+
+impl Var {
+    /// The state of the field at `path`, when the whole value is owned.
+    fn field_state(&self, path: &[String]) -> State {
+        self.fields
+            .iter()
+            .find(|(field, _)| path.starts_with(field))
+            .map_or(State::Owned, |(_, state)| *state)
+    }
+
+    /// A part strictly inside `path` that has been consumed, and where.
+    fn consumed_part(&self, path: &[String]) -> Option<(&[String], Span)> {
+        self.fields.iter().find_map(|(field, state)| match state {
+            State::Moved { at, .. } | State::Maybe { at, .. }
+                if field.len() > path.len() && field.starts_with(path) =>
+            {
+                Some((field.as_slice(), *at))
+            }
+            _ => None,
+        })
+    }
+
+    /// The parts of this variable to check for leaks, with their names:
+    /// the whole value, or each field when some were consumed apart.
+    fn parts(&self) -> Vec<(String, Shape, State)> {
+        let mut parts = Vec::new();
+        match self.state {
+            State::Owned if !self.fields.is_empty() => {
+                self.collect_parts(&self.shape, &mut Vec::new(), &mut parts)
+            }
+            state => parts.push((self.name.clone(), self.shape.clone(), state)),
+        }
+        parts
+    }
+
+    fn collect_parts(
+        &self,
+        shape: &Shape,
+        path: &mut Vec<String>,
+        parts: &mut Vec<(String, Shape, State)>,
+    ) {
+        let state = self.field_state(path);
+        match shape {
+            Shape::Object(fields)
+                if matches!(state, State::Owned) && self.consumed_part(path).is_some() =>
+            {
+                for (name, shape) in fields {
+                    path.push(name.clone());
+                    self.collect_parts(shape, path, parts);
+                    path.pop();
+                }
+            }
+            _ => {
+                let name = std::iter::once(&self.name)
+                    .chain(path.iter())
+                    .map(String::as_str)
+                    .collect::<Vec<_>>()
+                    .join(".");
+                parts.push((name, shape.clone(), state));
+            }
+        }
+    }
+}
+
+struct Loop {
+    /// The first variable declared inside the loop.
+    start: usize,
+    value: Shape,
+    /// The variables from before the loop, as it was entered.
+    entry: Branch,
+    /// The variables at each way out of the loop.
+    exits: Vec<Branch>,
+}
+
+struct Function {
+    /// The first variable declared inside the function.
+    start: usize,
+    /// The function's body, whose end its variables do not outlive.
+    span: Span,
+    /// The id of each parameter bound to a plain name.
+    params: Vec<Option<usize>>,
+    /// The variables from enclosing functions that this one uses.
+    captures: Vec<(usize, String, Span)>,
+    /// The values passed to `return`.
+    returns: Shape,
+    /// The errors in the body that depend on what its parameters are
+    /// passed.
+    obligations: Vec<Obligation>,
+}
+
+/// An error in a function body that is only an error if one of `params`
+/// is passed a value of at least `tier`.
+#[derive(Clone)]
+struct Obligation {
+    params: Vec<usize>,
+    tier: Tier,
+    diagnostic: Diagnostic,
+}
+
+/// A snapshot of variable states at the end of one branch.
+struct Branch {
+    vars: Vec<(Shape, State, Fields)>,
+    live: bool,
+    span: Span,
+}
+
+/// The state of a variable after branches that leave it in `states`.
+fn merge(states: impl Iterator<Item = (State, Span)>) -> State {
+    let mut moved = None;
+    let mut kept = None;
+    let mut maybe = None;
+    for (state, span) in states {
+        match state {
+            State::Owned => kept = kept.or(Some(span)),
+            State::Moved { .. } => moved = moved.or(Some(state)),
+            State::Maybe { .. } => maybe = maybe.or(Some(state)),
+        }
+    }
+    match (maybe, moved, kept) {
+        (Some(state), _, _) => state,
+        (None, Some(State::Moved { at, .. }), Some(kept)) => State::Maybe { at, kept },
+        (None, Some(state), None) => state,
+        _ => State::Owned,
+    }
+}
+
+/// The variable and field path of `a.b.c`.
+fn field_path(expr: &Expr) -> Option<(&str, Vec<String>)> {
+    match &expr.kind {
+        ExprKind::Ident(name) => Some((name, Vec::new())),
+        ExprKind::Field { expr, name } => {
+            let (var, mut path) = field_path(expr)?;
+            path.push(name.clone());
+            Some((var, path))
+        }
+        _ => None,
+    }
+}
+
+/// How the checker sees a built-in function.
+struct Signature {
+    name: &'static str,
+    /// How the arguments are passed.
+    mode: Mode,
+    returns: Returns,
+}
+
+enum Returns {
+    Copy,
+    Resource(&'static str, Tier),
+    /// The arguments, moved into the result (`Ok(x)`, `push(list, x)`).
+    Args,
+    /// Never returns (`panic`).
+    Never,
+    /// Passes each element of the first argument to the second, returning
+    /// the results if `collect` (`each`, `drain`).
+    Elements {
+        collect: bool,
+    },
+}
+
+macro_rules! signatures {
+    ($($name:literal => $mode:ident, $returns:expr;)*) => {
+        static BUILTINS: &[Signature] = &[
+            $(Signature { name: $name, mode: Mode::$mode, returns: $returns },)*
+        ];
+    };
+}
+
+signatures! {
+    "open" => Use, Returns::Resource("File", Tier::Linear);
+    "open-resource" => Use, Returns::Resource("Resource", Tier::Linear);
+    "acquire-resource" => Use, Returns::Resource("Resource", Tier::Linear);
+    "create-resource" => Use, Returns::Resource("Resource", Tier::Linear);
+    "open-connection" => Use, Returns::Resource("Connection", Tier::Affine);
+    "close" => Consume, Returns::Copy;
+    "drop" => Consume, Returns::Copy;
+    "read-result" => Consume, Returns::Args;
+    "Ok" => Consume, Returns::Args;
+    "Err" => Consume, Returns::Args;
+    "debug" => Consume, Returns::Args;
+    "push" => Consume, Returns::Args;
+    "each" => Consume, Returns::Elements { collect: false };
+    "drain" => Consume, Returns::Elements { collect: true };
+    "panic" => Consume, Returns::Never;
+    "throw" => Consume, Returns::Never;
+    "read" => Use, Returns::Copy;
+    "inspect" => Use, Returns::Copy;
+    "print" => Use, Returns::Copy;
+    "len" => Use, Returns::Copy;
+    "pop" => Use, Returns::Copy;
+    "keys" => Use, Returns::Copy;
+    "values" => Use, Returns::Copy;
+    "has-key" => Use, Returns::Copy;
+    "join" => Use, Returns::Copy;
+    "range" => Use, Returns::Copy;
+    "to-string" => Use, Returns::Copy;
+    "to-int" => Use, Returns::Copy;
+    "to-float" => Use, Returns::Copy;
+    "typeof" => Use, Returns::Copy;
+    "symbol" => Use, Returns::Copy;
+    "assert" => Use, Returns::Copy;
+    "some-condition" => Use, Returns::Copy;
+}
+
+enum Callee {
+    Builtin(&'static Signature),
+    Func(Rc<FnDef>),
+    Effect(Rc<EffectDecl>),
+    Unknown,
+}
+
+#[derive(Default)]
+struct Checker {
+    vars: Vec<Var>,
+    loops: Vec<Loop>,
+    /// The first variable declared inside each enclosing `nursery`, within
+    /// the enclosing function.
+    nurseries: Vec<usize>,
+    fns: Vec<Function>,
+    /// Whether the current point can be reached.
+    live: bool,
+    /// How many `@comptime` expressions enclose the current point.
+    comptime: usize,
+    /// The number of loops and functions around the innermost `defer`
+    /// body being checked, which no jump may leave.
+    defer: Option<(usize, usize)>,
+    /// The result of each function checked so far.
+    results: HashMap<*const FnDef, Shape>,
+    /// The loans each function checked so far holds on its captures.
+    captures: HashMap<*const FnDef, Vec<Origin>>,
+    /// The parameter ids of each function checked so far, and the errors
+    /// in its body that depend on what they are passed.
+    obligations: HashMap<*const FnDef, (Vec<Option<usize>>, Vec<Obligation>)>,
+    loans: Loans,
+    /// Set while checking the operand of `&`, which borrows rather than
+    /// reads.
+    borrowing: bool,
+    next_id: usize,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Checker {
+    // ---------------------------------------------------------------------
+    // Scopes and variables
+    // ---------------------------------------------------------------------
+
+    fn declare(&mut self, name: &str, shape: Shape, span: Span) {
+        self.next_id += 1;
+        self.vars.push(Var {
+            id: self.next_id,
+            name: name.to_string(),
+            shape,
+            state: State::Owned,
+            fields: Vec::new(),
+            span,
+            depth: self.fns.len(),
+            deferred: None,
+        });
+    }
+
+    fn lookup(&self, name: &str) -> Option<usize> {
+        self.vars.iter().rposition(|var| var.name == name)
+    }
+
+    /// Close the scope whose first variable is `start`. Linear values
+    /// still owned at its end are leaks.
+    fn end_scope(&mut self, start: usize) {
+        if self.live {
+            for i in start..self.vars.len() {
+                self.check_consumed(i, None);
+            }
+        }
+        self.vars.truncate(start);
+    }
+
+    /// Report the parts of variable `i` that are linear and still owned,
+    /// either at the end of its scope or at a jump (`exit`) that leaves it.
+    fn check_consumed(&mut self, i: usize, exit: Option<(Span, &str)>) {
+        // The `defer` runs on every way out of the scope.
+        if self.vars[i].deferred.is_some() {
+            return;
+        }
+        let declared = self.vars[i].span;
+        for (name, shape, state) in self.vars[i].parts() {
+            self.check_part(&name, &shape, state, declared, exit);
+        }
+    }
+
+    fn check_part(
+        &mut self,
+        name: &str,
+        shape: &Shape,
+        state: State,
+        declared: Span,
+        exit: Option<(Span, &str)>,
+    ) {
+        if shape.tier() != Tier::Linear {
+            return;
+        }
+        let note = format!(
+            "values of type `{}` are linear: each must be consumed exactly once",
+            shape.ty()
+        );
+        let diagnostic = match (state, exit) {
+            (State::Moved { .. }, _) => return,
+            (State::Owned, None) => Diagnostic::error(
+                format!("linear value `{}` was never consumed", name),
+                declared,
+            ),
+            (State::Owned, Some((span, jump))) => Diagnostic::error(
+                format!("linear value `{}` not consumed before {}", name, jump),
+                span,
+            )
+            .with_label(declared, format!("`{}` is declared here", name)),
+            (State::Maybe { at, kept }, exit) => {
+                let (message, span) = match exit {
+                    None => (
+                        format!("linear value `{}` may not be consumed", name),
+                        declared,
+                    ),
+                    Some((span, jump)) => (
+                        format!(
+                            "linear value `{}` may not be consumed before {}",
+                            name, jump
+                        ),
+                        span,
+                    ),
+                };
+                Diagnostic::error(message, span)
+                    .with_label(kept, "linear value not consumed in branch")
+                    .with_label(at, "consumed here, in another branch")
+            }
+        };
+        self.report(diagnostic.with_note(note), shape, Tier::Linear);
+    }
+
+    /// Check the variables a jump leaves, from `start` on.
+    fn check_exit(&mut self, start: usize, span: Span, jump: &str) {
+        if self.live {
+            for i in start..self.vars.len() {
+                self.check_consumed(i, Some((span, jump)));
+            }
+        }
+    }
+
+    /// A value that nothing takes ownership of.
+    fn discard(&mut self, shape: &Shape, span: Span) {
+        if self.live && shape.tier() == Tier::Linear {
+            let diagnostic =
+                Diagnostic::error("linear value was never consumed", span).with_note(format!(
+                    "this produces a `{}`, which must be consumed exactly once",
+                    shape.ty()
+                ));
+            self.report(diagnostic, shape, Tier::Linear);
+        }
+    }
+
+    /// Report an error about a value of shape `shape`, which is an error
+    /// if the value is at least `tier`. Where that depends on what a
+    /// parameter is passed, it is left to each call of the function.
+    fn report(&mut self, diagnostic: Diagnostic, shape: &Shape, tier: Tier) {
+        let params = shape.params();
+        let known = shape.clone().map_params(&mut |_| Shape::Copy);
+        let owner = self.fns.iter_mut().rev().find(|function| {
+            function
+                .params
+                .iter()
+                .any(|param| param.is_some_and(|id| params.contains(&id)))
+        });
+        match owner {
+            Some(function) if known.tier() < tier => {
+                // The notes describe the value, which only the call knows.
+                function.obligations.push(Obligation {
+                    params,
+                    tier,
+                    diagnostic: Diagnostic {
+                        notes: Vec::new(),
+                        ..diagnostic
+                    },
+                });
+            }
+            _ => self.diagnostics.push(diagnostic),
+        }
+    }
+
+    /// Use variable `name`, or the field at `path` within it, at `span`.
+    /// Fields are borrowed: they can be read in place, but only
+    /// destructuring moves them out.
+    fn variable(&mut self, name: &str, path: &[String], span: Span, mode: Mode) -> Shape {
+        let Some(i) = self.lookup(name) else {
+            return Shape::Copy;
+        };
+        self.capture(i, span);
+        let shape = self.vars[i]
+            .shape
+            .field(path)
+            .cloned()
+            .unwrap_or(Shape::Copy);
+        let tier = shape.tier();
+        let written = std::iter::once(name)
+            .chain(path.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join(".");
+        if !self.borrowing {
+            let access = match mode {
+                _ if tier == Tier::Copy || !path.is_empty() => Access::Read,
+                _ if matches!(shape, Shape::Param(_)) => Access::Read,
+                Mode::Use => Access::Read,
+                Mode::Move => Access::Move,
+                Mode::Consume => Access::Consume,
+            };
+            let place = Place {
+                var: self.vars[i].id,
+                path: path.to_vec(),
+            };
+            self.access(&place, &written, access, span);
+        }
+        if tier == Tier::Copy {
+            return shape;
+        }
+        let var = &self.vars[i];
+        let kind = if tier == Tier::Linear {
+            "linear"
+        } else {
+            "affine"
+        };
+        let state = match var.state {
+            State::Owned => var.field_state(path),
+            state => state,
+        };
+        let error = if var.depth < self.fns.len() {
+            Some(
+                Diagnostic::error(format!("cannot capture {} value `{}`", kind, name), span)
+                    .with_label(var.span, format!("`{}` is declared outside the function", name))
+                    .with_note("a function can be called any number of times; pass the value in as an argument instead"),
+            )
+        } else if let Some(at) = var.deferred
+            && mode != Mode::Use
+        {
+            Some(
+                Diagnostic::error(
+                    format!("value `{}` is consumed by a `defer`", written),
+                    span,
+                )
+                .with_label(at, "consumed here, when the block exits")
+                .with_note("the block can use the value until then, but not move it"),
+            )
+        } else if let State::Owned = state
+            && let Some((part, at)) = var.consumed_part(path)
+        {
+            let error = Diagnostic::error(
+                format!("value `{}` has been partially consumed", written),
+                span,
+            )
+            .with_label(at, format!("`{}.{}` consumed here", name, part.join(".")));
+            if mode != Mode::Use && path.is_empty() {
+                self.vars[i].state = State::Moved { at: span, mode };
+            }
+            Some(error)
+        } else {
+            match state {
+                State::Moved {
+                    at,
+                    mode: Mode::Consume,
+                } => Some(
+                    Diagnostic::error(format!("value `{}` was already consumed", written), span)
+                        .with_label(at, "consumed here"),
+                ),
+                State::Moved { at, .. } => Some(
+                    Diagnostic::error(format!("value `{}` has been moved", written), span)
+                        .with_label(at, "value already moved here"),
+                ),
+                State::Maybe { at, kept } => Some(
+                    Diagnostic::error(
+                        format!(
+                            "`{}` is used here, but its value may have been moved",
+                            written
+                        ),
+                        span,
+                    )
+                    .with_label(at, "moved here")
+                    .with_label(kept, "but not in this branch"),
+                ),
+                State::Owned if mode != Mode::Use && !path.is_empty() => {
+                    let error = Diagnostic::error(
+                        format!("cannot move field out of {} value", kind),
+                        span,
+                    )
+                    .with_label(var.span, format!("`{}` is declared here", name))
+                    .with_note(format!(
+                        "fields are borrowed; destructure to take ownership: `let {{ {}, ... }} = {}`",
+                        path[0], name
+                    ));
+                    // Treat the field as consumed so its leak is not
+                    // reported as well.
+                    let var = &mut self.vars[i];
+                    var.fields.retain(|(field, _)| !field.starts_with(path));
+                    var.fields
+                        .push((path.to_vec(), State::Moved { at: span, mode }));
+                    Some(error)
+                }
+                State::Owned => {
+                    if mode != Mode::Use {
+                        self.vars[i].state = State::Moved { at: span, mode };
+                    }
+                    None
+                }
+            }
+        };
+        if let Some(error) = error
+            && self.live
+        {
+            self.report(error, &shape, Tier::Affine);
+        }
+        if path.is_empty() { shape } else { Shape::Copy }
+    }
+
+    /// Record that the functions inside variable `i`'s scope capture it.
+    /// Named functions are not captured: they are declared up front and
+    /// hold no state of their own.
+    fn capture(&mut self, i: usize, span: Span) {
+        let var = &self.vars[i];
+        if matches!(var.shape, Shape::Func(..)) {
+            return;
+        }
+        for function in &mut self.fns[var.depth..] {
+            if !function.captures.iter().any(|(id, ..)| *id == var.id) {
+                function.captures.push((var.id, var.name.clone(), span));
+            }
+        }
+    }
+
+    // ---------------------------------------------------------------------
+    // Borrows
+    // ---------------------------------------------------------------------
+
+    /// The place `expr` names, if it is a variable or a field path within
+    /// one, and how it is written.
+    fn place(&self, expr: &Expr) -> Option<(Place, String)> {
+        match &expr.kind {
+            ExprKind::Ident(name) => {
+                let i = self.lookup(name)?;
+                let place = Place {
+                    var: self.vars[i].id,
+                    path: Vec::new(),
+                };
+                Some((place, name.clone()))
+            }
+            ExprKind::Field { expr, name } => {
+                let (mut place, written) = self.place(expr)?;
+                place.path.push(name.clone());
+                Some((place, format!("{}.{}", written, name)))
+            }
+            // Any element may be borrowed, so the whole list is.
+            ExprKind::Index { expr, .. } => self.place(expr),
+            _ => None,
+        }
+    }
+
+    /// The loans held by the variables in scope.
+    fn held(&self) -> Vec<Held<'_>> {
+        let mut held = Vec::new();
+        for var in &self.vars {
+            if matches!(var.state, State::Moved { .. }) {
+                continue;
+            }
+            for origin in var.shape.origins() {
+                if let Origin::Loan(loan) = origin {
+                    held.push(Held {
+                        loan: *loan,
+                        holder: &var.name,
+                        span: var.span,
+                    });
+                }
+            }
+        }
+        held
+    }
+
+    /// Check an access to `place` against the loans held here.
+    fn access(&mut self, place: &Place, name: &str, access: Access, span: Span) {
+        if !self.live || self.loans.is_empty() {
+            return;
+        }
+        let error = self.loans.check(place, name, access, span, &self.held());
+        if let Some(error) = error {
+            self.diagnostics.push(error);
+        }
+    }
+
+    /// Report loans of the variables from `start` on, other than `keep`,
+    /// that are held past the end of `scope`: by its value, or by a
+    /// variable declared before it.
+    fn escapes(&mut self, start: usize, keep: &[Option<usize>], value: &Shape, scope: Span) {
+        if !self.live || self.loans.is_empty() {
+            return;
+        }
+        let dying: Vec<usize> = self.vars[start..]
+            .iter()
+            .map(|var| var.id)
+            .filter(|id| !keep.contains(&Some(*id)))
+            .collect();
+        let mut origins = value.origins();
+        for var in &self.vars[..start] {
+            origins.extend(var.shape.origins());
+        }
+        let mut escaped = Vec::new();
+        for origin in origins {
+            if let Origin::Loan(id) = *origin
+                && dying.contains(&self.loans.get(id).place.var)
+                && !escaped.contains(&id)
+            {
+                escaped.push(id);
+            }
+        }
+        for id in escaped {
+            self.diagnostics.push(self.loans.outlives(id, scope));
+        }
+    }
+
+    /// Give the loans a function's result holds on its parameters to the
+    /// places passed as those arguments.
+    fn instantiate(
+        &mut self,
+        shape: Shape,
+        places: &[Option<(Place, String)>],
+        span: Span,
+    ) -> Shape {
+        let loans = &mut self.loans;
+        shape.map_origins(&mut |origin| match origin {
+            Origin::Param(k) => {
+                let (place, name) = places.get(k)?.clone()?;
+                Some(Origin::Loan(loans.borrow(Loan {
+                    place,
+                    name,
+                    mutable: false,
+                    capture: false,
+                    span,
+                })))
+            }
+            origin => Some(origin),
+        })
+    }
+
+    /// Bind the variables of `pattern` to the parts of a value.
+    fn bind(&mut self, pattern: &Pattern, shape: Shape) {
+        match &pattern.kind {
+            PatternKind::Wildcard => self.discard(&shape, pattern.span),
+            PatternKind::Bind { name, .. } => self.declare(name, shape, pattern.span),
+            PatternKind::Literal(_) => {}
+            PatternKind::List { items, rest } => {
+                let mut parts = match shape {
+                    Shape::List(parts) => parts,
+                    _ => Vec::new(),
+                };
+                parts.resize(parts.len().max(items.len()), Shape::Copy);
+                let remaining = parts.split_off(items.len());
+                for (item, part) in items.iter().zip(parts) {
+                    self.bind(item, part);
+                }
+                match rest {
+                    Some(Some(name)) => self.declare(name, Shape::list(remaining), pattern.span),
+                    _ => self.discard(&Shape::list(remaining), pattern.span),
+                }
+            }
+            PatternKind::Object { fields, rest } => {
+                let mut parts = match shape {
+                    Shape::Object(parts) => parts,
+                    _ => Vec::new(),
+                };
+                for (name, pattern) in fields {
+                    let part = match parts.iter().position(|(field, _)| field == name) {
+                        Some(i) => parts.remove(i).1,
+                        None => Shape::Copy,
+                    };
+                    self.bind(pattern, part);
+                }
+                match rest {
+                    Some(Some(name)) => self.declare(name, Shape::Object(parts), pattern.span),
+                    _ => {
+                        for (name, part) in parts {
+                            if self.live && part.tier() == Tier::Linear {
+                                let diagnostic = Diagnostic::error(
+                                    format!("linear field `{}` was never consumed", name),
+                                    pattern.span,
+                                )
+                                .with_note(format!(
+                                    "values of type `{}` are linear: bind the field, or keep it with `...rest`",
+                                    part.ty()
+                                ));
+                                self.report(diagnostic, &part, Tier::Linear);
+                            }
+                        }
+                    }
+                }
+            }
+            // Every alternative binds the same names.
+            PatternKind::Or(alternatives) => {
+                if let Some(first) = alternatives.first() {
+                    self.bind(first, shape);
+                }
+            }
+            PatternKind::Tagged { inner, .. } => self.bind(inner, shape),
+        }
+    }
+
+    fn snapshot(&self, len: usize, span: Span) -> Branch {
+        Branch {
+            vars: self.vars[..len]
+                .iter()
+                .map(|var| (var.shape.clone(), var.state, var.fields.clone()))
+                .collect(),
+            live: self.live,
+            span,
+        }
+    }
+
+    fn restore(&mut self, branch: &Branch) {
+        self.vars.truncate(branch.vars.len());
+        for (var, (shape, state, fields)) in self.vars.iter_mut().zip(&branch.vars) {
+            var.shape = shape.clone();
+            var.state = *state;
+            var.fields = fields.clone();
+        }
+        self.live = branch.live;
+    }
+
+    /// Join the branches that reach the point after them.
+    fn join(&mut self, branches: Vec<Branch>) {
+        let live: Vec<&Branch> = branches.iter().filter(|b| b.live).collect();
+        let Some(first) = live.first() else {
+            if let Some(first) = branches.first() {
+                self.restore(first);
+            }
+            self.live = false;
+            return;
+        };
+        self.restore(first);
+        for (i, var) in self.vars.iter_mut().enumerate() {
+            var.state = merge(live.iter().map(|branch| (branch.vars[i].1, branch.span)));
+            let mut paths: Vec<&Vec<String>> = Vec::new();
+            for branch in &live {
+                let (shape, _, fields) = &branch.vars[i];
+                var.shape = var.shape.clone().join(shape.clone());
+                for (path, _) in fields {
+                    if !paths.contains(&path) {
+                        paths.push(path);
+                    }
+                }
+            }
+            var.fields = paths
+                .into_iter()
+                .map(|path| {
+                    let state = merge(live.iter().map(|branch| {
+                        let fields = &branch.vars[i].2;
+                        let state = fields.iter().find(|(field, _)| field == path);
+                        (state.map_or(State::Owned, |(_, state)| *state), branch.span)
+                    }));
+                    (path.clone(), state)
+                })
+                .filter(|(_, state)| !matches!(state, State::Owned))
+                .collect();
+        }
+        self.live = true;
+    }
+
+    // ---------------------------------------------------------------------
+    // Blocks and functions
+    // ---------------------------------------------------------------------
+
+    /// Check a block, returning the shape of its value.
+    fn block(&mut self, block: &Block) -> Shape {
+        let start = self.vars.len();
+        let value = self.stmts(block);
+        self.escapes(start, &[], &value, block.span);
+        self.end_scope(start);
+        value
+    }
+
+    /// Check the statements of a block, leaving its variables in scope.
+    fn stmts(&mut self, block: &Block) -> Shape {
+        for stmt in &block.stmts {
+            match &stmt.kind {
+                StmtKind::Fn(def) => {
+                    if let Some(name) = &def.name {
+                        self.declare(name, Shape::Func(def.clone(), Vec::new()), stmt.span);
+                    }
+                }
+                StmtKind::Effect(decl) => {
+                    self.declare(&decl.name, Shape::Effect(decl.clone()), stmt.span);
+                }
+                _ => {}
+            }
+        }
+        let mut value = Shape::Copy;
+        for (i, stmt) in block.stmts.iter().enumerate() {
+            value = Shape::Copy;
+            match &stmt.kind {
+                StmtKind::Let { pattern, value, .. } => {
+                    let shape = self.expr(value, Mode::Move);
+                    self.bind(pattern, shape);
+                }
+                StmtKind::Fn(def) => {
+                    self.function(def);
+                    if let Some(name) = &def.name
+                        && let Some(i) = self.lookup(name)
+                    {
+                        let captures = self.captures.get(&Rc::as_ptr(def)).cloned();
+                        self.vars[i].shape = Shape::Func(def.clone(), captures.unwrap_or_default());
+                    }
+                }
+                StmtKind::Install(expr) => {
+                    self.expr(expr, Mode::Use);
+                }
+                StmtKind::Expr { expr, semi } => {
+                    // A `nursery` that is a statement of its own, rather
+                    // than a value bound or passed on, lets nothing escape.
+                    let shape = match &expr.kind {
+                        ExprKind::Nursery(body) => self.nursery(body),
+                        _ => self.expr(expr, Mode::Move),
+                    };
+                    if i + 1 == block.stmts.len() && !semi {
+                        value = shape;
+                    } else {
+                        self.discard(&shape, expr.span);
+                    }
+                }
+                StmtKind::Defer { body, .. } => self.defer(body),
+                StmtKind::Effect(_)
+                | StmtKind::With(_)
+                | StmtKind::TypeDef { .. }
+                | StmtKind::Import { .. } => {}
+            }
+        }
+        value
+    }
+
+    /// Check a `defer` body. It runs when its block exits, however it
+    /// exits, so what it consumes counts as consumed on every path out;
+    /// until then the block may still use it.
+    fn defer(&mut self, body: &Block) {
+        let before = self.snapshot(self.vars.len(), body.span);
+        let outer = self.defer.replace((self.loops.len(), self.fns.len()));
+        self.block(body);
+        self.defer = outer;
+        let consumed: Vec<(usize, Span)> = self.vars[..before.vars.len()]
+            .iter()
+            .zip(&before.vars)
+            .enumerate()
+            .filter_map(|(i, (var, (_, state, _)))| match (var.state, state) {
+                (State::Moved { at, .. }, State::Owned) => Some((i, at)),
+                _ => None,
+            })
+            .collect();
+        self.restore(&before);
+        for (i, at) in consumed {
+            self.vars[i].deferred = Some(at);
+        }
+    }
+
+    /// Report a jump out of the `defer` body being checked.
+    fn defer_jump(&mut self, jump: &str, span: Span) {
+        let Some(defer) = self.defer else {
+            return;
+        };
+        if defer.1 == self.fns.len() && (jump == "return" || defer.0 == self.loops.len()) {
+            self.diagnostics.push(
+                Diagnostic::error(format!("cannot {} from defer block", jump), span)
+                    .with_note("a `defer` runs while its block exits, so it cannot jump elsewhere"),
+            );
+        }
+    }
+
+    /// Check a function body once, returning the shape of its result.
+    fn function(&mut self, def: &Rc<FnDef>) -> Shape {
+        let key = Rc::as_ptr(def);
+        if let Some(shape) = self.results.get(&key) {
+            return shape.clone();
+        }
+        // A recursive call sees the function's other results.
+        self.results.insert(key, Shape::Copy);
+        let live = std::mem::replace(&mut self.live, true);
+        let loops = std::mem::take(&mut self.loops);
+        let nurseries = std::mem::take(&mut self.nurseries);
+        let start = self.vars.len();
+        self.fns.push(Function {
+            start,
+            span: def.body.span,
+            params: Vec::new(),
+            captures: Vec::new(),
+            returns: Shape::Copy,
+            obligations: Vec::new(),
+        });
+        for param in &def.params {
+            if let Some(default) = &param.default {
+                self.expr(default, Mode::Use);
+            }
+            self.bind(&param.pattern, Shape::Copy);
+            let id = match param.pattern.kind {
+                PatternKind::Bind { .. } => self.vars.last_mut().map(|var| {
+                    var.shape = Shape::Param(var.id);
+                    var.id
+                }),
+                _ => None,
+            };
+            if let Some(function) = self.fns.last_mut() {
+                function.params.push(id);
+            }
+        }
+        let value = self.block(&def.body);
+        self.end_scope(start);
+        let function = self.fns.pop().expect("function was pushed");
+        self.loops = loops;
+        self.nurseries = nurseries;
+        self.live = live;
+        // Loans on the parameters become the caller's loans on its
+        // arguments. A function that captures a parameter is fine to
+        // return: the parameter lives on in it.
+        let loans = &self.loans;
+        let params = &function.params;
+        let result = value
+            .join(function.returns)
+            .map_origins(&mut |origin| match origin {
+                Origin::Loan(id) => {
+                    let loan = loans.get(id);
+                    match params.iter().position(|p| *p == Some(loan.place.var)) {
+                        Some(_) if loan.capture => None,
+                        Some(k) => Some(Origin::Param(k)),
+                        None => Some(origin),
+                    }
+                }
+                origin => Some(origin),
+            });
+        let captures = function
+            .captures
+            .into_iter()
+            .map(|(var, name, span)| {
+                Origin::Loan(self.loans.borrow(Loan {
+                    place: Place {
+                        var,
+                        path: Vec::new(),
+                    },
+                    name,
+                    mutable: false,
+                    capture: true,
+                    span,
+                }))
+            })
+            .collect();
+        self.captures.insert(key, captures);
+        self.obligations
+            .insert(key, (function.params, function.obligations));
+        let why = "the task would keep running after its function returned";
+        self.outlives(&result, tail(&def.body), why);
+        self.results.insert(key, result.clone());
+        result
+    }
+
+    // ---------------------------------------------------------------------
+    // Loops
+    // ---------------------------------------------------------------------
+
+    fn enter_loop(&mut self, span: Span) {
+        let start = self.vars.len();
+        self.loops.push(Loop {
+            start,
+            value: Shape::Copy,
+            entry: self.snapshot(start, span),
+            exits: Vec::new(),
+        });
+    }
+
+    /// Record the current point as a way out of the innermost loop.
+    fn loop_exit(&mut self, span: Span) {
+        if !self.live {
+            return;
+        }
+        let Some(start) = self.loops.last().map(|lp| lp.start) else {
+            return;
+        };
+        let exit = self.snapshot(start, span);
+        if let Some(lp) = self.loops.last_mut() {
+            lp.exits.push(exit);
+        }
+    }
+
+    /// Leave the innermost loop, joining the ways out of it, and return
+    /// the value it breaks with.
+    fn leave_loop(&mut self) -> Shape {
+        let lp = self.loops.pop().expect("loop was pushed");
+        if lp.exits.is_empty() {
+            self.restore(&lp.entry);
+            self.live = false;
+        } else {
+            self.join(lp.exits);
+        }
+        lp.value
+    }
+
+    /// The loop goes round again from here. A value from outside it that
+    /// was owned when the loop was entered, but has been consumed on the
+    /// way here, would be consumed again the next time round. Consuming
+    /// it on a path that leaves the loop is fine.
+    fn back_edge(&mut self) {
+        if !self.live {
+            return;
+        }
+        let Some(lp) = self.loops.last() else {
+            return;
+        };
+        let mut errors = Vec::new();
+        for (var, (shape, entry, fields)) in self.vars.iter().zip(&lp.entry.vars) {
+            let tier = var.shape.tier();
+            if tier == Tier::Copy || !matches!(entry, State::Owned) {
+                continue;
+            }
+            let consumed = std::iter::once(var.state).chain(
+                var.fields
+                    .iter()
+                    .filter(|(path, _)| !fields.iter().any(|(field, _)| field == path))
+                    .map(|(_, state)| *state),
+            );
+            let at = consumed.into_iter().find_map(|state| match state {
+                State::Moved { at, .. } | State::Maybe { at, .. } => Some(at),
+                State::Owned => None,
+            });
+            let Some(at) = at else {
+                continue;
+            };
+            let kind = if tier == Tier::Linear {
+                "linear"
+            } else {
+                "affine"
+            };
+            errors.push((
+                Diagnostic::error(format!("cannot consume {} value in loop", kind), at)
+                    .with_label(
+                        var.span,
+                        format!("`{}` is declared outside the loop", var.name),
+                    )
+                    .with_note(
+                        "cannot move in loop: the loop goes round again after this, and would consume it a second time; `break` after consuming it",
+                    ),
+                shape.clone(),
+            ));
+        }
+        for (error, shape) in errors {
+            self.report(error, &shape, Tier::Affine);
+        }
+    }
+
+    /// Check the body of a `nursery`, returning the shape of its value.
+    fn nursery(&mut self, block: &Block) -> Shape {
+        self.nurseries.push(self.vars.len());
+        let shape = self.block(block);
+        self.nurseries.pop();
+        shape
+    }
+
+    /// Report a task handle in `value`, which leaves its scope at `span`.
+    fn outlives(&mut self, value: &Shape, span: Span, why: &str) {
+        if let Some(spawned) = value.spawned() {
+            let mut diagnostic = Diagnostic::error("task cannot outlive its parent scope", span);
+            if spawned != span {
+                diagnostic = diagnostic.with_label(spawned, "the task is spawned here");
+            }
+            self.diagnostics.push(diagnostic.with_note(format!(
+                "{}; `await` it before the end of the scope, or spawn it where it is awaited",
+                why
+            )));
+        }
+    }
+
+    // ---------------------------------------------------------------------
+    // Expressions
+    // ---------------------------------------------------------------------
+
+    fn expr(&mut self, expr: &Expr, mode: Mode) -> Shape {
+        let shape = self.eval(expr, mode);
+        let place = matches!(
+            expr.kind,
+            ExprKind::Ident(_) | ExprKind::Field { .. } | ExprKind::Index { .. }
+        );
+        if mode == Mode::Use && !place {
+            self.discard(&shape, expr.span);
+        }
+        shape
+    }
+
+    fn eval(&mut self, expr: &Expr, mode: Mode) -> Shape {
+        let span = expr.span;
+        match &expr.kind {
+            ExprKind::Int(_)
+            | ExprKind::Float(_)
+            | ExprKind::Str(_)
+            | ExprKind::Bool(_)
+            | ExprKind::Nil
+            | ExprKind::Subject
+            | ExprKind::Handler(_)
+            | ExprKind::Error => Shape::Copy,
+            ExprKind::Ident(name) => self.variable(name, &[], span, mode),
+            ExprKind::Interp(parts) => {
+                for part in parts {
+                    if let InterpPart::Expr(expr) = part {
+                        self.expr(expr, Mode::Use);
+                    }
+                }
+                Shape::Copy
+            }
+            ExprKind::List(items) => self.list(items),
+            ExprKind::Object(items) => self.object(items),
+            ExprKind::Unary { expr, .. } => {
+                self.expr(expr, Mode::Use);
+                Shape::Copy
+            }
+            ExprKind::Binary { lhs, rhs, .. } => {
+                self.expr(lhs, Mode::Use);
+                self.expr(rhs, Mode::Use);
+                Shape::Copy
+            }
+            ExprKind::Compare { first, rest } => {
+                self.expr(first, Mode::Use);
+                for (_, expr) in rest {
+                    self.expr(expr, Mode::Use);
+                }
+                Shape::Copy
+            }
+            ExprKind::Call { func, args } => match &func.kind {
+                // Method-style: `file.close()`.
+                ExprKind::Field {
+                    expr: receiver,
+                    name,
+                } if !self.has_field(receiver, name) => {
+                    let callee = self.callee_named(name);
+                    self.call(callee, Some(receiver), args, span)
+                }
+                _ => {
+                    let callee = self.callee(func);
+                    self.callee_expr(func);
+                    self.call(callee, None, args, span)
+                }
+            },
+            ExprKind::Apply {
+                func,
+                subject,
+                args,
+            } => {
+                let callee = self.callee(func);
+                let mode = self.mode(&callee);
+                let mut shapes = vec![self.expr(subject, mode)];
+                self.callee_expr(func);
+                let mut exprs = vec![&**subject];
+                for arg in args.iter().flatten() {
+                    exprs.push(&arg.value);
+                    shapes.push(self.expr(&arg.value, mode));
+                }
+                self.result(callee, shapes, &exprs, span)
+            }
+            ExprKind::As { expr, name } => {
+                let shape = self.expr(expr, Mode::Move);
+                self.declare(name, shape, span);
+                Shape::Copy
+            }
+            ExprKind::Try(inner) => {
+                let shape = self.expr(inner, mode);
+                if let Some(start) = self.fns.last().map(|f| f.start) {
+                    self.check_exit(start, span, "`?` returns early");
+                }
+                shape
+            }
+            ExprKind::Field { expr: inner, .. } => match field_path(expr) {
+                Some((name, path)) => self.variable(name, &path, span, mode),
+                None => {
+                    self.expr(inner, Mode::Use);
+                    Shape::Copy
+                }
+            },
+            ExprKind::Ref { mutable, expr } => {
+                let borrowing = std::mem::replace(&mut self.borrowing, true);
+                self.expr(expr, Mode::Use);
+                self.borrowing = borrowing;
+                let Some((place, name)) = self.place(expr) else {
+                    return Shape::Copy;
+                };
+                let access = if *mutable {
+                    Access::BorrowMut
+                } else {
+                    Access::Borrow
+                };
+                self.access(&place, &name, access, span);
+                let loan = self.loans.borrow(Loan {
+                    place,
+                    name,
+                    mutable: *mutable,
+                    capture: false,
+                    span,
+                });
+                Shape::Ref(vec![Origin::Loan(loan)])
+            }
+            ExprKind::Index { expr: list, index } => {
+                let shape = self.expr(list, Mode::Use);
+                self.expr(index, Mode::Use);
+                if mode != Mode::Use && shape.tier() != Tier::Copy {
+                    self.element_moved(list, &shape, span);
+                }
+                Shape::Copy
+            }
+            ExprKind::Assign { target, value } => {
+                let shape = self.expr(value, Mode::Move);
+                self.assign(target, shape);
+                Shape::Copy
+            }
+            ExprKind::Block(block) => self.block(block),
+            ExprKind::Nursery(block) => {
+                let shape = self.nursery(block);
+                if self.live && shape.tier() == Tier::Linear {
+                    let diagnostic =
+                        Diagnostic::error("linear value cannot escape nursery", tail(block))
+                            .with_note(format!(
+                                "values of type `{}` are linear, and tasks spawned in the nursery may still be using it; consume it inside the nursery",
+                                shape.ty()
+                            ));
+                    self.report(diagnostic, &shape, Tier::Linear);
+                }
+                let why = "a `nursery` waits for the tasks spawned in it";
+                self.outlives(&shape, tail(block), why);
+                shape
+            }
+            ExprKind::Spawn(block) => {
+                let shape = self.block(block);
+                self.discard(&shape, span);
+                Shape::Task(span)
+            }
+            ExprKind::Lambda(def) => {
+                self.function(def);
+                let captures = self.captures.get(&Rc::as_ptr(def)).cloned();
+                Shape::Func(def.clone(), captures.unwrap_or_default())
+            }
+            ExprKind::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                self.expr(cond, Mode::Use);
+                let before = self.snapshot(self.vars.len(), span);
+                let mut value = self.block(then);
+                let after = self.snapshot(before.vars.len(), then.span);
+                self.restore(&before);
+                let span = match otherwise {
+                    Some(otherwise) => {
+                        value = value.join(self.expr(otherwise, Mode::Move));
+                        otherwise.span
+                    }
+                    None => span,
+                };
+                let other = self.snapshot(before.vars.len(), span);
+                self.join(vec![after, other]);
+                value
+            }
+            ExprKind::Match { subject, arms } => {
+                let subject = self.expr(subject, Mode::Move);
+                let before = self.snapshot(self.vars.len(), span);
+                let mut value = Shape::Copy;
+                let mut branches = Vec::new();
+                for arm in arms {
+                    self.restore(&before);
+                    let start = self.vars.len();
+                    self.bind(&arm.pattern, subject.clone());
+                    if let Some(guard) = &arm.guard {
+                        self.expr(guard, Mode::Use);
+                    }
+                    value = value.join(self.expr(&arm.body, Mode::Move));
+                    self.end_scope(start);
+                    branches.push(self.snapshot(start, arm.span));
+                }
+                if branches.is_empty() {
+                    branches.push(before);
+                }
+                self.join(branches);
+                value
+            }
+            ExprKind::Loop(body) => {
+                self.enter_loop(span);
+                let shape = self.block(body);
+                self.discard(&shape, body.span);
+                self.back_edge();
+                // Only a `break` leaves a `loop`.
+                self.leave_loop()
+            }
+            ExprKind::While { cond, body } => {
+                self.enter_loop(span);
+                self.expr(cond, Mode::Use);
+                // The condition can end the loop before the body runs.
+                self.loop_exit(span);
+                let shape = self.block(body);
+                self.discard(&shape, body.span);
+                self.back_edge();
+                self.loop_exit(body.span);
+                self.leave_loop();
+                Shape::Copy
+            }
+            ExprKind::Break(value) => {
+                let shape = match value {
+                    Some(value) => self.expr(value, Mode::Move),
+                    None => Shape::Copy,
+                };
+                self.defer_jump("break", span);
+                if let Some(start) = self.loops.last().map(|lp| lp.start) {
+                    self.check_exit(start, span, "break");
+                    self.loop_exit(span);
+                    if let Some(lp) = self.loops.last_mut() {
+                        lp.value = std::mem::replace(&mut lp.value, Shape::Copy).join(shape);
+                    }
+                }
+                self.live = false;
+                Shape::Copy
+            }
+            ExprKind::Continue => {
+                self.defer_jump("continue", span);
+                if let Some(start) = self.loops.last().map(|lp| lp.start) {
+                    self.check_exit(start, span, "continue");
+                    self.back_edge();
+                }
+                self.live = false;
+                Shape::Copy
+            }
+            ExprKind::Return(value) => {
+                let shape = match value {
+                    Some(value) => self.expr(value, Mode::Move),
+                    None => Shape::Copy,
+                };
+                self.defer_jump("return", span);
+                if let Some(function) = self.fns.last() {
+                    let (start, scope, params) =
+                        (function.start, function.span, function.params.clone());
+                    self.escapes(start, &params, &shape, scope);
+                    self.check_exit(start, span, "return");
+                }
+                if let Some(function) = self.fns.last_mut() {
+                    function.returns =
+                        std::mem::replace(&mut function.returns, Shape::Copy).join(shape);
+                }
+                self.live = false;
+                Shape::Copy
+            }
+            ExprKind::Stage { stage, expr } => {
+                let comptime = *stage == Stage::Comptime;
+                self.comptime += usize::from(comptime);
+                let shape = self.expr(expr, mode);
+                self.comptime -= usize::from(comptime);
+                shape
+            }
+        }
+    }
+
+    fn list(&mut self, items: &[Item]) -> Shape {
+        let mut shapes = Vec::new();
+        for item in items {
+            match self.expr(&item.expr, Mode::Move) {
+                Shape::List(spread) if item.spread => shapes.extend(spread),
+                shape => shapes.push(shape),
+            }
+        }
+        Shape::list(shapes)
+    }
+
+    fn object(&mut self, items: &[ObjectItem]) -> Shape {
+        let mut fields = Vec::new();
+        for item in items {
+            match item {
+                ObjectItem::Field { key, value } => {
+                    let name = match key {
+                        Key::Name(name) => name.clone(),
+                        Key::Computed(key) => {
+                            self.expr(key, Mode::Use);
+                            format!("[{}]", fields.len())
+                        }
+                    };
+                    let shape = self.expr(value, Mode::Move);
+                    fields.retain(|(field, _)| field != &name);
+                    fields.push((name, shape));
+                }
+                ObjectItem::Spread(expr) => match self.expr(expr, Mode::Move) {
+                    Shape::Object(spread) => {
+                        for (name, shape) in spread {
+                            fields.retain(|(field, _)| field != &name);
+                            fields.push((name, shape));
+                        }
+                    }
+                    shape => fields.push((format!("...{}", fields.len()), shape)),
+                },
+            }
+        }
+        if fields.iter().all(|(_, s)| matches!(s, Shape::Copy)) {
+            Shape::Copy
+        } else {
+            Shape::Object(fields)
+        }
+    }
+
+    /// An element moved out of a list by indexing, which only borrows.
+    fn element_moved(&mut self, list: &Expr, shape: &Shape, span: Span) {
+        let kind = if shape.tier() == Tier::Linear {
+            "linear"
+        } else {
+            "affine"
+        };
+        let name = match &list.kind {
+            ExprKind::Ident(name) => name.as_str(),
+            _ => "list",
+        };
+        if self.live {
+            let diagnostic =
+                Diagnostic::error(format!("cannot move element out of {} list", kind), span)
+                    .with_note(format!(
+                        "indexing only borrows; take the elements with `let [first, ...rest] = {}`, or consume them all with `{} | each(close)`",
+                        name, name
+                    ));
+            self.report(diagnostic, shape, Tier::Affine);
+        }
+    }
+
+    /// `target = value`: the target owns the new value.
+    fn assign(&mut self, target: &Expr, shape: Shape) {
+        if let Some((place, name)) = self.place(target) {
+            self.access(&place, &name, Access::Write, target.span);
+        }
+        if let Some((name, path)) = field_path(target)
+            && let Some(i) = self.lookup(name)
+        {
+            self.vars[i]
+                .fields
+                .retain(|(field, _)| !field.starts_with(&path));
+        }
+        let ExprKind::Ident(name) = &target.kind else {
+            let borrowing = std::mem::replace(&mut self.borrowing, true);
+            self.expr(target, Mode::Use);
+            self.borrowing = borrowing;
+            return;
+        };
+        let Some(i) = self.lookup(name) else {
+            return;
+        };
+        // A variable from outside the innermost `nursery` outlives it, and
+        // so would a task handle assigned to it.
+        if self.nurseries.last().is_some_and(|start| i < *start) && self.live {
+            let why = "a `nursery` waits for the tasks spawned in it";
+            self.outlives(&shape, target.span, why);
+        }
+        let var = &self.vars[i];
+        // Assigning to a mutable reference writes through it.
+        if let Shape::Ref(origins) = &var.shape
+            && origins
+                .iter()
+                .any(|origin| matches!(origin, Origin::Loan(id) if self.loans.get(*id).mutable))
+        {
+            return;
+        }
+        if var.shape.tier() == Tier::Linear
+            && matches!(var.state, State::Owned)
+            && var.depth == self.fns.len()
+            && self.live
+        {
+            let diagnostic = Diagnostic::error(
+                format!(
+                    "linear value `{}` is overwritten before it was consumed",
+                    name
+                ),
+                target.span,
+            )
+            .with_label(var.span, format!("`{}` is declared here", name));
+            let overwritten = var.shape.clone();
+            self.report(diagnostic, &overwritten, Tier::Linear);
+        }
+        let var = &mut self.vars[i];
+        var.shape = shape;
+        var.state = State::Owned;
+    }
+
+    // ---------------------------------------------------------------------
+    // Calls
+    // ---------------------------------------------------------------------
+
+    fn callee(&self, func: &Expr) -> Callee {
+        match &func.kind {
+            ExprKind::Ident(name) => self.callee_named(name),
+            ExprKind::Lambda(def) => Callee::Func(def.clone()),
+            _ => Callee::Unknown,
+        }
+    }
+
+    fn callee_named(&self, name: &str) -> Callee {
+        match self.lookup(name) {
+            Some(i) => match &self.vars[i].shape {
+                Shape::Func(def, _) => Callee::Func(def.clone()),
+                Shape::Effect(decl) => Callee::Effect(decl.clone()),
+                _ => Callee::Unknown,
+            },
+            None => match BUILTINS.iter().find(|sig| sig.name == name) {
+                Some(sig) => Callee::Builtin(sig),
+                None => Callee::Unknown,
+            },
+        }
+    }
+
+    /// Check the function expression of a call.
+    fn callee_expr(&mut self, func: &Expr) {
+        match &func.kind {
+            ExprKind::Ident(_) => {}
+            _ => {
+                self.expr(func, Mode::Use);
+            }
+        }
+    }
+
+    /// How a call passes its arguments. Functions other than borrowing
+    /// builtins take ownership.
+    fn mode(&self, callee: &Callee) -> Mode {
+        match callee {
+            Callee::Builtin(sig) => sig.mode,
+            _ => Mode::Consume,
+        }
+    }
+
+    fn call(&mut self, callee: Callee, receiver: Option<&Expr>, args: &[Arg], span: Span) -> Shape {
+        let mode = self.mode(&callee);
+        let exprs: Vec<&Expr> = receiver
+            .into_iter()
+            .chain(args.iter().map(|arg| &*arg.value))
+            .collect();
+        let shapes = exprs.iter().map(|arg| self.expr(arg, mode)).collect();
+        self.result(callee, shapes, &exprs, span)
+    }
+
+    fn result(&mut self, callee: Callee, args: Vec<Shape>, exprs: &[&Expr], span: Span) -> Shape {
+        let shape = match callee {
+            Callee::Builtin(sig) => match sig.returns {
+                Returns::Copy => Shape::Copy,
+                Returns::Resource(ty, tier) => Shape::Resource { ty, tier },
+                Returns::Args => Shape::bundle(args),
+                Returns::Never => {
+                    self.live = false;
+                    Shape::Copy
+                }
+                Returns::Elements { collect } => {
+                    let list = args.first().unwrap_or(&Shape::Copy);
+                    let shape = match exprs.get(1) {
+                        Some(callback) => self.elements(sig.name, list, callback),
+                        None => Shape::Copy,
+                    };
+                    if collect { shape } else { Shape::Copy }
+                }
+            },
+            Callee::Func(def) => {
+                let places: Vec<_> = exprs.iter().map(|arg| self.place(arg)).collect();
+                let shape = self.function(&def);
+                let shape = self.pass(&def, shape, &args, span);
+                self.instantiate(shape, &places, span)
+            }
+            Callee::Effect(decl) => {
+                self.perform(&decl, &args, exprs, span);
+                Shape::Copy
+            }
+            Callee::Unknown => Shape::Copy,
+        };
+        if self.comptime > 0 && shape.tier() == Tier::Linear && self.live {
+            let diagnostic = Diagnostic::error("linear types not allowed at compile time", span)
+                .with_note(format!(
+                    "this produces a `{}`; compile-time code has no cleanup to consume it",
+                    shape.ty()
+                ));
+            self.report(diagnostic, &shape, Tier::Linear);
+        }
+        shape
+    }
+
+    /// Pass `args` to a call of `def` at `span`: check the errors in its
+    /// body that depend on what its parameters are passed, and return its
+    /// result `shape` with each parameter replaced by its argument.
+    fn pass(&mut self, def: &Rc<FnDef>, shape: Shape, args: &[Shape], span: Span) -> Shape {
+        let Some((params, obligations)) = self.obligations.get(&Rc::as_ptr(def)).cloned() else {
+            return shape;
+        };
+        let arg = |id: usize| {
+            params
+                .iter()
+                .position(|param| *param == Some(id))
+                .and_then(|k| args.get(k))
+        };
+        if self.live {
+            for obligation in obligations {
+                let passed = obligation
+                    .params
+                    .iter()
+                    .filter_map(|id| arg(*id))
+                    .fold(Shape::Copy, |shape, arg| shape.join(arg.clone()));
+                if passed.tier() < obligation.tier {
+                    continue;
+                }
+                let mut diagnostic = obligation.diagnostic;
+                if passed.params().is_empty() {
+                    let kind = if passed.tier() == Tier::Linear {
+                        "linear: each must be consumed exactly once"
+                    } else {
+                        "affine: each may be moved at most once"
+                    };
+                    diagnostic = diagnostic
+                        .with_label(span, format!("called here with a `{}`", passed.ty()))
+                        .with_note(format!("values of type `{}` are {}", passed.ty(), kind));
+                } else {
+                    diagnostic = diagnostic.with_label(span, "called here");
+                }
+                self.report(diagnostic, &passed, obligation.tier);
+            }
+        }
+        shape.map_params(&mut |id| match arg(id) {
+            Some(arg) => arg.clone(),
+            None if params.contains(&Some(id)) => Shape::Copy,
+            None => Shape::Param(id),
+        })
+    }
+
+    /// Check a call to effect `decl` with arguments of shape `args`.
+    /// Nothing on the other side of the call consumes a linear argument:
+    /// the handler may not resume, or may resume more than once. A `Fork`
+    /// effect copies the whole computation that performs it, so nothing
+    /// that computation owns may be linear or affine.
+    fn perform(&mut self, decl: &EffectDecl, args: &[Shape], exprs: &[&Expr], span: Span) {
+        if !self.live {
+            return;
+        }
+        for (shape, expr) in args.iter().zip(exprs) {
+            if shape.tier() == Tier::Linear {
+                let diagnostic =
+                    Diagnostic::error("linear value cannot escape effect handler", expr.span)
+                        .with_label(span, format!("`{}` is performed here", decl.name))
+                        .with_note(format!(
+                            "values of type `{}` are linear, and a handler is not guaranteed to consume what it is passed; pass a reference instead",
+                            shape.ty()
+                        ));
+                self.report(diagnostic, shape, Tier::Linear);
+            }
+        }
+        match decl.resume {
+            ResumeKind::Never => self.live = false,
+            ResumeKind::Fork => {
+                let held: Vec<_> = self
+                    .vars
+                    .iter()
+                    .flat_map(|var| var.parts().into_iter().map(|part| (part, var.span)))
+                    .filter(|((_, shape, state), _)| {
+                        matches!(state, State::Owned) && shape.tier() != Tier::Copy
+                    })
+                    .collect();
+                for ((name, shape, _), declared) in held {
+                    let diagnostic = Diagnostic::error(
+                            format!("`{}` would be copied by `{}`", name, decl.name),
+                            span,
+                        )
+                        .with_label(declared, format!("`{}` is declared here", name))
+                        .with_note(format!(
+                            "`{}` is declared `where resume: Fork`, so the computation that performs it may continue more than once, but `{}` is a `{}`, which cannot be copied",
+                            decl.name,
+                            name,
+                            shape.ty()
+                        ));
+                    self.report(diagnostic, &shape, Tier::Affine);
+                }
+            }
+            ResumeKind::Once | ResumeKind::Many => {}
+        }
+    }
+
+    /// Check `callback`, which `each` or `drain` calls with each element of
+    /// `list`, returning the shape of the list of its results.
+    fn elements(&mut self, name: &str, list: &Shape, callback: &Expr) -> Shape {
+        let result = match self.callee(callback) {
+            Callee::Builtin(sig) => {
+                if sig.mode == Mode::Use && list.tier() == Tier::Linear && self.live {
+                    let diagnostic = Diagnostic::error(
+                        format!("`{}` does not consume the elements it is given", sig.name),
+                        callback.span,
+                    )
+                    .with_note(format!(
+                        "`{}` consumes the list, so each `{}` in it must be consumed by the function it is passed to",
+                        name,
+                        list.ty()
+                    ));
+                    self.report(diagnostic, list, Tier::Linear);
+                }
+                match sig.returns {
+                    Returns::Resource(ty, tier) => Shape::Resource { ty, tier },
+                    _ => Shape::Copy,
+                }
+            }
+            Callee::Func(def) => {
+                let element = match list {
+                    Shape::List(items) => items.iter().cloned().fold(Shape::Copy, Shape::join),
+                    shape => shape.clone(),
+                };
+                let shape = self.function(&def);
+                self.pass(&def, shape, &[element], callback.span)
+            }
+            Callee::Effect(_) | Callee::Unknown => Shape::Copy,
+        };
+        Shape::list(vec![result])
+    }
+
+    /// Whether `expr` is known to be an object with field `name`, so that
+    /// `expr.name(...)` calls the field rather than a function.
+    fn has_field(&self, expr: &Expr, name: &str) -> bool {
+        let ExprKind::Ident(var) = &expr.kind else {
+            return true;
+        };
+        match self.lookup(var).map(|i| &self.vars[i].shape) {
+            Some(Shape::Object(fields)) => fields.iter().any(|(field, _)| field == name),
+            Some(Shape::Resource { .. }) => false,
+            _ => !matches!(
+                self.callee_named(name),
+                Callee::Builtin(_) | Callee::Func(_) | Callee::Effect(_)
+            ),
+        }
+    }
+}
+
+/// Where a block's value comes from: its last statement.
+fn tail(block: &Block) -> Span {
+    block.stmts.last().map_or(block.span, |stmt| stmt.span)
+}
@@ -0,0 +1,313 @@
+//! Resume checking
+//!
+//! An effect's declaration decides how its handlers may call `resume`:
+//! never for a `Never` effect, exactly once on every path by default, and
+//! any number of times for `where resume: Many` or `Fork`. This module
+//! counts the calls along the paths through a handler body. Paths that
+//! abort (by `throw`, `panic`, `return` or another `Never` effect) don't
+//! have to resume.
+
+use crate::ast::{
+    Block, Expr, ExprKind, Handler, InterpPart, Item, Key, ObjectItem, ResumeKind, StmtKind,
+};
+use crate::diagnostic::{Diagnostic, Span};
+
+/// "More than once" in a [`Count`].
+const MANY: u32 = u32::MAX;
+
+/// The fewest and most `resume` calls over a set of paths.
+#[derive(Clone, Copy)]
+struct Count {
+    min: u32,
+    max: u32,
+}
+
+impl Count {
+    const ZERO: Count = Count { min: 0, max: 0 };
+
+    fn then(self, next: Count) -> Count {
+        Count {
+            min: self.min.saturating_add(next.min),
+            max: self.max.saturating_add(next.max),
+        }
+    }
+}
+
+/// The count for paths run in sequence. `None` is a set of paths that all
+/// abort.
+fn then(first: Option<Count>, next: Option<Count>) -> Option<Count> {
+    Some(first?.then(next?))
+}
+
+/// The count for alternative paths.
+fn either(a: Option<Count>, b: Option<Count>) -> Option<Count> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(Count {
+            min: a.min.min(b.min),
+            max: a.max.max(b.max),
+        }),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+/// Check the calls to `resume` in `handler` against the effect's `kind`.
+/// `declared` is the effect's declaration, if it has one; `aborts` says
+/// whether a call to the named function never returns.
+pub fn check(
+    handler: &Handler,
+    kind: ResumeKind,
+    declared: Option<Span>,
+    aborts: &dyn Fn(&str) -> bool,
+) -> Option<Diagnostic> {
+    let mut walk = Walk {
+        calls: Vec::new(),
+        repeated: None,
+        aborts,
+    };
+    let count = walk.block(&handler.body);
+    let effect = &handler.effect;
+    match kind {
+        ResumeKind::Never => {
+            let span = walk.calls.first().copied().or(walk.repeated)?;
+            let diagnostic = Diagnostic::error(
+                format!("cannot resume `{}`: it is a `Never` effect", effect),
+                span,
+            );
+            Some(match declared {
+                Some(declared) => diagnostic.with_label(declared, "declared `: Never` here"),
+                None => diagnostic.with_note(format!("`{}` is a built-in `Never` effect", effect)),
+            }
+            .with_note("a `Never` handler ends the computation it handles; its value is the value of that block"))
+        }
+        ResumeKind::Once => {
+            let many = format!(
+                "`{}` resumes exactly once; declare it `where resume: Many` to resume more than once",
+                effect
+            );
+            if let Some(span) = walk.repeated {
+                return Some(
+                    Diagnostic::error("`resume` may be called more than once", span)
+                        .with_note("it is called in a loop or from a function")
+                        .with_note(many),
+                );
+            }
+            let count = count?;
+            if count.max > 1 {
+                let mut diagnostic =
+                    Diagnostic::error("`resume` may be called more than once", walk.calls[1]);
+                diagnostic = diagnostic.with_label(walk.calls[0], "first called here");
+                return Some(diagnostic.with_note(many));
+            }
+            if count.min == 0 {
+                let diagnostic = Diagnostic::error(
+                    format!("handler for `{}` does not resume on every path", effect),
+                    handler.span,
+                );
+                return Some(diagnostic.with_note(format!(
+                    "`{}` resumes exactly once: call `resume(value)` on each path, or declare it `: Never` if the handler aborts",
+                    effect
+                )));
+            }
+            None
+        }
+        ResumeKind::Many | ResumeKind::Fork => None,
+    }
+}
+
+struct Walk<'a> {
+    /// The `resume` calls, in source order.
+    calls: Vec<Span>,
+    /// A `resume` that may run any number of times: in a loop, in a
+    /// function, or passed around as a value.
+    repeated: Option<Span>,
+    aborts: &'a dyn Fn(&str) -> bool,
+}
+
+impl Walk<'_> {
+    fn block(&mut self, block: &Block) -> Option<Count> {
+        let mut count = Some(Count::ZERO);
+        for stmt in &block.stmts {
+            let next = match &stmt.kind {
+                StmtKind::Let { value, .. } => self.expr(value),
+                StmtKind::Expr { expr, .. } | StmtKind::Install(expr) => self.expr(expr),
+                StmtKind::Defer { body, .. } => self.block(body),
+                // A nested handler's body has its own `resume`.
+                StmtKind::With(_)
+                | StmtKind::Fn(_)
+                | StmtKind::Effect(_)
+                | StmtKind::TypeDef { .. }
+                | StmtKind::Import { .. } => Some(Count::ZERO),
+            };
+            count = then(count, next);
+        }
+        count
+    }
+
+    fn all<'e>(&mut self, exprs: impl IntoIterator<Item = &'e Expr>) -> Option<Count> {
+        let mut count = Some(Count::ZERO);
+        for expr in exprs {
+            count = then(count, self.expr(expr));
+        }
+        count
+    }
+
+    fn items(&mut self, items: &[Item]) -> Option<Count> {
+        self.all(items.iter().map(|item| &*item.expr))
+    }
+
+    /// Count the calls in a part that may run any number of times.
+    fn repeated(&mut self, count: Option<Count>, from: usize) -> Option<Count> {
+        if let Some(&span) = self.calls.get(from) {
+            self.repeated.get_or_insert(span);
+            return Some(Count { min: 0, max: MANY });
+        }
+        count
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Option<Count> {
+        match &expr.kind {
+            ExprKind::Int(_)
+            | ExprKind::Float(_)
+            | ExprKind::Str(_)
+            | ExprKind::Bool(_)
+            | ExprKind::Nil
+            | ExprKind::Subject
+            | ExprKind::Continue
+            | ExprKind::Error
+            // A handler value's clauses have their own `resume`.
+            | ExprKind::Handler(_) => Some(Count::ZERO),
+            ExprKind::Ident(name) => {
+                if name == "resume" {
+                    self.repeated.get_or_insert(expr.span);
+                    return Some(Count { min: 0, max: MANY });
+                }
+                Some(Count::ZERO)
+            }
+            ExprKind::Interp(parts) => self.all(parts.iter().filter_map(|part| match part {
+                InterpPart::Expr(expr) => Some(&**expr),
+                InterpPart::Text(_) => None,
+            })),
+            ExprKind::List(items) => self.items(items),
+            ExprKind::Object(items) => {
+                let mut count = Some(Count::ZERO);
+                for item in items {
+                    let next = match item {
+                        ObjectItem::Field { key, value } => {
+                            let key = match key {
+                                Key::Computed(key) => self.expr(key),
+                                Key::Name(_) => Some(Count::ZERO),
+                            };
+                            then(key, self.expr(value))
+                        }
+                        ObjectItem::Spread(expr) => self.expr(expr),
+                    };
+                    count = then(count, next);
+                }
+                count
+            }
+            ExprKind::Unary { expr, .. }
+            | ExprKind::As { expr, .. }
+            | ExprKind::Try(expr)
+            | ExprKind::Field { expr, .. }
+            | ExprKind::Ref { expr, .. }
+            | ExprKind::Stage { expr, .. } => self.expr(expr),
+            ExprKind::Binary { lhs, rhs, .. } => self.all([&**lhs, &**rhs]),
+            ExprKind::Compare { first, rest } => {
+                self.all(std::iter::once(&**first).chain(rest.iter().map(|(_, e)| &**e)))
+            }
+            ExprKind::Index { expr, index } => self.all([&**expr, &**index]),
+            ExprKind::Assign { target, value } => self.all([&**value, &**target]),
+            ExprKind::Call { func, args } => {
+                let args = self.all(args.iter().map(|arg| &*arg.value));
+                self.call(func, args, expr.span)
+            }
+            ExprKind::Apply {
+                func,
+                subject,
+                args,
+            } => {
+                let subject = self.expr(subject);
+                let args = then(
+                    subject,
+                    self.all(args.iter().flatten().map(|arg| &*arg.value)),
+                );
+                self.call(func, args, expr.span)
+            }
+            ExprKind::Block(block) => self.block(block),
+            ExprKind::Lambda(def) => {
+                let from = self.calls.len();
+                let count = self.block(&def.body);
+                let count = self.repeated(count, from);
+                // Defining the function calls nothing; calling it might.
+                count.map(|count| Count {
+                    min: 0,
+                    max: count.max,
+                })
+            }
+            ExprKind::If {
+                cond,
+                then: body,
+                otherwise,
+            } => {
+                let cond = self.expr(cond);
+                let body = self.block(body);
+                let otherwise = match otherwise {
+                    Some(otherwise) => self.expr(otherwise),
+                    None => Some(Count::ZERO),
+                };
+                then(cond, either(body, otherwise))
+            }
+            ExprKind::Match { subject, arms } => {
+                let subject = self.expr(subject);
+                let mut arms_count = None;
+                for arm in arms {
+                    let guard = match &arm.guard {
+                        Some(guard) => self.expr(guard),
+                        None => Some(Count::ZERO),
+                    };
+                    arms_count = either(arms_count, then(guard, self.expr(&arm.body)));
+                }
+                then(subject, arms_count)
+            }
+            ExprKind::Loop(body) => {
+                let from = self.calls.len();
+                let count = self.block(body);
+                self.repeated(count, from)
+            }
+            ExprKind::While { cond, body } => {
+                let from = self.calls.len();
+                let cond = self.expr(cond);
+                let body = self.block(body);
+                let count = then(cond, either(body, Some(Count::ZERO)));
+                self.repeated(count, from)
+            }
+            ExprKind::Break(value) => match value {
+                Some(value) => self.expr(value),
+                None => Some(Count::ZERO),
+            },
+            ExprKind::Return(value) => {
+                if let Some(value) = value {
+                    self.expr(value);
+                }
+                None
+            }
+            ExprKind::Spawn(block) | ExprKind::Nursery(block) => self.block(block),
+        }
+    }
+
+    /// A call to `func`, after its arguments.
+    fn call(&mut self, func: &Expr, args: Option<Count>, span: Span) -> Option<Count> {
+        match &func.kind {
+            ExprKind::Ident(name) if name == "resume" => {
+                self.calls.push(span);
+                then(args, Some(Count { min: 1, max: 1 }))
+            }
+            ExprKind::Ident(name) if (self.aborts)(name) => {
+                args?;
+                None
+            }
+            _ => then(args, self.expr(func)),
+        }
+    }
+}
@@ -18,11 +18,17 @@ fn later_binary() -> PathBuf {
 
 /// Run a .later file and return (exit_code, stdout, stderr)
 fn run_later_file(filename: &str) -> (i32, String, String) {
+    run_later(&[], filename)
+}
+
+/// Run the `later` binary with `flags` on a .later file
+fn run_later(flags: &[&str], filename: &str) -> (i32, String, String) {
     let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     path.push("examples");
     path.push(filename);
 
     let output = Command::new(later_binary())
+        .args(flags)
         .arg(&path)
         .output()
         .expect("Failed to execute later binary");
@@ -311,6 +317,29 @@ fn test_effect_generator() {
     expect_output("generator.later", "[0, 1, 2, 3, 4]");
 }
 
+#[test]
+fn test_generator_adapters() {
+    // take, map, filter and zip over infinite generators
+    expect_output(
+        "generator_adapters.later",
+        "[0, 1, 2, 3, 4]\n[0, 4, 16]\n[[0, \"a\"], [1, \"b\"], [2, \"c\"]]",
+    );
+}
+
+#[test]
+fn test_generator_take_defer() {
+    expect_output("generator_take_defer.later", "naturals done\n[0, 1, 2]");
+}
+
+#[test]
+fn test_generator_early_stop() {
+    // A handler that doesn't resume stops the generator
+    expect_output(
+        "generator_fibonacci.later",
+        "fib: 0\nfib: 1\nfib: 1\nfib: 2\nfib: 3\nfib: 5\nfib: 8\nfib: 13\nfib: 21\nfib: 34",
+    );
+}
+
 // =============================================================================
 // PHASE 4: Cancellation
 // =============================================================================
@@ -342,6 +371,14 @@ fn test_spawn_and_await() {
     expect_output("spawn_await.later", "42");
 }
 
+#[test]
+fn test_spawn_schedule() {
+    expect_output(
+        "spawn_schedule.later",
+        "quick woke\nbusy counted to 1500\nslow woke\n<task 1> is slow, <task 2> is quick, <task 3> is busy",
+    );
+}
+
 #[test]
 fn test_all_combinator() {
     expect_output("all.later", "[1, 2, 3]");
@@ -434,6 +471,11 @@ fn test_loop_continue() {
     expect_output("loop_continue.later", "[1, 3, 5, 7, 9]");
 }
 
+#[test]
+fn test_while_break_value() {
+    expect_output("while_break_value.later", "[1, 3, 5, 7, 9] 225");
+}
+
 // =============================================================================
 // PHASE 1: Pattern Matching
 // =============================================================================
@@ -516,6 +558,12 @@ fn test_comments_ignored() {
     expect_output("comments.later", "42");
 }
 
+#[test]
+fn test_comments_nested_and_doc() {
+    // Shebang, nested `#* *#` block comments and `##` doc comments
+    expect_output("comments_nested.later", "42");
+}
+
 #[test]
 fn test_trailing_comma_list() {
     expect_output("trailing_comma_list.later", "[1, 2, 3]");
@@ -609,6 +657,18 @@ fn test_pipe_chain() {
     expect_output("pipe_chain.later", "30");
 }
 
+#[test]
+fn test_postfix_application() {
+    // Juxtaposition passes the value on the left as the implicit subject
+    expect_output("postfix_apply.later", "got 26");
+}
+
+#[test]
+fn test_postfix_access_chain() {
+    // `.items .[1]` then `+ 1` continued on the next line
+    expect_output("postfix_access.later", "21");
+}
+
 // =============================================================================
 // PHASE 2: Linear Types - Advanced
 // =============================================================================
@@ -627,12 +687,48 @@ fn test_linear_struct_partial_consume_error() {
     );
 }
 
+#[test]
+fn test_linear_struct_bundle() {
+    // Fields are borrowed for reading; destructuring moves them out
+    expect_output(
+        "linear_struct_bundle.later",
+        "contents of db\ncontents of cache\nshut down",
+    );
+}
+
+#[test]
+fn test_linear_struct_forgotten_field_error() {
+    // Destructuring that leaves a linear field behind leaks it
+    expect_error(
+        "linear_struct_forgotten_field.later",
+        "linear field `b` was never consumed",
+    );
+}
+
 #[test]
 fn test_linear_in_list() {
     // Linear values in lists must all be consumed
     expect_output("linear_list.later", "done");
 }
 
+#[test]
+fn test_linear_list_destructure() {
+    // Destructuring and `drain` take elements out of a linear list
+    expect_output(
+        "linear_list_destructure.later",
+        "contents of a.txt\n[\"closed\", \"closed\"]",
+    );
+}
+
+#[test]
+fn test_linear_list_each_borrow_error() {
+    // `each` over a linear list needs a function that consumes
+    expect_error(
+        "linear_list_each_borrow.later",
+        "`inspect` does not consume the elements it is given",
+    );
+}
+
 #[test]
 fn test_linear_loop_consume() {
     // Linear value created in loop must be consumed each iteration
@@ -659,6 +755,32 @@ fn test_linear_fn_return_not_consumed_error() {
     );
 }
 
+#[test]
+fn test_linear_fn_param_not_consumed_error() {
+    // A parameter passed a linear value must be consumed in the body
+    expect_error(
+        "linear_fn_param_unused.later",
+        "linear value `file` was never consumed",
+    );
+}
+
+#[test]
+fn test_linear_fn_param_returned() {
+    // Returning a parameter returns whatever the caller passed
+    expect_error(
+        "linear_fn_param_returned.later",
+        "linear value was never consumed",
+    );
+}
+
+#[test]
+fn test_linear_fn_param_consumed_twice_error() {
+    expect_error(
+        "linear_fn_param_twice.later",
+        "value `file` was already consumed",
+    );
+}
+
 // =============================================================================
 // PHASE 3: Closures
 // =============================================================================
@@ -710,7 +832,7 @@ fn test_mutual_recursion() {
 
 #[test]
 fn test_effect_multiple_handlers() {
-    expect_output("effect_multi_handler.later", "a: 1, b: 2");
+    expect_output("effect_multi_handler.later", "a: 10, b: 20");
 }
 
 #[test]
@@ -835,6 +957,23 @@ fn test_error_line_number() {
     expect_error("error_line_number.later", "line 5");
 }
 
+#[test]
+fn test_list_build_large() {
+    // Pushing onto a `let mut` list appends in place instead of copying
+    expect_output("list_build_large.later", "20000\n39998");
+}
+
+#[test]
+fn test_list_push_cancel() {
+    expect_output("list_push_cancel.later", "[1, 2, 3]");
+}
+
+#[test]
+fn test_match_guard_not_bool() {
+    // The error points at the guard, not the start of the file
+    expect_error("match_guard_not_bool.later", "line 4, column 9");
+}
+
 #[test]
 fn test_error_syntax_unexpected_token() {
     expect_error("error_syntax.later", "unexpected token");
@@ -855,6 +994,31 @@ fn test_error_unclosed_paren() {
     expect_error("error_unclosed_paren.later", "unclosed `(`");
 }
 
+#[test]
+fn test_error_mismatched_paren_bracket() {
+    expect_error("error_mismatched_paren_bracket.later", "mismatched closing `]`, expected `)`");
+}
+
+#[test]
+fn test_error_mismatched_brace_bracket() {
+    expect_error("error_mismatched_brace_bracket.later", "mismatched closing `]`, expected `}`");
+}
+
+#[test]
+fn test_error_mismatched_brace_paren() {
+    expect_error("error_mismatched_brace_paren.later", "mismatched closing `)`, expected `}`");
+}
+
+#[test]
+fn test_error_mismatched_paren_brace() {
+    expect_error("error_mismatched_paren_brace.later", "mismatched closing `}`, expected `)`");
+}
+
+#[test]
+fn test_error_mismatched_fn_body() {
+    expect_error("error_mismatched_fn_body.later", "mismatched closing `]`, expected `}`");
+}
+
 // =============================================================================
 // Pattern Matching - Edge Cases
 // =============================================================================
@@ -997,6 +1161,21 @@ fn test_defer_on_error() {
     expect_output("defer_on_error.later", "deferred\nerror: boom");
 }
 
+#[test]
+fn test_defer_linear() {
+    // A deferred close consumes the file on every exit path
+    expect_output(
+        "defer_linear.later",
+        "closed notes.txt\ncontents of notes.txt\nclosed notes.txt\nnothing to read",
+    );
+}
+
+#[test]
+fn test_defer_linear_move() {
+    // A value consumed by a defer cannot be moved before it runs
+    expect_error("defer_linear_move.later", "value `file` is consumed by a `defer`");
+}
+
 // =============================================================================
 // Type Annotations (Optional)
 // =============================================================================
@@ -1139,13 +1318,16 @@ fn test_channel_bounded() {
 #[test]
 fn test_effect_as_capability() {
     // Effect types act as capabilities
-    expect_output("effect_capability.later", "io performed");
+    expect_output("effect_capability.later", "[LOG] work started\n[LOG] work done");
 }
 
 #[test]
 fn test_effect_composition() {
     // Multiple effects compose
-    expect_output("effect_compose.later", "yielded: 1\nlogged: step 1");
+    expect_output(
+        "effect_compose.later",
+        "yielded: 1\nlogged: step 1\nyielded: 2\nlogged: step 2",
+    );
 }
 
 #[test]
@@ -1495,6 +1677,14 @@ fn test_integer_underflow() {
     expect_error("int_underflow.later", "integer overflow");
 }
 
+#[test]
+fn test_integer_overflow_operands() {
+    expect_error(
+        "int_mul_overflow.later",
+        "integer overflow: 4611686018427387904 * 2",
+    );
+}
+
 #[test]
 fn test_float_nan_comparison() {
     // NaN comparisons should be false (IEEE 754)
@@ -1511,6 +1701,12 @@ fn test_float_neg_infinity() {
     expect_output("float_neg_infinity.later", "-inf");
 }
 
+#[test]
+fn test_float_inf_literal() {
+    // The lexer reads `inf` as a float literal
+    expect_output("float_inf_literal.later", "inf\ntrue\n-inf");
+}
+
 #[test]
 fn test_modulo_zero() {
     expect_error("mod_zero.later", "division by zero");
@@ -1537,13 +1733,19 @@ fn test_linear_conditional_only_one_branch_error() {
 
 #[test]
 fn test_linear_loop_consume_error() {
-    // Can't consume a linear value inside a loop (might run 0 or N times)
+    // Can't consume a linear value from outside a loop that goes round again
     expect_error(
         "linear_loop_consume.later",
         "cannot consume linear value in loop",
     );
 }
 
+#[test]
+fn test_linear_loop_retry_close() {
+    // Consuming a value from outside the loop is fine on a path that breaks
+    expect_output("linear_loop_retry_close.later", "closed after 3 tries");
+}
+
 #[test]
 fn test_linear_return_without_consume() {
     // Early return must not leak linear values
@@ -1562,6 +1764,21 @@ fn test_linear_break_without_consume() {
     );
 }
 
+#[test]
+fn test_linear_try_without_consume() {
+    // `?` may return early, so it must not leak linear values either
+    expect_error(
+        "linear_try_leak.later",
+        "linear value `file` not consumed before `?` returns early",
+    );
+}
+
+#[test]
+fn test_affine_value_may_go_unconsumed() {
+    // A value with drop need not be consumed
+    expect_output("affine_connection.later", "contents of connection");
+}
+
 #[test]
 fn test_linear_panic_cleanup() {
     // Panic should still run cleanup for linear values
@@ -1626,6 +1843,11 @@ fn test_cancel_check_frequency() {
     expect_output("cancel_cpu_bound.later", "cancelled after iterations");
 }
 
+#[test]
+fn test_cancel_recursion() {
+    expect_output("cancel_recursion.later", "cleanup\ncancelled");
+}
+
 // =============================================================================
 // Effect Edge Cases
 // =============================================================================
@@ -1636,6 +1858,55 @@ fn test_effect_handler_scope() {
     expect_error("effect_handler_scope.later", "unhandled effect: ask");
 }
 
+#[test]
+fn test_effect_private_propagates() {
+    // An effect declared inside a function can't be handled outside it
+    expect_error(
+        "effect_private.later",
+        "effect 'log' is not handled: it is declared inside `work`",
+    );
+}
+
+#[test]
+fn test_effect_row_through_callback() {
+    // Calling a parameter performs whatever the passed function performs
+    expect_error("effect_row_callback.later", "`f` is called here");
+}
+
+#[test]
+fn test_effect_signatures() {
+    // Inferred effect rows are shown in function signatures
+    let (exit_code, stdout, stderr) = run_later(&["--signatures"], "effect_signatures.later");
+    assert_eq!(exit_code, 0, "stderr: {}", stderr);
+    assert_eq!(
+        stdout.trim(),
+        "fn double(x) : <>\nfn use-ask() : <ask>\nfn chatty(f) : <log | f>\nfn answered() : <>"
+    );
+}
+
+#[test]
+fn test_effect_throw_rethrow() {
+    // An `error` handler catches `throw` and `?`, and can re-throw outward
+    expect_output("effect_throw.later", "1\nrolling back\ncaught: too big: 5");
+}
+
+#[test]
+fn test_effect_legacy_handle() {
+    // Older handler forms are reported with their `with` rewrite
+    expect_error(
+        "effect_legacy.later",
+        "write `with ask(q) { ... resume(value) }`",
+    );
+    expect_error("effect_legacy.later", "put `with error(e) { ... }` at the start");
+    expect_error("effect_legacy.later", "put `with panic(msg) { ... }` on the line before");
+}
+
+#[test]
+fn test_effect_legacy_send() {
+    // `send X with Y` is reported with the function-call form
+    expect_error("effect_legacy.later", "write `ask(\"sure?\")`");
+}
+
 #[test]
 fn test_effect_shadowing() {
     // Inner handler shadows outer handler for same effect
@@ -1648,6 +1919,16 @@ fn test_effect_resume_multiple_times() {
     expect_output("effect_multi_resume.later", "true, true\ntrue, false\nfalse, true\nfalse, false");
 }
 
+#[test]
+fn test_effect_fork_local_state() {
+    expect_output("effect_fork_local_state.later", "11\n1\n10\n0");
+}
+
+#[test]
+fn test_effect_abandon_defer() {
+    expect_output("effect_abandon_defer.later", "cleanup\nabandoned");
+}
+
 #[test]
 fn test_effect_resume_with_value() {
     // Handler can provide a value when resuming
@@ -1663,6 +1944,70 @@ fn test_effect_linear_across_boundary() {
     );
 }
 
+#[test]
+fn test_effect_resume_once() {
+    // A default effect resumes exactly once on every path
+    expect_error(
+        "effect_resume_twice.later",
+        "`resume` may be called more than once",
+    );
+    expect_error(
+        "effect_resume_missing.later",
+        "handler for `ask` does not resume on every path",
+    );
+}
+
+#[test]
+fn test_effect_never_resume() {
+    // A `Never` handler has no continuation to resume
+    expect_error(
+        "effect_never_resume.later",
+        "cannot resume `fail`: it is a `Never` effect",
+    );
+}
+
+#[test]
+fn test_effect_fork_linear() {
+    // Forking would copy the linear values the computation holds
+    expect_error(
+        "effect_fork_linear.later",
+        "`file` would be copied by `choose`",
+    );
+}
+
+#[test]
+fn test_effect_fork_search() {
+    // Each resume of a forking effect runs a copy of the computation
+    expect_output("effect_fork_search.later", "[3, 5, 2]\n[3, 7]");
+}
+
+#[test]
+fn test_effect_many_linear() {
+    // A second resume is refused when the computation holds a resource
+    expect_error(
+        "effect_many_linear.later",
+        "cannot resume `retry` again: the computation holds `file`, an open `File`",
+    );
+}
+
+#[test]
+fn test_effect_handler_return() {
+    // A return clause wraps the block's value, but not a handler's
+    expect_output(
+        "effect_handler_return.later",
+        "Ok(5)\nErr(\"divide by zero\")",
+    );
+}
+
+#[test]
+fn test_effect_handler_value() {
+    // Handler values are built by functions and installed with `with`
+    expect_output(
+        "effect_handler_value.later",
+        "> starting\n> done\n42 after 2 messages",
+    );
+}
+
 // =============================================================================
 // Concurrency Edge Cases
 // =============================================================================
@@ -1691,6 +2036,60 @@ fn test_nursery_error_cancels_siblings() {
     expect_output("nursery_error_cancel.later", "sibling cancelled");
 }
 
+#[test]
+fn test_nursery_group_as_data() {
+    // Failures of several children reach the handler as one `Group`
+    expect_output(
+        "nursery_group.later",
+        "2 tasks failed\ntask 2: error: disk full\ntask 1: error: log flush failed",
+    );
+}
+
+#[test]
+fn test_nursery_group_unhandled() {
+    expect_error(
+        "nursery_group_unhandled.later",
+        "error: 2 tasks failed\n  task 3: 2 tasks failed\n    task 3.2: error: connection reset\n    task 3.1: panic: index out of range\n  task 1: error: log flush failed",
+    );
+}
+
+#[test]
+fn test_nursery_handle_escape() {
+    expect_error("nursery_handle_escape.later", "task cannot outlive its parent scope");
+}
+
+#[test]
+fn test_nursery_handle_assign() {
+    // Assigning the handle to a variable from outside the nursery escapes it too
+    expect_error("nursery_handle_assign.later", "task cannot outlive its parent scope");
+}
+
+#[test]
+fn test_nursery_handle_returned() {
+    // A function returning its argument returns the handle
+    expect_error("nursery_handle_returned.later", "task cannot outlive its parent scope");
+}
+
+#[test]
+fn test_cleanup_shield() {
+    // A cancelled task finishes its cleanup; one with a passed deadline is cut short
+    expect_output(
+        "cleanup_shield.later",
+        "transfer done\nrollback started\nrollback finished\nnursery failed: disk full",
+    );
+}
+
+#[test]
+fn test_any_combinator() {
+    // First success wins; an earlier failure doesn't count
+    expect_output("any.later", "mirror 2");
+}
+
+#[test]
+fn test_settle_combinator() {
+    expect_output("settle.later", "ok: 1\ntask 2 failed: no luck\nok: 3");
+}
+
 #[test]
 fn test_deadlock_detection() {
     // Obvious deadlock should be detected
@@ -1979,6 +2378,18 @@ fn test_block_trailing_semicolon() {
     expect_output("block_trailing_semi.later", "nil");
 }
 
+#[test]
+fn test_block_vs_object() {
+    // `{ a, b: 2 }` and `{ ...obj }` are objects, `{ let ... }` is a block
+    expect_output("block_vs_object.later", "3");
+}
+
+#[test]
+fn test_block_ambiguous() {
+    // `{ x }` is rejected with a suggestion for each reading
+    expect_error("block_ambiguous.later", "ambiguous `{ x }`");
+}
+
 // =============================================================================
 // Integration: Linear Types + Concurrency
 // =============================================================================
@@ -2090,6 +2501,24 @@ fn test_multiple_errors_cleanup() {
     expect_output("multi_error_cleanup.later", "all cleanup errors logged");
 }
 
+#[test]
+fn test_cleanup_errors_as_data() {
+    // Suppressed cleanup failures reach the handler in the order they ran
+    expect_output(
+        "cleanup_errors.later",
+        "failed: request failed\n  error in cleanup: log flush failed\n  panic in cleanup: socket close failed",
+    );
+}
+
+#[test]
+fn test_cleanup_errors_unhandled() {
+    // An unhandled failure reports the cleanup that failed after it
+    expect_error(
+        "cleanup_errors_unhandled.later",
+        "cleanup also failed: panic: could not close the log",
+    );
+}
+
 // =============================================================================
 // Stress Tests (for implementation robustness)
 // =============================================================================
@@ -2201,6 +2630,12 @@ fn test_borrow_through_function() {
     expect_output("borrow_fn.later", "42");
 }
 
+#[test]
+fn test_borrow_escapes_through_assignment() {
+    // A reference assigned to an outer variable can't outlive its value
+    expect_error("borrow_escape_assign.later", "borrow outlives borrowed value");
+}
+
 // =============================================================================
 // Move Semantics
 // =============================================================================
@@ -2225,7 +2660,7 @@ fn test_move_in_condition() {
 
 #[test]
 fn test_move_in_loop_error() {
-    // Move in loop is an error (unless rebinding)
+    // Move in loop is an error when the loop goes round again (unless rebinding)
     expect_error("move_loop.later", "cannot move in loop");
 }
 